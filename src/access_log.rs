@@ -0,0 +1,268 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str;
+use std::sync::mpsc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use chrono::prelude::*;
+use serde_json;
+
+/// Longer propagated request ids are replaced with generated ones
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// Lines waiting for writer thread, more are dropped
+const QUEUED_LINES: usize = 65536;
+
+/// Request ids unique to process run, e.g. `1f3a-5a0c3b12-2a`
+pub struct RequestIds {
+    prefix: String,
+    next: AtomicUsize,
+}
+
+impl RequestIds {
+    pub fn new() -> Self {
+        RequestIds {
+            prefix: format!("{:x}-{:x}", process::id(), Utc::now().timestamp()),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Propagated `X-Request-Id` if it is short printable ASCII, otherwise new one
+    pub fn get(&self, propagated: Option<&[u8]>) -> String {
+        match propagated.and_then(|id| str::from_utf8(id).ok()) {
+            Some(id) if !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH && id.bytes().all(|b| b.is_ascii_graphic()) =>
+                id.to_string(),
+            _ => format!("{}-{:x}", self.prefix, self.next.fetch_add(1, Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Request served, latency is time until response head is ready, bytes are body bytes sent
+#[derive(Debug, Serialize)]
+pub struct Entry {
+    pub request_id: String,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub bytes: u64,
+    pub latency_ms: f64,
+    pub client: String,
+}
+
+impl Entry {
+    pub fn latency_ms(latency: Duration) -> f64 {
+        latency.as_secs() as f64 * 1000.0 + f64::from(latency.subsec_nanos()) / 1_000_000.0
+    }
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    time: String,
+    thread: &'a str,
+    #[serde(flatten)]
+    entry: &'a Entry,
+}
+
+struct LogFile {
+    file: fs::File,
+    size: u64,
+}
+
+/// JSON lines file rotated when it would exceed `max_size`. Previous files are kept as
+/// `PATH.1` (the latest) to `PATH.N`.
+struct LogWriter {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: LogFile,
+}
+
+fn open_append(path: &Path) -> io::Result<LogFile> {
+    let file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok(LogFile {
+        file: file,
+        size: size,
+    })
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+impl LogWriter {
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.max_files).rev() {
+                match fs::rename(rotated_path(&self.path, index), rotated_path(&self.path, index + 1)) {
+                    Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+                    result => result?,
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+        self.file = open_append(&self.path)?;
+        Ok(())
+    }
+
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.file.size > 0 && self.file.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.file.write_all(line)?;
+        self.file.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn run(mut self, lines: mpsc::Receiver<Vec<u8>>) {
+        for line in lines {
+            if let Err(err) = self.write(&line) {
+                error!("Access log error {:?}", err);
+            }
+        }
+    }
+}
+
+/// Lines are written by own thread, so request threads do not wait for disk.
+/// Queued lines are written before the log is dropped.
+pub struct AccessLog {
+    lines: Option<mpsc::SyncSender<Vec<u8>>>,
+    writer: Option<thread::JoinHandle<()>>,
+}
+
+impl AccessLog {
+    pub fn open(path: PathBuf, max_size: u64, max_files: usize) -> io::Result<Self> {
+        let writer = LogWriter {
+            file: open_append(&path)?,
+            path: path,
+            max_size: max_size,
+            max_files: max_files,
+        };
+        let (sender, receiver) = mpsc::sync_channel(QUEUED_LINES);
+        let writer = thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || writer.run(receiver))?;
+        Ok(AccessLog {
+            lines: Some(sender),
+            writer: Some(writer),
+        })
+    }
+
+    /// Line is queued for writer thread, it is dropped if the queue is full
+    pub fn write(&self, entry: &Entry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(&Line {
+            time: Utc::now().to_rfc3339(),
+            thread: thread::current().name().unwrap_or("unnamed"),
+            entry: entry,
+        })?;
+        line.push(b'\n');
+
+        match self.lines.as_ref().map(|lines| lines.try_send(line)) {
+            Some(Ok(())) => Ok(()),
+            Some(Err(mpsc::TrySendError::Full(_))) =>
+                Err(io::Error::new(io::ErrorKind::WouldBlock, "access log queue is full, line dropped")),
+            _ => Err(io::Error::new(io::ErrorKind::BrokenPipe, "access log writer stopped")),
+        }
+    }
+}
+
+impl Drop for AccessLog {
+    fn drop(&mut self) {
+        self.lines.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::Read;
+
+    fn entry(path: &str) -> Entry {
+        Entry {
+            request_id: "id".into(),
+            method: "GET".into(),
+            path: path.into(),
+            status: 200,
+            bytes: 2,
+            latency_ms: Entry::latency_ms(Duration::new(1, 500_000)),
+            client: "127.0.0.1:50000".into(),
+        }
+    }
+
+    fn read_lines(path: &Path) -> Vec<serde_json::Value> {
+        let mut content = String::new();
+        fs::File::open(path).unwrap().read_to_string(&mut content).unwrap();
+        content.lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+    }
+
+    #[test]
+    fn propagate_request_id() {
+        let request_ids = RequestIds::new();
+        assert_eq!(request_ids.get(Some(b"abc-123")), "abc-123");
+
+        let generated = request_ids.get(None);
+        assert!(generated.starts_with(&request_ids.prefix));
+        assert_ne!(request_ids.get(Some(b"")), generated);
+        assert_ne!(request_ids.get(Some(b"new\nline")), "new\nline");
+        assert_ne!(request_ids.get(Some(&[b'a'; MAX_REQUEST_ID_LENGTH + 1])).len(), MAX_REQUEST_ID_LENGTH + 1);
+    }
+
+    #[test]
+    fn write_json_lines() {
+        let path = env::temp_dir().join(format!("hlcup1-access-{}.log", process::id()));
+        let _ = fs::remove_file(&path);
+        let log = AccessLog::open(path.clone(), 1024, 2).unwrap();
+        log.write(&entry("/users/1")).unwrap();
+        drop(log);
+
+        let lines = read_lines(&path);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["path"], "/users/1");
+        assert_eq!(lines[0]["latency_ms"], 1000.5);
+        assert_eq!(lines[0]["thread"], thread::current().name().unwrap());
+        assert!(lines[0]["time"].is_string());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rotate_by_size() {
+        let dir = env::temp_dir().join(format!("hlcup1-rotate-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("access.log");
+
+        // Lines are of the same size, each file holds two of them
+        let line = |id: usize| format!("{{\"path\":\"/users/{}\"}}\n", id).into_bytes();
+        let mut writer = LogWriter {
+            path: path.clone(),
+            max_size: line(0).len() as u64 * 2,
+            max_files: 2,
+            file: open_append(&path).unwrap(),
+        };
+        for id in 0..7 {
+            writer.write(&line(id)).unwrap();
+        }
+        drop(writer);
+
+        let paths = |lines: Vec<serde_json::Value>| lines.iter().map(|line| line["path"].clone()).collect::<Vec<_>>();
+        assert_eq!(paths(read_lines(&path)), vec!["/users/6"]);
+        assert_eq!(paths(read_lines(&rotated_path(&path, 1))), vec!["/users/4", "/users/5"]);
+        assert_eq!(paths(read_lines(&rotated_path(&path, 2))), vec!["/users/2", "/users/3"]);
+        assert!(!rotated_path(&path, 3).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
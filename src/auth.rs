@@ -0,0 +1,191 @@
+use std::fmt;
+use std::str::FromStr;
+
+use hyper;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    ReadOnly,
+    Writer,
+    Admin,
+}
+
+/// Access required by request
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Permission {
+    Read,
+    Write,
+    Admin,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    MissingKey,
+    UnknownKey,
+    Forbidden(Role, Permission),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::MissingKey => write!(f, "API key is required"),
+            Error::UnknownKey => write!(f, "Unknown API key"),
+            Error::Forbidden(role, permission) => write!(f, "Role {} has no {} access", role, permission),
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "read-only" => Ok(Role::ReadOnly),
+            "writer" => Ok(Role::Writer),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("unknown role `{}`, expected read-only, writer or admin", value)),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Role::ReadOnly => write!(f, "read-only"),
+            Role::Writer => write!(f, "writer"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Permission::Read => write!(f, "read"),
+            Permission::Write => write!(f, "write"),
+            Permission::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+impl Role {
+    pub fn allows(&self, permission: Permission) -> bool {
+        match (*self, permission) {
+            (Role::Admin, _) => true,
+            (Role::Writer, Permission::Read) | (Role::Writer, Permission::Write) => true,
+            (Role::ReadOnly, Permission::Read) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Permission {
+    /// Reads are GET requests, entity history with client addresses is for admins only
+    pub fn of(method: &hyper::Method, path: &str) -> Self {
+        match *method {
+            hyper::Method::Get | hyper::Method::Head if path.ends_with("/history") => Permission::Admin,
+            hyper::Method::Get | hyper::Method::Head => Permission::Read,
+            _ => Permission::Write,
+        }
+    }
+}
+
+/// API key with its role, configured as `KEY:ROLE`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ApiKey {
+    pub key: String,
+    pub role: Role,
+}
+
+impl FromStr for ApiKey {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.rfind(':') {
+            Some(index) if index > 0 => Ok(ApiKey {
+                key: value[..index].to_string(),
+                role: value[index + 1..].parse()?,
+            }),
+            _ => Err("expected KEY:ROLE".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.key, self.role)
+    }
+}
+
+/// Comparison time does not depend on position of first mismatch
+fn same_key(expected: &str, actual: &str) -> bool {
+    expected.len() == actual.len() &&
+        expected.bytes().zip(actual.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Role of configured key
+pub fn role(api_keys: &[ApiKey], key: &str) -> Option<Role> {
+    api_keys.iter()
+        .find(|api_key| same_key(&api_key.key, key))
+        .map(|api_key| api_key.role)
+}
+
+/// Role of presented key if it grants permission
+pub fn authorize(api_keys: &[ApiKey], key: Option<&str>, permission: Permission) -> Result<Role, Error> {
+    let key = key.ok_or(Error::MissingKey)?;
+    let role = role(api_keys, key).ok_or(Error::UnknownKey)?;
+
+    if role.allows(permission) {
+        Ok(role)
+    } else {
+        Err(Error::Forbidden(role, permission))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api_keys() -> Vec<ApiKey> {
+        vec!["reader:read-only".parse().unwrap(), "writer:writer".parse().unwrap(), "root:admin".parse().unwrap()]
+    }
+
+    #[test]
+    fn parse_api_key() {
+        assert_eq!("a:b:admin".parse(), Ok(ApiKey { key: "a:b".into(), role: Role::Admin }));
+        assert_eq!("key".parse::<ApiKey>(), Err("expected KEY:ROLE".to_string()));
+        assert_eq!(":admin".parse::<ApiKey>(), Err("expected KEY:ROLE".to_string()));
+        assert_eq!(
+            "key:owner".parse::<ApiKey>(),
+            Err("unknown role `owner`, expected read-only, writer or admin".to_string())
+        );
+        assert_eq!(ApiKey { key: "key".into(), role: Role::ReadOnly }.to_string(), "key:read-only");
+    }
+
+    #[test]
+    fn request_permissions() {
+        assert_eq!(Permission::of(&hyper::Method::Get, "/users/1/visits"), Permission::Read);
+        assert_eq!(Permission::of(&hyper::Method::Get, "/users/1/history"), Permission::Admin);
+        assert_eq!(Permission::of(&hyper::Method::Post, "/users/new"), Permission::Write);
+        assert_eq!(Permission::of(&hyper::Method::Patch, "/users/1"), Permission::Write);
+    }
+
+    #[test]
+    fn authorize_roles() {
+        let api_keys = api_keys();
+
+        assert_eq!(authorize(&api_keys, None, Permission::Read), Err(Error::MissingKey));
+        assert_eq!(authorize(&api_keys, Some("readers"), Permission::Read), Err(Error::UnknownKey));
+        assert_eq!(authorize(&api_keys, Some("reader"), Permission::Read), Ok(Role::ReadOnly));
+        assert_eq!(
+            authorize(&api_keys, Some("reader"), Permission::Write),
+            Err(Error::Forbidden(Role::ReadOnly, Permission::Write))
+        );
+        assert_eq!(authorize(&api_keys, Some("writer"), Permission::Write), Ok(Role::Writer));
+        assert_eq!(
+            authorize(&api_keys, Some("writer"), Permission::Admin),
+            Err(Error::Forbidden(Role::Writer, Permission::Admin))
+        );
+        assert_eq!(authorize(&api_keys, Some("root"), Permission::Admin), Ok(Role::Admin));
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::VecDeque;
+
+use futures::sync::mpsc;
+use serde_json::Value;
+
+use super::models::*;
+
+/// Changes missed by resuming subscriber
+#[derive(Debug, PartialEq)]
+pub enum Backlog {
+    Changes(Vec<Change>),
+    /// Some changes after `since` are no longer buffered, subscriber has to reload snapshot.
+    /// Holds sequence number of last change.
+    Reset(u64),
+}
+
+/// False when subscriber is disconnected or lagging
+fn send(subscriber: &mut mpsc::Sender<Change>, change: &Change) -> bool {
+    match subscriber.try_send(change.clone()) {
+        Ok(()) => true,
+        Err(ref err) if err.is_full() => {
+            warn!("Drop subscriber lagging behind change {}", change.seq);
+            false
+        },
+        Err(_) => false,
+    }
+}
+
+/// Sequenced feed of accepted mutations. Keeps at most `capacity` latest changes for resuming subscribers.
+/// Subscriber is dropped when it falls behind by more than its queue holds, so its receiver ends.
+pub struct ChangeFeed {
+    next_seq: u64,
+    capacity: usize,
+    buffer: VecDeque<Change>,
+    subscribers: Vec<mpsc::Sender<Change>>,
+}
+
+impl ChangeFeed {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            next_seq: 1,
+            capacity: capacity,
+            buffer: VecDeque::new(),
+            subscribers: Vec::new(),
+        }
+    }
+
+    /// Assign next sequence number and send change to subscribers. Never blocks.
+    pub fn publish(&mut self, entity: EntityKind, id: Id, version: Version, data: Value) -> u64 {
+        let change = Change {
+            seq: self.next_seq,
+            entity: entity,
+            id: id,
+            version: version,
+            data: data,
+        };
+        self.next_seq += 1;
+        debug!("Publish change {:?}", change);
+
+        // Disconnected and lagging subscribers are dropped
+        self.subscribers = self.subscribers.drain(..)
+            .filter_map(|mut subscriber| if send(&mut subscriber, &change) { Some(subscriber) } else { None })
+            .collect();
+
+        if self.capacity > 0 {
+            if self.buffer.len() >= self.capacity {
+                self.buffer.pop_front();
+            }
+            self.buffer.push_back(change.clone());
+        }
+
+        change.seq
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.next_seq - 1
+    }
+
+    /// Continue after `last_seq` of replaced state. Buffered changes are discarded and subscribers are dropped,
+    /// so their receivers end and they have to reload state.
+    pub fn reset(&mut self, last_seq: u64) {
+        debug!("Reset change feed to {}", last_seq);
+        self.next_seq = last_seq + 1;
+        self.buffer.clear();
+        self.subscribers.clear();
+    }
+
+    /// Buffered changes after `since` and receiver of next ones, closed when more than `limit` of them
+    /// are not received yet. Sequence numbers ahead of feed are from previous process run,
+    /// so those subscribers are reset as well.
+    pub fn subscribe(&mut self, since: Option<u64>, limit: usize) -> (Backlog, mpsc::Receiver<Change>) {
+        let oldest_seq = self.buffer.front().map_or(self.next_seq, |change| change.seq);
+        let backlog = match since {
+            Some(since) if since > self.last_seq() || since + 1 < oldest_seq => Backlog::Reset(self.last_seq()),
+            Some(since) => Backlog::Changes(
+                self.buffer.iter()
+                    .filter(|change| change.seq > since)
+                    .cloned()
+                    .collect()
+            ),
+            None => Backlog::Changes(Vec::new()),
+        };
+
+        (backlog, self.subscribe_bounded(limit))
+    }
+
+    /// Receiver of next changes, closed when more than `limit` of them are not received yet
+    pub fn subscribe_bounded(&mut self, limit: usize) -> mpsc::Receiver<Change> {
+        let (sender, receiver) = mpsc::channel(limit);
+        self.subscribers.push(sender);
+        receiver
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{Future, Stream};
+
+    fn seqs(changes: &[Change]) -> Vec<u64> {
+        changes.iter().map(|change| change.seq).collect()
+    }
+
+    fn backlog_seqs(backlog: Backlog) -> Vec<u64> {
+        match backlog {
+            Backlog::Changes(changes) => seqs(&changes),
+            Backlog::Reset(last_seq) => panic!("Unexpected reset at {}", last_seq),
+        }
+    }
+
+    #[test]
+    fn publish_to_subscribers() {
+        let mut feed = ChangeFeed::new(10);
+        feed.publish(EntityKind::Users, 1, 1, json!({"id": 1}));
+
+        let (backlog, receiver) = feed.subscribe(None, 10);
+        assert_eq!(backlog, Backlog::Changes(Vec::new()));
+
+        feed.publish(EntityKind::Visits, 2, 1, json!({"id": 2}));
+        feed.publish(EntityKind::Locations, 3, 1, json!({"id": 3}));
+        drop(feed);
+
+        let changes = receiver.collect().wait().unwrap();
+        assert_eq!(seqs(&changes), vec![2, 3]);
+        assert_eq!(changes[0].entity, EntityKind::Visits);
+        assert_eq!(changes[0].id, 2);
+        assert_eq!(changes[0].data, json!({"id": 2}));
+    }
+
+    #[test]
+    fn resume_from_buffer() {
+        let mut feed = ChangeFeed::new(2);
+        for id in 1..4 {
+            feed.publish(EntityKind::Users, id, 1, json!({"id": id}));
+        }
+
+        assert_eq!(backlog_seqs(feed.subscribe(Some(1), 10).0), vec![2, 3]);
+        assert_eq!(backlog_seqs(feed.subscribe(Some(2), 10).0), vec![3]);
+        assert!(backlog_seqs(feed.subscribe(Some(3), 10).0).is_empty());
+    }
+
+    #[test]
+    fn reset_when_changes_are_lost() {
+        let mut feed = ChangeFeed::new(2);
+        assert!(backlog_seqs(feed.subscribe(Some(0), 10).0).is_empty());
+        assert_eq!(feed.subscribe(Some(5), 10).0, Backlog::Reset(0));
+
+        for id in 1..5 {
+            feed.publish(EntityKind::Users, id, 1, json!({"id": id}));
+        }
+        assert_eq!(feed.subscribe(Some(1), 10).0, Backlog::Reset(4));
+        assert_eq!(backlog_seqs(feed.subscribe(Some(2), 10).0), vec![3, 4]);
+        assert_eq!(feed.subscribe(Some(7), 10).0, Backlog::Reset(4));
+
+        let mut unbuffered = ChangeFeed::new(0);
+        unbuffered.publish(EntityKind::Users, 1, 1, json!({"id": 1}));
+        assert_eq!(unbuffered.subscribe(Some(0), 10).0, Backlog::Reset(1));
+        assert!(backlog_seqs(unbuffered.subscribe(Some(1), 10).0).is_empty());
+    }
+
+    #[test]
+    fn drop_closed_subscribers() {
+        let mut feed = ChangeFeed::new(0);
+        let (_, receiver) = feed.subscribe(None, 10);
+        drop(receiver);
+
+        feed.publish(EntityKind::Users, 1, 1, json!({"id": 1}));
+        assert!(feed.subscribers.is_empty());
+    }
+
+    #[test]
+    fn drop_lagging_subscribers() {
+        let mut feed = ChangeFeed::new(0);
+        let receiver = feed.subscribe_bounded(2);
+        for id in 1..10 {
+            feed.publish(EntityKind::Users, id, 1, json!({"id": id}));
+        }
+        assert!(feed.subscribers.is_empty());
+
+        // Queued changes are still received before end of stream
+        let changes = receiver.collect().wait().unwrap();
+        assert_eq!(seqs(&changes), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn reset_to_restored_seq() {
+        let mut feed = ChangeFeed::new(10);
+        feed.publish(EntityKind::Users, 1, 1, json!({"id": 1}));
+        let receiver = feed.subscribe_bounded(10);
+
+        feed.reset(20);
+        assert!(feed.subscribers.is_empty());
+        assert!(receiver.collect().wait().unwrap().is_empty());
+        assert_eq!(feed.subscribe(Some(1), 10).0, Backlog::Reset(20));
+
+        assert_eq!(feed.publish(EntityKind::Users, 1, 2, json!({"id": 1})), 21);
+        assert_eq!(backlog_seqs(feed.subscribe(Some(20), 10).0), vec![21]);
+    }
+}
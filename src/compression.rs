@@ -0,0 +1,95 @@
+use std::io;
+use std::io::Write;
+
+use flate2;
+use hyper::header::{
+    AcceptEncoding,
+    Encoding,
+    q,
+};
+
+const SUPPORTED: [Encoding; 2] = [Encoding::Gzip, Encoding::Deflate];
+
+/// Content encoding preferred by client among supported ones. Equal quality prefers gzip.
+/// Wildcard matches only encodings the client does not list (RFC 7231 section 5.3.4).
+pub fn negotiate(accept_encoding: &AcceptEncoding) -> Option<Encoding> {
+    let listed = |encoding: &Encoding| accept_encoding.iter().any(|item| item.item == *encoding);
+
+    let mut best: Option<(Encoding, _)> = None;
+    for item in accept_encoding.iter() {
+        let encodings = match item.item {
+            Encoding::Gzip | Encoding::Deflate => vec![item.item.clone()],
+            Encoding::EncodingExt(ref name) if name == "*" =>
+                SUPPORTED.iter().filter(|encoding| !listed(encoding)).cloned().collect(),
+            _ => continue,
+        };
+        for encoding in encodings {
+            let better = match best {
+                Some((ref best_encoding, best_quality)) =>
+                    item.quality > best_quality || (item.quality == best_quality && encoding == Encoding::Gzip && *best_encoding != Encoding::Gzip),
+                None => true,
+            };
+            if item.quality > q(0) && better {
+                best = Some((encoding, item.quality));
+            }
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Body encoded with gzip or deflate (zlib format as required by HTTP)
+pub fn compress(encoding: &Encoding, body: &[u8]) -> io::Result<Vec<u8>> {
+    match *encoding {
+        Encoding::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::Default);
+            encoder.write_all(body)?;
+            encoder.finish()
+        },
+        Encoding::Deflate => {
+            let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::Default);
+            encoder.write_all(body)?;
+            encoder.finish()
+        },
+        ref encoding => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unsupported encoding {}", encoding))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use hyper::header::Header;
+
+    fn accept_encoding(value: &str) -> AcceptEncoding {
+        AcceptEncoding::parse_header(&value.into()).unwrap()
+    }
+
+    #[test]
+    fn negotiate_encoding() {
+        assert_eq!(negotiate(&accept_encoding("gzip, deflate, br")), Some(Encoding::Gzip));
+        assert_eq!(negotiate(&accept_encoding("deflate, gzip")), Some(Encoding::Gzip));
+        assert_eq!(negotiate(&accept_encoding("gzip;q=0.5, deflate")), Some(Encoding::Deflate));
+        assert_eq!(negotiate(&accept_encoding("deflate;q=0.5, *")), Some(Encoding::Gzip));
+        assert_eq!(negotiate(&accept_encoding("gzip;q=0, br")), None);
+        assert_eq!(negotiate(&accept_encoding("gzip;q=0, *")), Some(Encoding::Deflate));
+        assert_eq!(negotiate(&accept_encoding("*, gzip;q=0, deflate;q=0")), None);
+        assert_eq!(negotiate(&accept_encoding("*;q=0")), None);
+        assert_eq!(negotiate(&accept_encoding("identity")), None);
+    }
+
+    #[test]
+    fn compress_body() {
+        let body = b"{\"visits\":[{\"mark\":5,\"visited_at\":0,\"place\":\"Musei\"}]}".repeat(10);
+
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(&compress(&Encoding::Gzip, &body).unwrap()[..]).unwrap()
+            .read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, body);
+
+        let deflated = compress(&Encoding::Deflate, &body).unwrap();
+        assert!(deflated.len() < body.len());
+        decoded.clear();
+        flate2::read::ZlibDecoder::new(&deflated[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, body);
+    }
+}
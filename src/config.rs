@@ -0,0 +1,765 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use toml;
+
+use super::auth::ApiKey;
+use super::limits::BodyLimits;
+
+/// Option key, default value and description. Environment variable is upper-cased key,
+/// command line flag is key with dashes, e.g. `data_path`, `DATA_PATH` and `--data-path`.
+const OPTIONS: &'static [(&'static str, &'static str, &'static str)] = &[
+    ("listen", "127.0.0.1:9999", "Comma separated HTTP listen addresses, unix:PATH for Unix socket"),
+    ("ipv6_only", "false", "Accept only IPv6 connections on IPv6 addresses instead of dual-stack"),
+    ("backlog", "1024", "Listen backlog"),
+    ("data_path", "data", "Directory with options.txt and data.zip"),
+    ("threads", "4", "Number of server threads"),
+    ("history_retention", "0", "Number of history entries to keep per entity, 0 disables history"),
+    ("changes_buffer", "10000", "Number of changes kept for resuming feed subscribers"),
+    ("changes_heartbeat_secs", "15", "Interval of change feed heartbeat comments"),
+    ("changes_subscriber_buffer", "1000", "Number of changes queued for feed subscriber before it is reset as lagging"),
+    ("replication_listen", "off", "Address to stream changes to replicas on"),
+    ("replicate_from", "off", "Replication address of primary, makes instance a read-only replica"),
+    ("replication_api_key", "off", "API key with admin role replica presents to primary"),
+    ("replication_buffer", "10000", "Number of changes queued for replica before it is disconnected as lagging"),
+    ("slow_query_threshold_ms", "100", "Store queries taking longer are logged with their options to slow_query target"),
+    ("max_scanned_visits", "1000000", "Top locations queries filtering more visits are rejected"),
+    ("strict", "false", "Reject unknown fields and values of wrong type in request bodies"),
+    ("api_keys", "off", "Comma separated API keys as KEY:ROLE, role is read-only, writer or admin"),
+    ("read_rate_limit", "off", "Read requests per second allowed to client IP or API key"),
+    ("write_rate_limit", "off", "Write requests per second allowed to client IP or API key"),
+    ("rate_limit_burst_secs", "1", "Seconds of requests client may send at once before rate limit applies"),
+    ("max_connections", "off", "Maximum open client connections per server thread"),
+    ("max_body_size", "1048576", "Maximum request body size in bytes"),
+    ("header_read_timeout_secs", "10", "Time to receive request head after its first byte"),
+    ("body_read_timeout_secs", "30", "Time to receive request body"),
+    ("keep_alive_timeout_secs", "60", "Time idle connection is kept open waiting for next request"),
+    ("access_log", "off", "File of JSON lines access log"),
+    ("access_log_max_size", "104857600", "Size in bytes access log is rotated at"),
+    ("access_log_files", "5", "Number of rotated access log files kept"),
+    ("compression_threshold", "1024", "Minimal response size in bytes to compress with gzip or deflate"),
+    ("tls_listen", "off", "Comma separated HTTPS listen addresses"),
+    ("tls_cert", "off", "PEM certificate chain of HTTPS listeners, reloaded on SIGHUP"),
+    ("tls_key", "off", "PEM private key of HTTPS listeners, reloaded on SIGHUP"),
+    ("stream_keepalive_secs", "30", "TCP keepalive of client connections"),
+    ("stream_linger_secs", "5", "SO_LINGER of client connections"),
+    ("stream_nodelay", "true", "TCP_NODELAY of client connections"),
+    ("stream_send_buffer_size", "262144", "Send buffer size of client connections"),
+    ("stream_recv_buffer_size", "262144", "Receive buffer size of client connections"),
+];
+
+/// Value of optional setting which disables it
+const OFF: &'static str = "off";
+
+/// Replaces API keys in printed config and errors
+const MASK: &'static str = "********";
+
+/// Options with API keys, their values are never printed
+const SECRET_OPTIONS: &'static [&'static str] = &["api_keys", "replication_api_key"];
+
+const UNIX_PREFIX: &'static str = "unix:";
+
+const CONFIG_ENV: &'static str = "CONFIG";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Default,
+    File(String),
+    Env(String),
+    Flag(String),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Source::Default => write!(f, "default"),
+            Source::File(ref path) => write!(f, "config file {}", path),
+            Source::Env(ref name) => write!(f, "environment variable {}", name),
+            Source::Flag(ref flag) => write!(f, "flag {}", flag),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    IoError(String, io::Error),
+    TomlError(String, toml::de::Error),
+    UnknownOption(String, Source),
+    MissingValue(String),
+    MissingOption {
+        key: String,
+        required_by: String,
+    },
+    InvalidValue {
+        key: String,
+        value: String,
+        source: Source,
+        message: String,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::IoError(ref path, ref err) =>
+                write!(f, "can not read config file {}: {}", path, err),
+            Error::TomlError(ref path, ref err) =>
+                write!(f, "invalid config file {}: {}", path, err),
+            Error::UnknownOption(ref key, ref source) =>
+                write!(f, "unknown option `{}` in {}", key, source),
+            Error::MissingValue(ref flag) =>
+                write!(f, "flag {} requires a value", flag),
+            Error::MissingOption { ref key, ref required_by } =>
+                write!(f, "option `{}` is required by `{}`", key, required_by),
+            Error::InvalidValue { ref key, ref value, ref source, ref message } =>
+                write!(f, "invalid value `{}` of `{}` from {}: {}", value, key, source, message),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Listen {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Listen {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value.starts_with(UNIX_PREFIX) {
+            match &value[UNIX_PREFIX.len()..] {
+                "" => Err("empty Unix socket path".to_string()),
+                path => Ok(Listen::Unix(PathBuf::from(path))),
+            }
+        } else {
+            value.parse().map(Listen::Tcp).map_err(|err: ::std::net::AddrParseError| err.to_string())
+        }
+    }
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Listen::Tcp(ref address) => write!(f, "{}", address),
+            Listen::Unix(ref path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub listen: Vec<Listen>,
+    pub ipv6_only: bool,
+    pub backlog: i32,
+    pub data_path: String,
+    pub threads: usize,
+    pub history_retention: usize,
+    pub changes_buffer: usize,
+    pub changes_heartbeat_secs: u64,
+    pub changes_subscriber_buffer: usize,
+    pub replication_listen: Option<SocketAddr>,
+    pub replicate_from: Option<SocketAddr>,
+    pub replication_api_key: Option<String>,
+    pub replication_buffer: usize,
+    pub slow_query_threshold_ms: Option<u64>,
+    pub max_scanned_visits: Option<usize>,
+    pub strict: bool,
+    pub api_keys: Vec<ApiKey>,
+    pub read_rate_limit: Option<u64>,
+    pub write_rate_limit: Option<u64>,
+    pub rate_limit_burst_secs: u64,
+    pub max_connections: Option<usize>,
+    pub max_body_size: Option<u64>,
+    pub header_read_timeout_secs: Option<u64>,
+    pub body_read_timeout_secs: Option<u64>,
+    pub keep_alive_timeout_secs: Option<u64>,
+    pub access_log: Option<PathBuf>,
+    pub access_log_max_size: u64,
+    pub access_log_files: usize,
+    pub compression_threshold: Option<u64>,
+    pub tls_listen: Vec<SocketAddr>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub stream_keepalive_secs: Option<u64>,
+    pub stream_linger_secs: Option<u64>,
+    pub stream_nodelay: bool,
+    pub stream_send_buffer_size: usize,
+    pub stream_recv_buffer_size: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Run(Config),
+    PrintConfig(Config),
+    Help,
+}
+
+struct Values(BTreeMap<&'static str, (String, Source)>);
+
+impl Values {
+    fn set(&mut self, key: &str, value: String, source: Source) -> Result<(), Error> {
+        match OPTIONS.iter().find(|&&(name, _, _)| name == key) {
+            Some(&(name, _, _)) => {
+                self.0.insert(name, (value, source));
+                Ok(())
+            },
+            None => Err(Error::UnknownOption(key.to_string(), source)),
+        }
+    }
+
+    fn parse_with<T, F>(&self, key: &str, parse: F) -> Result<T, Error>
+    where F: FnOnce(&str) -> Result<T, String>
+    {
+        let &(ref value, ref source) = &self.0[key];
+        parse(value).map_err(|message| Error::InvalidValue {
+            key: key.to_string(),
+            value: if SECRET_OPTIONS.contains(&key) { MASK.to_string() } else { value.clone() },
+            source: source.clone(),
+            message: message,
+        })
+    }
+
+    fn parse<T>(&self, key: &str) -> Result<T, Error>
+    where T: FromStr, T::Err: fmt::Display
+    {
+        self.parse_with(key, |value| value.parse().map_err(|err: T::Err| err.to_string()))
+    }
+
+    fn parse_positive<T>(&self, key: &str) -> Result<T, Error>
+    where T: FromStr + Default + PartialOrd, T::Err: fmt::Display
+    {
+        self.parse_with(key, |value| match value.parse::<T>() {
+            Ok(number) => if number > T::default() { Ok(number) } else { Err("must be positive".to_string()) },
+            Err(err) => Err(err.to_string()),
+        })
+    }
+
+    fn parse_optional<T>(&self, key: &str) -> Result<Option<T>, Error>
+    where T: FromStr, T::Err: fmt::Display
+    {
+        self.parse_with(key, |value| match value {
+            OFF => Ok(None),
+            value => value.parse().map(Some).map_err(|err: T::Err| err.to_string()),
+        })
+    }
+
+    fn parse_optional_positive<T>(&self, key: &str) -> Result<Option<T>, Error>
+    where T: FromStr + Default + PartialOrd, T::Err: fmt::Display
+    {
+        self.parse_with(key, |value| match value {
+            OFF => Ok(None),
+            value => match value.parse::<T>() {
+                Ok(number) => if number > T::default() { Ok(Some(number)) } else { Err("must be positive".to_string()) },
+                Err(err) => Err(err.to_string()),
+            },
+        })
+    }
+
+    fn parse_list<T>(&self, key: &str) -> Result<Vec<T>, Error>
+    where T: FromStr, T::Err: fmt::Display
+    {
+        self.parse_with(key, |value| {
+            let items = parse_items(value)?;
+            if items.is_empty() {
+                Err("at least one value required".to_string())
+            } else {
+                Ok(items)
+            }
+        })
+    }
+
+    fn parse_optional_list<T>(&self, key: &str) -> Result<Vec<T>, Error>
+    where T: FromStr, T::Err: fmt::Display
+    {
+        self.parse_with(key, |value| match value {
+            OFF => Ok(Vec::new()),
+            value => parse_items(value),
+        })
+    }
+
+    /// List of secrets, invalid item is reported by its position only
+    fn parse_optional_secret_list<T>(&self, key: &str) -> Result<Vec<T>, Error>
+    where T: FromStr, T::Err: fmt::Display
+    {
+        self.parse_with(key, |value| match value {
+            OFF => Ok(Vec::new()),
+            value => value.split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .enumerate()
+                .map(|(index, item)| item.parse().map_err(|err: T::Err| format!("item {}: {}", index + 1, err)))
+                .collect(),
+        })
+    }
+
+    fn parse_bool(&self, key: &str) -> Result<bool, Error> {
+        self.parse_with(key, |value| match value {
+            "true" | "1" => Ok(true),
+            "false" | "0" => Ok(false),
+            _ => Err("expected true or false".to_string()),
+        })
+    }
+
+    fn config(&self) -> Result<Config, Error> {
+        Ok(Config {
+            listen: self.parse_list("listen")?,
+            ipv6_only: self.parse_bool("ipv6_only")?,
+            backlog: self.parse_positive("backlog")?,
+            data_path: self.parse("data_path")?,
+            threads: self.parse_positive("threads")?,
+            history_retention: self.parse("history_retention")?,
+            changes_buffer: self.parse("changes_buffer")?,
+            changes_heartbeat_secs: self.parse_positive("changes_heartbeat_secs")?,
+            changes_subscriber_buffer: self.parse_positive("changes_subscriber_buffer")?,
+            replication_listen: self.parse_optional("replication_listen")?,
+            replicate_from: self.parse_optional("replicate_from")?,
+            replication_api_key: self.parse_optional("replication_api_key")?,
+            replication_buffer: self.parse_positive("replication_buffer")?,
+            slow_query_threshold_ms: self.parse_optional("slow_query_threshold_ms")?,
+            max_scanned_visits: self.parse_optional_positive("max_scanned_visits")?,
+            strict: self.parse_bool("strict")?,
+            api_keys: self.parse_optional_secret_list("api_keys")?,
+            read_rate_limit: self.parse_optional_positive("read_rate_limit")?,
+            write_rate_limit: self.parse_optional_positive("write_rate_limit")?,
+            rate_limit_burst_secs: self.parse_positive("rate_limit_burst_secs")?,
+            max_connections: self.parse_optional_positive("max_connections")?,
+            max_body_size: self.parse_optional_positive("max_body_size")?,
+            header_read_timeout_secs: self.parse_optional_positive("header_read_timeout_secs")?,
+            body_read_timeout_secs: self.parse_optional_positive("body_read_timeout_secs")?,
+            keep_alive_timeout_secs: self.parse_optional_positive("keep_alive_timeout_secs")?,
+            access_log: self.parse_optional("access_log")?,
+            access_log_max_size: self.parse_positive("access_log_max_size")?,
+            access_log_files: self.parse("access_log_files")?,
+            compression_threshold: self.parse_optional("compression_threshold")?,
+            tls_listen: self.parse_optional_list("tls_listen")?,
+            tls_cert: self.parse_optional("tls_cert")?,
+            tls_key: self.parse_optional("tls_key")?,
+            stream_keepalive_secs: self.parse_optional("stream_keepalive_secs")?,
+            stream_linger_secs: self.parse_optional("stream_linger_secs")?,
+            stream_nodelay: self.parse_bool("stream_nodelay")?,
+            stream_send_buffer_size: self.parse_positive("stream_send_buffer_size")?,
+            stream_recv_buffer_size: self.parse_positive("stream_recv_buffer_size")?,
+        })
+    }
+}
+
+fn parse_items<T>(value: &str) -> Result<Vec<T>, String>
+where T: FromStr, T::Err: fmt::Display
+{
+    value.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| item.parse().map_err(|err: T::Err| format!("{}: {}", item, err)))
+        .collect()
+}
+
+fn env_name(key: &str) -> String {
+    key.to_uppercase()
+}
+
+fn flag_name(key: &str) -> String {
+    format!("--{}", key.replace('_', "-"))
+}
+
+fn load_file(values: &mut Values, path: &str) -> Result<(), Error> {
+    let mut content = String::new();
+    fs::File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut content))
+        .map_err(|err| Error::IoError(path.to_string(), err))?;
+
+    let table = match content.parse::<toml::Value>() {
+        Ok(toml::Value::Table(table)) => table,
+        Ok(_) => return Ok(()),
+        Err(err) => return Err(Error::TomlError(path.to_string(), err)),
+    };
+
+    for (key, value) in table {
+        let source = Source::File(path.to_string());
+        let value = match value {
+            toml::Value::String(value) => value,
+            toml::Value::Integer(value) => value.to_string(),
+            toml::Value::Boolean(value) => value.to_string(),
+            toml::Value::Array(ref items) if items.iter().all(toml::Value::is_str) =>
+                items.iter()
+                    .filter_map(toml::Value::as_str)
+                    .collect::<Vec<&str>>()
+                    .join(","),
+            value => return Err(Error::InvalidValue {
+                key: key,
+                value: value.to_string(),
+                source: source,
+                message: "expected string, array of strings, integer or boolean".to_string(),
+            }),
+        };
+        values.set(&key, value, source)?;
+    }
+
+    Ok(())
+}
+
+/// Build config from defaults, config file, environment and command line flags. Later source takes precedence.
+pub fn load(args: Vec<String>, env_vars: Vec<(String, String)>) -> Result<Command, Error> {
+    let mut flags = Vec::new();
+    let mut config_path = env_vars.iter()
+        .find(|&&(ref name, _)| name == CONFIG_ENV)
+        .map(|&(_, ref value)| value.clone());
+    let mut print_config = false;
+
+    let mut args = args.into_iter().peekable();
+    while let Some(arg) = args.next() {
+        let (flag, inline_value) = match arg.find('=') {
+            Some(index) => (arg[..index].to_string(), Some(arg[index + 1..].to_string())),
+            None => (arg.clone(), None),
+        };
+
+        match flag.as_str() {
+            "--help" | "-h" => return Ok(Command::Help),
+            "--print-config" => print_config = true,
+            _ => {
+                let key = if flag.starts_with("--") { flag[2..].replace('-', "_") } else { flag.clone() };
+                let is_bool = OPTIONS.iter().any(|&(name, default, _)| name == key && (default == "true" || default == "false"));
+                let separate_bool = is_bool && args.peek().map_or(false, |next| next == "true" || next == "false");
+                let value = match inline_value {
+                    Some(value) => value,
+                    None if is_bool && !separate_bool => "true".to_string(),
+                    None => args.next().ok_or_else(|| Error::MissingValue(flag.clone()))?,
+                };
+
+                if flag == "--config" {
+                    config_path = Some(value);
+                } else {
+                    flags.push((key, value, flag));
+                }
+            },
+        }
+    }
+
+    let mut values = Values(BTreeMap::new());
+    for &(key, default, _) in OPTIONS {
+        values.set(key, default.to_string(), Source::Default)?;
+    }
+
+    if let Some(ref path) = config_path {
+        load_file(&mut values, path)?;
+    }
+
+    for &(key, _, _) in OPTIONS {
+        let name = env_name(key);
+        if let Some(&(_, ref value)) = env_vars.iter().find(|&&(ref env_name, _)| *env_name == name) {
+            values.set(key, value.clone(), Source::Env(name))?;
+        }
+    }
+
+    for (key, value, flag) in flags {
+        values.set(&key, value, Source::Flag(flag))?;
+    }
+
+    let config = values.config()?;
+    let required = [
+        ("tls_cert", "tls_listen", !config.tls_listen.is_empty() && config.tls_cert.is_none()),
+        ("tls_key", "tls_listen", !config.tls_listen.is_empty() && config.tls_key.is_none()),
+        ("api_keys", "replication_listen", config.replication_listen.is_some() && config.api_keys.is_empty()),
+        ("replication_api_key", "replicate_from", config.replicate_from.is_some() && config.replication_api_key.is_none()),
+    ];
+    for &(key, required_by, missing) in &required {
+        if missing {
+            return Err(Error::MissingOption {
+                key: key.to_string(),
+                required_by: required_by.to_string(),
+            })
+        }
+    }
+
+    Ok(if print_config { Command::PrintConfig(config) } else { Command::Run(config) })
+}
+
+fn optional_value<T: ToString>(value: &Option<T>) -> toml::Value {
+    toml::Value::String(value.as_ref().map_or(OFF.to_string(), ToString::to_string))
+}
+
+fn optional_list<T: ToString>(values: &[T]) -> toml::Value {
+    if values.is_empty() {
+        toml::Value::String(OFF.to_string())
+    } else {
+        toml::Value::Array(values.iter().map(|value| toml::Value::String(value.to_string())).collect())
+    }
+}
+
+fn optional_path(value: &Option<PathBuf>) -> toml::Value {
+    toml::Value::String(value.as_ref().map_or(OFF.to_string(), |path| path.display().to_string()))
+}
+
+fn optional_integer(value: Option<u64>) -> toml::Value {
+    value.map_or(toml::Value::String(OFF.to_string()), |value| toml::Value::Integer(value as i64))
+}
+
+impl Config {
+    pub fn body_limits(&self) -> BodyLimits {
+        BodyLimits {
+            max_size: self.max_body_size,
+            timeout: self.body_read_timeout_secs.map(Duration::from_secs),
+        }
+    }
+
+    /// Config file content with API keys masked, so printed config does not leak them
+    pub fn to_toml(&self) -> String {
+        let api_keys = self.api_keys.iter()
+            .map(|api_key| ApiKey { key: MASK.to_string(), role: api_key.role })
+            .collect::<Vec<ApiKey>>();
+        let replication_api_key = self.replication_api_key.as_ref().map(|_| MASK);
+
+        let values = vec![
+            ("listen", toml::Value::Array(self.listen.iter().map(|listen| toml::Value::String(listen.to_string())).collect())),
+            ("ipv6_only", toml::Value::Boolean(self.ipv6_only)),
+            ("backlog", toml::Value::Integer(i64::from(self.backlog))),
+            ("data_path", toml::Value::String(self.data_path.clone())),
+            ("threads", toml::Value::Integer(self.threads as i64)),
+            ("history_retention", toml::Value::Integer(self.history_retention as i64)),
+            ("changes_buffer", toml::Value::Integer(self.changes_buffer as i64)),
+            ("changes_heartbeat_secs", toml::Value::Integer(self.changes_heartbeat_secs as i64)),
+            ("changes_subscriber_buffer", toml::Value::Integer(self.changes_subscriber_buffer as i64)),
+            ("replication_listen", optional_value(&self.replication_listen)),
+            ("replicate_from", optional_value(&self.replicate_from)),
+            ("replication_api_key", optional_value(&replication_api_key)),
+            ("replication_buffer", toml::Value::Integer(self.replication_buffer as i64)),
+            ("slow_query_threshold_ms", optional_integer(self.slow_query_threshold_ms)),
+            ("max_scanned_visits", optional_integer(self.max_scanned_visits.map(|max| max as u64))),
+            ("strict", toml::Value::Boolean(self.strict)),
+            ("api_keys", optional_list(&api_keys)),
+            ("read_rate_limit", optional_integer(self.read_rate_limit)),
+            ("write_rate_limit", optional_integer(self.write_rate_limit)),
+            ("rate_limit_burst_secs", toml::Value::Integer(self.rate_limit_burst_secs as i64)),
+            ("max_connections", optional_integer(self.max_connections.map(|max| max as u64))),
+            ("max_body_size", optional_integer(self.max_body_size)),
+            ("header_read_timeout_secs", optional_integer(self.header_read_timeout_secs)),
+            ("body_read_timeout_secs", optional_integer(self.body_read_timeout_secs)),
+            ("keep_alive_timeout_secs", optional_integer(self.keep_alive_timeout_secs)),
+            ("access_log", optional_path(&self.access_log)),
+            ("access_log_max_size", toml::Value::Integer(self.access_log_max_size as i64)),
+            ("access_log_files", toml::Value::Integer(self.access_log_files as i64)),
+            ("compression_threshold", optional_integer(self.compression_threshold)),
+            ("tls_listen", optional_list(&self.tls_listen)),
+            ("tls_cert", optional_path(&self.tls_cert)),
+            ("tls_key", optional_path(&self.tls_key)),
+            ("stream_keepalive_secs", optional_integer(self.stream_keepalive_secs)),
+            ("stream_linger_secs", optional_integer(self.stream_linger_secs)),
+            ("stream_nodelay", toml::Value::Boolean(self.stream_nodelay)),
+            ("stream_send_buffer_size", toml::Value::Integer(self.stream_send_buffer_size as i64)),
+            ("stream_recv_buffer_size", toml::Value::Integer(self.stream_recv_buffer_size as i64)),
+        ];
+
+        values.into_iter()
+            .map(|(key, value)| format!("{} = {}\n", key, value))
+            .collect()
+    }
+}
+
+pub fn usage() -> String {
+    let mut usage = String::from(
+        "Usage: hlcup1 [--config FILE] [--print-config] [--OPTION VALUE]...\n\n\
+         Options are read from defaults, config file (--config or CONFIG), environment and flags.\n\
+         Later source takes precedence. Optional settings are disabled with `off`.\n\n"
+    );
+    for &(key, default, description) in OPTIONS {
+        usage.push_str(&format!("  {:<28} {:<22} {} (default {})\n", flag_name(key), env_name(key), description, default));
+    }
+    usage
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::Write;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn env_vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter().map(|&(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    fn config_file(name: &str, content: &str) -> String {
+        let path = env::temp_dir().join(format!("hlcup1_{}_{}.toml", name, ::std::process::id()));
+        fs::File::create(&path).unwrap().write_all(content.as_bytes()).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn run_config(command: Command) -> Config {
+        match command {
+            Command::Run(config) => config,
+            command => panic!("Unexpected command {:?}", command),
+        }
+    }
+
+    #[test]
+    fn load_defaults() {
+        let config = run_config(load(args(&[]), env_vars(&[])).unwrap());
+        assert_eq!(config.listen, vec![Listen::Tcp("127.0.0.1:9999".parse().unwrap())]);
+        assert_eq!(config.threads, 4);
+        assert_eq!(config.replicate_from, None);
+        assert_eq!(config.stream_keepalive_secs, Some(30));
+        assert_eq!(config.strict, false);
+        assert!(config.tls_listen.is_empty());
+        assert_eq!(config.compression_threshold, Some(1024));
+        assert!(config.api_keys.is_empty());
+        assert_eq!(config.write_rate_limit, None);
+        assert_eq!(config.max_connections, None);
+        assert_eq!(config.max_body_size, Some(1048576));
+        assert_eq!(config.keep_alive_timeout_secs, Some(60));
+        assert_eq!(config.access_log, None);
+        assert_eq!(config.slow_query_threshold_ms, Some(100));
+        assert_eq!(config.max_scanned_visits, Some(1000000));
+    }
+
+    #[test]
+    fn load_with_precedence() {
+        let path = config_file(
+            "precedence",
+            "threads = 2\nbacklog = 10\ndata_path = \"/file\"\nstream_linger_secs = \"off\"\nlisten = [\"[::]:80\", \"unix:/tmp/a.sock\"]\n",
+        );
+
+        let config = run_config(load(
+            args(&["--config", &path, "--backlog=30", "--strict", "--stream-nodelay", "false", "--ipv6-only", "true"]),
+            env_vars(&[("BACKLOG", "20"), ("DATA_PATH", "/env"), ("PATH", "/bin")]),
+        ).unwrap());
+
+        assert_eq!(config.threads, 2);
+        assert_eq!(config.data_path, "/env");
+        assert_eq!(config.backlog, 30);
+        assert_eq!(config.stream_linger_secs, None);
+        assert_eq!(config.strict, true);
+        assert_eq!(config.stream_nodelay, false);
+        assert_eq!(config.ipv6_only, true);
+        assert_eq!(config.listen, vec![Listen::Tcp("[::]:80".parse().unwrap()), Listen::Unix("/tmp/a.sock".into())]);
+    }
+
+    #[test]
+    fn load_listen_list() {
+        let config = run_config(load(args(&["--listen", "127.0.0.1:80, [::1]:81,unix:/run/hlcup1.sock"]), env_vars(&[])).unwrap());
+        assert_eq!(config.listen, vec![
+            Listen::Tcp("127.0.0.1:80".parse().unwrap()),
+            Listen::Tcp("[::1]:81".parse().unwrap()),
+            Listen::Unix("/run/hlcup1.sock".into()),
+        ]);
+
+        assert_eq!(
+            load(args(&["--listen", "unix:"]), env_vars(&[])).unwrap_err().to_string(),
+            "invalid value `unix:` of `listen` from flag --listen: unix:: empty Unix socket path"
+        );
+        assert_eq!(
+            load(args(&["--listen", ","]), env_vars(&[])).unwrap_err().to_string(),
+            "invalid value `,` of `listen` from flag --listen: at least one value required"
+        );
+    }
+
+    #[test]
+    fn load_tls() {
+        let config = run_config(load(
+            args(&["--tls-listen", "0.0.0.0:443,[::]:443", "--tls-cert", "/etc/cert.pem"]),
+            env_vars(&[("TLS_KEY", "/etc/key.pem")]),
+        ).unwrap());
+        assert_eq!(config.tls_listen, vec!["0.0.0.0:443".parse().unwrap(), "[::]:443".parse().unwrap()]);
+        assert_eq!(config.tls_cert, Some("/etc/cert.pem".into()));
+        assert_eq!(config.tls_key, Some("/etc/key.pem".into()));
+
+        assert_eq!(
+            load(args(&["--tls-listen", "0.0.0.0:443", "--tls-cert", "/etc/cert.pem"]), env_vars(&[])).unwrap_err().to_string(),
+            "option `tls_key` is required by `tls_listen`"
+        );
+    }
+
+    #[test]
+    fn load_replication() {
+        let config = run_config(load(
+            args(&["--replication-listen", "127.0.0.1:9990", "--api-keys", "replica:admin", "--replication-buffer", "100"]),
+            env_vars(&[]),
+        ).unwrap());
+        assert_eq!(config.replication_listen, Some("127.0.0.1:9990".parse().unwrap()));
+        assert_eq!(config.replication_buffer, 100);
+
+        assert_eq!(
+            load(args(&["--replication-listen", "127.0.0.1:9990"]), env_vars(&[])).unwrap_err().to_string(),
+            "option `api_keys` is required by `replication_listen`"
+        );
+        assert_eq!(
+            load(args(&["--replicate-from", "127.0.0.1:9990"]), env_vars(&[])).unwrap_err().to_string(),
+            "option `replication_api_key` is required by `replicate_from`"
+        );
+    }
+
+    #[test]
+    fn print_config() {
+        let flags = args(&[
+            "--print-config", "--replicate-from", "127.0.0.1:9990", "--replication-api-key", "replica",
+            "--tls-listen", "[::]:443", "--tls-cert", "cert.pem", "--tls-key", "key.pem",
+            "--api-keys", "reader:read-only,root:admin",
+            "--read-rate-limit", "100", "--write-rate-limit", "10", "--max-connections", "1000",
+            "--max-body-size", "off", "--body-read-timeout-secs", "5",
+            "--access-log", "/var/log/hlcup1/access.log", "--access-log-files", "0",
+            "--slow-query-threshold-ms", "off",
+        ]);
+        let config = match load(flags, env_vars(&[])).unwrap() {
+            Command::PrintConfig(config) => config,
+            command => panic!("Unexpected command {:?}", command),
+        };
+
+        let toml = config.to_toml();
+        assert!(!toml.contains("reader:") && !toml.contains("\"replica\""));
+
+        let path = config_file("print", &toml);
+        assert_eq!(
+            run_config(load(args(&[]), env_vars(&[("CONFIG", &path)])).unwrap()),
+            Config {
+                api_keys: vec!["********:read-only".parse().unwrap(), "********:admin".parse().unwrap()],
+                replication_api_key: Some("********".into()),
+                ..config
+            }
+        );
+    }
+
+    #[test]
+    fn readable_errors() {
+        assert_eq!(
+            load(args(&["--threads", "0"]), env_vars(&[])).unwrap_err().to_string(),
+            "invalid value `0` of `threads` from flag --threads: must be positive"
+        );
+        assert_eq!(
+            load(args(&[]), env_vars(&[("LISTEN", "localhost")])).unwrap_err().to_string(),
+            "invalid value `localhost` of `listen` from environment variable LISTEN: localhost: invalid socket address syntax"
+        );
+        assert_eq!(
+            load(args(&["--theads", "2"]), env_vars(&[])).unwrap_err().to_string(),
+            "unknown option `theads` in flag --theads"
+        );
+        assert_eq!(
+            load(args(&["--write-rate-limit", "0"]), env_vars(&[])).unwrap_err().to_string(),
+            "invalid value `0` of `write_rate_limit` from flag --write-rate-limit: must be positive"
+        );
+        assert_eq!(load(args(&["--threads"]), env_vars(&[])).unwrap_err().to_string(), "flag --threads requires a value");
+        assert_eq!(
+            load(args(&[]), env_vars(&[("API_KEYS", "first:admin,secret:root")])).unwrap_err().to_string(),
+            "invalid value `********` of `api_keys` from environment variable API_KEYS: \
+             item 2: unknown role `root`, expected read-only, writer or admin"
+        );
+        assert_eq!(
+            load(args(&["--api-keys", "secret"]), env_vars(&[])).unwrap_err().to_string(),
+            "invalid value `********` of `api_keys` from flag --api-keys: item 1: expected KEY:ROLE"
+        );
+
+        let path = config_file("errors", "strict = \"yes\"\n");
+        assert_eq!(
+            load(args(&["--config", &path]), env_vars(&[])).unwrap_err().to_string(),
+            format!("invalid value `yes` of `strict` from config file {}: expected true or false", path)
+        );
+    }
+}
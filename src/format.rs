@@ -0,0 +1,283 @@
+use csv;
+use hyper::header::{
+    Accept,
+    ContentType,
+    q,
+};
+use hyper::mime;
+use rmp_serde;
+use serde::de::DeserializeOwned;
+use serde::ser::Serialize;
+use serde_cbor;
+use serde_json;
+use serde_json::{
+    Map,
+    Value,
+};
+
+const MESSAGE_PACK_SUBTYPES: &'static [&'static str] = &["msgpack", "x-msgpack", "vnd.msgpack"];
+
+/// Column of CSV made from array of scalars
+const VALUE_COLUMN: &'static str = "value";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    MessagePack,
+    Cbor,
+    Csv,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    JsonError(serde_json::Error),
+    MessagePackEncodeError(rmp_serde::encode::Error),
+    MessagePackDecodeError(rmp_serde::decode::Error),
+    CborError(serde_cbor::error::Error),
+    CsvError(csv::Error),
+    CsvRecords(usize),
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::JsonError(err)
+    }
+}
+
+impl From<rmp_serde::encode::Error> for Error {
+    fn from(err: rmp_serde::encode::Error) -> Self {
+        Error::MessagePackEncodeError(err)
+    }
+}
+
+impl From<rmp_serde::decode::Error> for Error {
+    fn from(err: rmp_serde::decode::Error) -> Self {
+        Error::MessagePackDecodeError(err)
+    }
+}
+
+impl From<serde_cbor::error::Error> for Error {
+    fn from(err: serde_cbor::error::Error) -> Self {
+        Error::CborError(err)
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(err: csv::Error) -> Self {
+        Error::CsvError(err)
+    }
+}
+
+impl Format {
+    fn from_mime(mime: &mime::Mime) -> Option<Format> {
+        match (mime.type_().as_str(), mime.subtype().as_str()) {
+            ("application", "json") | ("application", "*") | ("*", "*") => Some(Format::Json),
+            ("application", "cbor") => Some(Format::Cbor),
+            ("application", subtype) if MESSAGE_PACK_SUBTYPES.contains(&subtype) => Some(Format::MessagePack),
+            ("text", "csv") | ("text", "*") => Some(Format::Csv),
+            _ => None,
+        }
+    }
+
+    /// Format of request body. Missing content type is JSON as before, unknown one is not supported.
+    pub fn from_content_type(content_type: Option<&ContentType>) -> Option<Format> {
+        match content_type {
+            Some(&ContentType(ref mime)) => Self::from_mime(mime),
+            None => Some(Format::Json),
+        }
+    }
+
+    /// Most preferred acceptable format. First one wins on equal quality, JSON without `Accept`.
+    pub fn negotiate(accept: Option<&Accept>) -> Option<Format> {
+        let accept = match accept {
+            Some(accept) => accept,
+            None => return Some(Format::Json),
+        };
+
+        let mut best: Option<(Format, _)> = None;
+        for item in accept.iter() {
+            if let Some(format) = Self::from_mime(&item.item) {
+                let better = best.as_ref().map_or(true, |&(_, quality)| item.quality > quality);
+                if item.quality > q(0) && better {
+                    best = Some((format, item.quality));
+                }
+            }
+        }
+        best.map(|(format, _)| format)
+    }
+
+    /// Short name distinguishing representations of the same entity
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Format::Json => "json",
+            Format::MessagePack => "msgpack",
+            Format::Cbor => "cbor",
+            Format::Csv => "csv",
+        }
+    }
+
+    pub fn mime(&self) -> mime::Mime {
+        match *self {
+            Format::Json => mime::APPLICATION_JSON,
+            Format::MessagePack => "application/msgpack".parse().unwrap(),
+            Format::Cbor => "application/cbor".parse().unwrap(),
+            Format::Csv => "text/csv; charset=utf-8".parse().unwrap(),
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, Error> {
+        match *self {
+            Format::Json => Ok(serde_json::to_vec(value)?),
+            Format::MessagePack => Ok(rmp_serde::to_vec_named(value)?),
+            Format::Cbor => Ok(serde_cbor::to_vec(value)?),
+            Format::Csv => encode_csv(&serde_json::to_value(value)?),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, body: &[u8]) -> Result<T, Error> {
+        match *self {
+            Format::Json => Ok(serde_json::from_slice(body)?),
+            Format::MessagePack => Ok(rmp_serde::from_slice(body)?),
+            Format::Cbor => Ok(serde_cbor::from_slice(body)?),
+            Format::Csv => Ok(serde_json::from_value(decode_csv(body)?)?),
+        }
+    }
+}
+
+fn csv_cell(value: &Value) -> String {
+    match *value {
+        Value::Null => String::new(),
+        Value::String(ref value) => value.clone(),
+        ref value => value.to_string(),
+    }
+}
+
+/// Rows are items of array or of the only array field of object, e.g. `visits` of `UserVisits`.
+/// Other object is a single row, nested values are written as JSON.
+fn encode_csv(value: &Value) -> Result<Vec<u8>, Error> {
+    let (rows, name) = match *value {
+        Value::Array(ref items) => (items.iter().collect::<Vec<&Value>>(), VALUE_COLUMN),
+        Value::Object(ref map) if map.len() == 1 && map.values().all(Value::is_array) => {
+            let (name, items) = map.iter().next().unwrap();
+            (items.as_array().unwrap().iter().collect(), name.as_str())
+        },
+        ref value => (vec![value], VALUE_COLUMN),
+    };
+
+    let mut columns: Vec<&str> = Vec::new();
+    for row in &rows {
+        match **row {
+            Value::Object(ref map) =>
+                for key in map.keys() {
+                    if !columns.contains(&key.as_str()) {
+                        columns.push(key);
+                    }
+                },
+            _ => if !columns.contains(&name) {
+                columns.push(name);
+            },
+        }
+    }
+
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(&columns)?;
+    for row in rows {
+        let record = columns.iter().map(|&column| match *row {
+            Value::Object(ref map) => map.get(column).map(csv_cell).unwrap_or_default(),
+            ref value if column == name => csv_cell(value),
+            _ => String::new(),
+        });
+        writer.write_record(record)?;
+    }
+    writer.into_inner().map_err(|err| Error::CsvError(err.into_error().into()))
+}
+
+/// Header and single record as object. Empty cells are left out, others are strings
+/// as CSV has no types, see `strict::parse_numbers`.
+fn decode_csv(body: &[u8]) -> Result<Value, Error> {
+    let mut reader = csv::Reader::from_reader(body);
+    let headers = reader.headers()?.clone();
+    let records = reader.records().collect::<Result<Vec<csv::StringRecord>, csv::Error>>()?;
+    if records.len() != 1 {
+        return Err(Error::CsvRecords(records.len()))
+    }
+
+    let map = headers.iter().zip(records[0].iter())
+        .filter(|&(_, cell)| !cell.is_empty())
+        .map(|(column, cell)| (column.to_string(), Value::String(cell.to_string())))
+        .collect::<Map<String, Value>>();
+    Ok(Value::Object(map))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::Header;
+    use models::*;
+
+    fn accept(value: &str) -> Accept {
+        Accept::parse_header(&value.into()).unwrap()
+    }
+
+    fn user_visits() -> UserVisits {
+        UserVisits {
+            visits: vec![
+                UserVisit { mark: 5, visited_at: 100, place: "Musei".into(), ..Default::default() },
+                UserVisit { mark: 3, visited_at: 200, place: "Park, \"Central\"".into(), ..Default::default() },
+            ],
+        }
+    }
+
+    #[test]
+    fn negotiate_format() {
+        assert_eq!(Format::negotiate(None), Some(Format::Json));
+        assert_eq!(Format::negotiate(Some(&accept("*/*"))), Some(Format::Json));
+        assert_eq!(Format::negotiate(Some(&accept("application/msgpack"))), Some(Format::MessagePack));
+        assert_eq!(Format::negotiate(Some(&accept("application/json;q=0.5, application/cbor"))), Some(Format::Cbor));
+        assert_eq!(Format::negotiate(Some(&accept("text/csv, application/json"))), Some(Format::Csv));
+        assert_eq!(Format::negotiate(Some(&accept("text/csv;q=0, text/html"))), None);
+    }
+
+    #[test]
+    fn content_type_format() {
+        assert_eq!(Format::from_content_type(None), Some(Format::Json));
+        assert_eq!(Format::from_content_type(Some(&ContentType::form_url_encoded())), None);
+        assert_eq!(Format::from_content_type(Some(&ContentType("application/x-msgpack".parse().unwrap()))), Some(Format::MessagePack));
+        assert_eq!(Format::from_content_type(Some(&ContentType("text/csv; charset=utf-8".parse().unwrap()))), Some(Format::Csv));
+    }
+
+    #[test]
+    fn binary_formats_round_trip() {
+        let value = serde_json::to_value(user_visits()).unwrap();
+        for format in &[Format::Json, Format::MessagePack, Format::Cbor] {
+            let decoded: Value = format.decode(&format.encode(&user_visits()).unwrap()).unwrap();
+            assert_eq!(decoded, value);
+        }
+    }
+
+    #[test]
+    fn encode_csv_rows() {
+        assert_eq!(
+            String::from_utf8(Format::Csv.encode(&user_visits()).unwrap()).unwrap(),
+            "mark,place,visited_at\n5,Musei,100\n3,\"Park, \"\"Central\"\"\",200\n"
+        );
+        assert_eq!(
+            String::from_utf8(Format::Csv.encode(&json!({"id": 1, "tags": ["a"]})).unwrap()).unwrap(),
+            "id,tags\n1,\"[\"\"a\"\"]\"\n"
+        );
+        assert_eq!(
+            String::from_utf8(Format::Csv.encode(&json!({"countries": ["Russia", "Chile"]})).unwrap()).unwrap(),
+            "countries\nRussia\nChile\n"
+        );
+    }
+
+    #[test]
+    fn decode_csv_record() {
+        assert_eq!(
+            Format::Csv.decode::<Value>(b"email,first_name,last_name,birth_date\nnew@mail.com,,123,-100\n").unwrap(),
+            json!({"email": "new@mail.com", "last_name": "123", "birth_date": "-100"})
+        );
+
+        assert_matches!(Format::Csv.decode::<Value>(b"email\na@mail.com\nb@mail.com\n"), Err(Error::CsvRecords(2)));
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::{
+    BTreeMap,
+    VecDeque,
+};
+use std::net::SocketAddr;
+
+use chrono::prelude::*;
+use fnv;
+use serde_json::Value;
+
+use super::models::*;
+
+/// Audit log of entity mutations. Keeps at most `retention` latest entries of each entity.
+pub struct History {
+    retention: usize,
+    entries: fnv::FnvHashMap<(EntityKind, Id), VecDeque<HistoryEntry>>,
+}
+
+impl History {
+    pub fn new(retention: usize) -> Self {
+        Self {
+            retention: retention,
+            entries: fnv::FnvHashMap::default(),
+        }
+    }
+
+    pub fn record(
+        &mut self,
+        entity: EntityKind,
+        id: Id,
+        before: Option<Value>,
+        after: Option<Value>,
+        client: Option<SocketAddr>,
+    ) {
+        let changes = diff(before, after);
+        if changes.is_empty() {
+            return
+        }
+
+        let entry = HistoryEntry {
+            entity: entity,
+            id: id,
+            time: Utc::now().timestamp(),
+            client: client.map(|client| client.to_string()),
+            changes: changes,
+        };
+        debug!("Record history {:?}", entry);
+
+        let entries = self.entries.entry((entity, id)).or_insert_with(VecDeque::new);
+        if entries.len() >= self.retention {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    pub fn get(&self, entity: EntityKind, id: Id) -> EntityHistory {
+        EntityHistory {
+            history: self.entries.get(&(entity, id))
+                .map_or_else(Vec::new, |entries| entries.iter().cloned().collect()),
+        }
+    }
+}
+
+fn object_fields(value: Option<Value>) -> BTreeMap<String, Value> {
+    match value {
+        Some(Value::Object(map)) => map.into_iter().collect(),
+        _ => BTreeMap::new(),
+    }
+}
+
+/// Changed fields only. Missing side (created entity) is `null`.
+fn diff(before: Option<Value>, after: Option<Value>) -> BTreeMap<String, FieldChange> {
+    let mut before = object_fields(before);
+    let after = object_fields(after);
+
+    let mut changes = BTreeMap::new();
+    for (field, after_value) in after {
+        let before_value = before.remove(&field).unwrap_or(Value::Null);
+        if before_value != after_value {
+            changes.insert(field, FieldChange {
+                before: before_value,
+                after: after_value,
+            });
+        }
+    }
+    for (field, before_value) in before {
+        changes.insert(field, FieldChange {
+            before: before_value,
+            after: Value::Null,
+        });
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_value(email: &str) -> Value {
+        json!({
+            "id": 1,
+            "email": email,
+            "first_name": "Vasia",
+        })
+    }
+
+    #[test]
+    fn record_changed_fields_only() {
+        let mut history = History::new(10);
+
+        history.record(EntityKind::Users, 1, None, Some(user_value("a@mail.com")), None);
+        history.record(EntityKind::Users, 1, Some(user_value("a@mail.com")), Some(user_value("a@mail.com")), None);
+        history.record(
+            EntityKind::Users,
+            1,
+            Some(user_value("a@mail.com")),
+            Some(user_value("b@mail.com")),
+            Some("127.0.0.1:1234".parse().unwrap()),
+        );
+
+        let entries = history.get(EntityKind::Users, 1).history;
+        assert_eq!(entries.len(), 2);
+
+        assert_eq!(entries[0].changes.len(), 3);
+        assert_eq!(entries[0].changes["email"], FieldChange { before: Value::Null, after: json!("a@mail.com") });
+
+        assert_eq!(entries[1].client, Some("127.0.0.1:1234".to_string()));
+        assert_eq!(entries[1].changes.len(), 1);
+        assert_eq!(entries[1].changes["email"], FieldChange { before: json!("a@mail.com"), after: json!("b@mail.com") });
+
+        assert!(history.get(EntityKind::Visits, 1).history.is_empty());
+    }
+
+    #[test]
+    fn keep_retention() {
+        let mut history = History::new(2);
+
+        history.record(EntityKind::Users, 1, None, Some(user_value("a@mail.com")), None);
+        history.record(EntityKind::Users, 1, Some(user_value("a@mail.com")), Some(user_value("b@mail.com")), None);
+        history.record(EntityKind::Users, 1, Some(user_value("b@mail.com")), Some(user_value("c@mail.com")), None);
+        history.record(EntityKind::Users, 2, None, Some(user_value("d@mail.com")), None);
+        history.record(EntityKind::Visits, 1, None, Some(json!({"id": 1})), None);
+        history.record(EntityKind::Visits, 1, Some(json!({"id": 1})), Some(json!({"id": 1, "mark": 5})), None);
+
+        assert_eq!(history.get(EntityKind::Users, 2).history.len(), 1);
+        assert_eq!(history.get(EntityKind::Visits, 1).history.len(), 2);
+
+        let entries = history.get(EntityKind::Users, 1).history;
+        assert_eq!(
+            entries.iter().map(|e| e.changes["email"].after.clone()).collect::<Vec<Value>>(),
+            vec![json!("b@mail.com"), json!("c@mail.com")]
+        );
+    }
+}
@@ -0,0 +1,753 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::io;
+use std::io::{
+    Read,
+    Write,
+};
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures::{
+    future,
+    Future,
+    Poll,
+    Stream,
+};
+use hyper;
+use tokio_core::reactor::{
+    Handle,
+    Timeout,
+};
+use tokio_io::{
+    AsyncRead,
+    AsyncWrite,
+};
+
+use super::auth::Permission;
+
+/// Number of tracked buckets before first pruning of idle clients
+const MIN_PRUNE_AT: usize = 1024;
+
+/// Client sharing request budget. Requests with known API key are limited by key, other by IP.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Client {
+    Ip(IpAddr),
+    ApiKey(String),
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum Budget {
+    Read,
+    Write,
+}
+
+impl Budget {
+    /// History is read with GET as well, so only `Write` spends write budget
+    fn of(permission: Permission) -> Self {
+        match permission {
+            Permission::Write => Budget::Write,
+            Permission::Read | Permission::Admin => Budget::Read,
+        }
+    }
+}
+
+/// Requests per second and bucket size
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Rate {
+    per_sec: f64,
+    burst: f64,
+}
+
+impl Rate {
+    fn new(per_sec: u64, burst_secs: u64) -> Self {
+        let per_sec = per_sec as f64;
+        Rate {
+            per_sec: per_sec,
+            burst: (per_sec * burst_secs as f64).max(1.0),
+        }
+    }
+
+    fn refill_time(&self) -> Duration {
+        Duration::from_secs_f64(self.burst / self.per_sec)
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn full(rate: &Rate, now: Instant) -> Self {
+        TokenBucket {
+            tokens: rate.burst,
+            updated: now,
+        }
+    }
+
+    /// Takes token or returns time until next one is available
+    fn take(&mut self, rate: &Rate, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.per_sec).min(rate.burst);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / rate.per_sec))
+        }
+    }
+}
+
+struct Buckets {
+    buckets: HashMap<(Client, Budget), TokenBucket>,
+    prune_at: usize,
+}
+
+/// Token buckets of clients shared by all server threads
+pub struct RateLimiter {
+    read: Option<Rate>,
+    write: Option<Rate>,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    /// Limits are requests per second, bucket holds `burst_secs` of requests
+    pub fn new(read: Option<u64>, write: Option<u64>, burst_secs: u64) -> Self {
+        RateLimiter {
+            read: read.map(|per_sec| Rate::new(per_sec, burst_secs)),
+            write: write.map(|per_sec| Rate::new(per_sec, burst_secs)),
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                prune_at: MIN_PRUNE_AT,
+            }),
+        }
+    }
+
+    /// Spends token of client budget. Error is delay rounded up to whole seconds as sent in `Retry-After`.
+    pub fn check(&self, client: Client, permission: Permission) -> Result<(), Duration> {
+        self.check_at(client, permission, Instant::now())
+    }
+
+    fn check_at(&self, client: Client, permission: Permission, now: Instant) -> Result<(), Duration> {
+        let budget = Budget::of(permission);
+        let rate = match budget {
+            Budget::Read => self.read,
+            Budget::Write => self.write,
+        };
+        let rate = match rate {
+            Some(rate) => rate,
+            None => return Ok(()),
+        };
+
+        // Buckets are plain counters, state of panicked thread is still usable
+        let mut buckets = self.buckets.lock().unwrap_or_else(|err| err.into_inner());
+        if buckets.buckets.len() >= buckets.prune_at {
+            self.prune(&mut buckets, now);
+        }
+
+        buckets.buckets.entry((client, budget))
+            .or_insert_with(|| TokenBucket::full(&rate, now))
+            .take(&rate, now)
+            .map_err(|delay| {
+                let secs = delay.as_secs() + if delay.subsec_nanos() > 0 { 1 } else { 0 };
+                Duration::from_secs(secs)
+            })
+    }
+
+    /// Buckets refilled since last request are the same as new ones
+    fn prune(&self, buckets: &mut Buckets, now: Instant) {
+        let (read, write) = (self.read, self.write);
+        buckets.buckets.retain(|&(_, budget), bucket| {
+            let rate = match budget {
+                Budget::Read => read,
+                Budget::Write => write,
+            };
+            rate.map_or(false, |rate| now.duration_since(bucket.updated) < rate.refill_time())
+        });
+        buckets.prune_at = (buckets.buckets.len() * 2).max(MIN_PRUNE_AT);
+        debug!("Pruned rate limit buckets, {} left", buckets.buckets.len());
+    }
+}
+
+/// Open connections of server thread
+#[derive(Clone)]
+pub struct ConnectionLimit {
+    open: Rc<Cell<usize>>,
+    max: Option<usize>,
+}
+
+/// Slot of open connection, released on drop
+pub struct Connection {
+    open: Rc<Cell<usize>>,
+    requests: Rc<Cell<usize>>,
+    closing: Rc<Cell<bool>>,
+}
+
+/// Request in progress on connection, finished on drop
+pub struct Request(Rc<Cell<usize>>);
+
+impl ConnectionLimit {
+    pub fn new(max: Option<usize>) -> Self {
+        ConnectionLimit {
+            open: Rc::new(Cell::new(0)),
+            max: max,
+        }
+    }
+
+    pub fn open(&self) -> usize {
+        self.open.get()
+    }
+
+    /// None when thread already has maximum connections open
+    pub fn acquire(&self) -> Option<Connection> {
+        if self.max.map_or(false, |max| self.open.get() >= max) {
+            return None
+        }
+        self.open.set(self.open.get() + 1);
+        Some(Connection {
+            open: self.open.clone(),
+            requests: Rc::new(Cell::new(0)),
+            closing: Rc::new(Cell::new(false)),
+        })
+    }
+}
+
+impl Connection {
+    /// Read timeouts of connection do not apply while request is in progress
+    pub fn start_request(&self) -> Request {
+        self.requests.set(self.requests.get() + 1);
+        Request(self.requests.clone())
+    }
+
+    /// Connection is closed after next response is written instead of reading rest of request,
+    /// e.g. when body is rejected
+    pub fn close(&self) {
+        self.closing.set(true);
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.open.set(self.open.get() - 1);
+    }
+}
+
+impl Drop for Request {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() - 1);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Phase {
+    /// Waiting for next request
+    Idle,
+    /// Request head started but not dispatched yet
+    Head,
+    /// Request in progress, its body has own timeout
+    Busy,
+}
+
+/// Connection closed with `TimedOut` error when client is idle or sends request head too slowly.
+/// Timeouts are checked only when server waits for client, so streamed responses are not cut.
+pub struct TimeoutIo<I> {
+    io: I,
+    requests: Rc<Cell<usize>>,
+    closing: Rc<Cell<bool>>,
+    /// Response of closing connection is written
+    closed: bool,
+    header_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    handle: Handle,
+    phase: Phase,
+    timer: Option<Timeout>,
+}
+
+impl<I> TimeoutIo<I> {
+    pub fn new(
+        io: I,
+        connection: &Connection,
+        header_timeout: Option<Duration>,
+        idle_timeout: Option<Duration>,
+        handle: &Handle,
+    ) -> Self {
+        Self {
+            io: io,
+            requests: connection.requests.clone(),
+            closing: connection.closing.clone(),
+            closed: false,
+            header_timeout: header_timeout,
+            idle_timeout: idle_timeout,
+            handle: handle.clone(),
+            // Becomes idle or starts head on first read
+            phase: Phase::Busy,
+            timer: None,
+        }
+    }
+
+    /// Header deadline starts with first byte of request and is not extended by following ones
+    fn update_phase(&mut self, received: bool) -> io::Result<()> {
+        let phase = match self.phase {
+            _ if self.requests.get() > 0 => Phase::Busy,
+            Phase::Idle | Phase::Busy if received => Phase::Head,
+            Phase::Busy => Phase::Idle,
+            phase => phase,
+        };
+
+        if phase != self.phase {
+            self.phase = phase;
+            let timeout = match phase {
+                Phase::Idle => self.idle_timeout,
+                Phase::Head => self.header_timeout,
+                Phase::Busy => None,
+            };
+            self.timer = match timeout {
+                Some(timeout) => Some(Timeout::new(timeout, &self.handle)?),
+                None => None,
+            };
+        }
+        Ok(())
+    }
+
+    fn check_timer(&mut self) -> io::Result<()> {
+        let expired = match self.timer {
+            Some(ref mut timer) => timer.poll()?.is_ready(),
+            None => false,
+        };
+        if expired {
+            let message = match self.phase {
+                Phase::Head => "request head is not received in time",
+                _ => "idle connection timed out",
+            };
+            return Err(io::Error::new(io::ErrorKind::TimedOut, message))
+        }
+        Ok(())
+    }
+}
+
+impl<I: Read> Read for TimeoutIo<I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.io.read(buf) {
+            Ok(size) => {
+                self.update_phase(size > 0)?;
+                Ok(size)
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                self.update_phase(false)?;
+                self.check_timer()?;
+                Err(io::ErrorKind::WouldBlock.into())
+            },
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl<I: Write> Write for TimeoutIo<I> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = self.io.write(buf)?;
+        self.closed = self.closing.get();
+        Ok(size)
+    }
+
+    /// HTTP/1 server flushes whole response at once, then it is dropped with the connection
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()?;
+        if self.closed {
+            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed after response"))
+        }
+        Ok(())
+    }
+}
+
+impl<I: AsyncRead> AsyncRead for TimeoutIo<I> {}
+
+impl<I: AsyncWrite> AsyncWrite for TimeoutIo<I> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.io.shutdown()
+    }
+}
+
+#[derive(Debug)]
+pub enum BodyError {
+    HyperError(hyper::Error),
+    IoError(io::Error),
+    TooLarge,
+    TimedOut,
+}
+
+impl From<hyper::Error> for BodyError {
+    fn from(err: hyper::Error) -> Self {
+        BodyError::HyperError(err)
+    }
+}
+
+impl From<io::Error> for BodyError {
+    fn from(err: io::Error) -> Self {
+        BodyError::IoError(err)
+    }
+}
+
+/// Limits of request body, `None` is unlimited
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BodyLimits {
+    pub max_size: Option<u64>,
+    pub timeout: Option<Duration>,
+}
+
+/// Body of at most `max_size` bytes received within `timeout`, either of HTTP/1 or of HTTP/2 stream
+pub fn read_body<B>(body: B, limits: BodyLimits, handle: &Handle) -> Box<Future<Item = Vec<u8>, Error = BodyError>>
+where B: Stream + 'static,
+      B::Item: AsRef<[u8]>,
+      B::Error: Into<BodyError>,
+{
+    let max_size = limits.max_size;
+    let read = body
+        .map_err(Into::into)
+        .fold(Vec::new(), move |mut buffer, chunk| {
+            let chunk = chunk.as_ref();
+            if max_size.map_or(false, |max_size| (buffer.len() + chunk.len()) as u64 > max_size) {
+                return Err(BodyError::TooLarge)
+            }
+            buffer.extend_from_slice(chunk);
+            Ok(buffer)
+        });
+
+    let timeout = match limits.timeout.map(|timeout| Timeout::new(timeout, handle)) {
+        Some(Ok(timeout)) => timeout,
+        Some(Err(err)) => return Box::new(future::err(BodyError::IoError(err))),
+        None => return Box::new(read),
+    };
+
+    Box::new(read
+        .select(timeout.then(|_| Err(BodyError::TimedOut)))
+        .map(|(body, _)| body)
+        .map_err(|(err, _)| err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{
+        SocketAddr,
+        TcpStream,
+    };
+    use std::sync::mpsc;
+    use std::thread;
+
+    use futures::Sink;
+    use hyper::server::{
+        Http,
+        Service,
+    };
+    use tokio_core;
+    use tokio_core::reactor::Core;
+
+    const TIMEOUT_MS: u64 = 300;
+    const MAX_BODY_SIZE: u64 = 8;
+
+    /// Responds with request path, `/slow` one after longer delay than timeouts
+    struct SlowService {
+        connection: Rc<Connection>,
+        handle: Handle,
+    }
+
+    impl Service for SlowService {
+        type Request = hyper::Request;
+        type Response = hyper::Response;
+        type Error = hyper::Error;
+        type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+
+        fn call(&self, request: Self::Request) -> Self::Future {
+            let started = self.connection.start_request();
+            let path = request.path().to_string();
+            let delay = if path == "/slow" { TIMEOUT_MS * 2 } else { 0 };
+            Box::new(Timeout::new(Duration::from_millis(delay), &self.handle).unwrap()
+                .map(move |_| {
+                    drop(started);
+                    hyper::Response::new()
+                        .with_header(hyper::header::ContentLength(path.len() as u64))
+                        .with_body(path)
+                })
+                .map_err(hyper::Error::from))
+        }
+    }
+
+    /// Responds with request body. Like router, it rejects body declared or growing larger than limit
+    /// with 413 and closes connection instead of reading rest of request.
+    struct BodyService {
+        connection: Rc<Connection>,
+        handle: Handle,
+    }
+
+    impl Service for BodyService {
+        type Request = hyper::Request;
+        type Response = hyper::Response;
+        type Error = hyper::Error;
+        type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+
+        fn call(&self, request: Self::Request) -> Self::Future {
+            let started = self.connection.start_request();
+            let connection = self.connection.clone();
+            let declared_size = request.headers().get::<hyper::header::ContentLength>().map(|length| length.0);
+            let body = if declared_size.map_or(false, |size| size > MAX_BODY_SIZE) {
+                Box::new(future::err(BodyError::TooLarge))
+            } else {
+                let limits = BodyLimits {
+                    max_size: Some(MAX_BODY_SIZE),
+                    timeout: Some(Duration::from_millis(TIMEOUT_MS)),
+                };
+                read_body(request.body(), limits, &self.handle)
+            };
+
+            Box::new(body.then(move |body| {
+                drop(started);
+                Ok(match body {
+                    Ok(body) => hyper::Response::new()
+                        .with_header(hyper::header::ContentLength(body.len() as u64))
+                        .with_body(body),
+                    Err(_) => {
+                        connection.close();
+                        hyper::Response::new()
+                            .with_status(hyper::StatusCode::PayloadTooLarge)
+                            .with_header(hyper::header::Connection::close())
+                    },
+                })
+            }))
+        }
+    }
+
+    fn start_server() -> SocketAddr {
+        start_server_with(|connection, handle| SlowService { connection: Rc::new(connection), handle: handle.clone() })
+    }
+
+    fn start_server_with<S, F>(new_service: F) -> SocketAddr
+    where F: Fn(Connection, &Handle) -> S + Send + 'static,
+          S: Service<Request = hyper::Request, Response = hyper::Response, Error = hyper::Error> + 'static
+    {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut core = Core::new().unwrap();
+            let handle = core.handle();
+            let listener = tokio_core::net::TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+            sender.send(listener.local_addr().unwrap()).unwrap();
+
+            let connections = ConnectionLimit::new(None);
+            core.run(listener.incoming().for_each(|(stream, remote_addr)| {
+                let connection = connections.acquire().unwrap();
+                let timeout = Some(Duration::from_millis(TIMEOUT_MS));
+                let stream = TimeoutIo::new(stream, &connection, timeout, timeout, &handle);
+                let service = new_service(connection, &handle);
+                Http::new().bind_connection(&handle, stream, remote_addr, service);
+                Ok(())
+            })).unwrap();
+        });
+        receiver.recv().unwrap()
+    }
+
+    /// Response received before connection is closed by server
+    fn read_until_closed(stream: &mut TcpStream) -> String {
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    /// Responses to `requests` sent at once, until connection is closed or reset because of unread requests
+    fn pipelined_responses(requests: &[u8]) -> (String, Duration) {
+        let mut stream = TcpStream::connect(start_server_with(|connection, handle|
+            BodyService { connection: Rc::new(connection), handle: handle.clone() }
+        )).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(requests).unwrap();
+        let started = Instant::now();
+
+        let mut response = Vec::new();
+        loop {
+            let mut chunk = [0; 4096];
+            match stream.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(size) => response.extend_from_slice(&chunk[..size]),
+                Err(ref err) if err.kind() == io::ErrorKind::ConnectionReset => break,
+                Err(err) => panic!("Connection is not closed: {:?}", err),
+            }
+        }
+        (String::from_utf8(response).unwrap(), started.elapsed())
+    }
+
+    /// Single 413 response is written before connection is closed
+    fn assert_rejected(response: &str) {
+        assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"), "{}", response);
+        assert!(response.contains("\r\nConnection: close\r\n"));
+        assert_eq!(response.matches("HTTP/1.1 ").count(), 1);
+    }
+
+    fn read_body_with(chunks: Vec<&'static str>, keep_open: bool) -> Result<Vec<u8>, BodyError> {
+        let mut core = Core::new().unwrap();
+        let (sender, body) = hyper::Body::pair();
+        let chunks = chunks.into_iter().map(|chunk| Ok(hyper::Chunk::from(chunk))).collect::<Vec<_>>();
+        let sent = sender.send_all(futures::stream::iter_ok(chunks));
+        let limits = BodyLimits {
+            max_size: Some(10),
+            timeout: Some(Duration::from_millis(TIMEOUT_MS)),
+        };
+        let read = read_body(body, limits, &core.handle());
+
+        if keep_open {
+            let (_sender, _) = core.run(sent).ok().unwrap();
+            core.run(read)
+        } else {
+            core.handle().spawn(sent.map(|_| ()).map_err(|_| ()));
+            core.run(read)
+        }
+    }
+
+    #[test]
+    fn read_limited_body() {
+        assert_eq!(read_body_with(vec!["{\"id\":", "1}"], false).unwrap(), b"{\"id\":1}");
+        assert_matches!(read_body_with(vec!["{\"id\":", "1, \"a\": 2}"], false), Err(BodyError::TooLarge));
+        assert_matches!(read_body_with(vec!["{\"id\":"], true), Err(BodyError::TimedOut));
+    }
+
+    #[test]
+    fn close_after_declared_large_body() {
+        let (response, _) = pipelined_responses(
+            b"POST /large HTTP/1.1\r\nHost: a\r\nContent-Length: 20\r\n\r\n{\"id\": 1, \"mark\": 5}\
+              POST /next HTTP/1.1\r\nHost: a\r\nContent-Length: 2\r\n\r\n{}"
+        );
+        assert_rejected(&response);
+
+        // Connection is aborted after response instead of waiting for rest of body until idle timeout
+        let (response, elapsed) = pipelined_responses(b"POST /large HTTP/1.1\r\nHost: a\r\nContent-Length: 1000\r\n\r\n{");
+        assert_rejected(&response);
+        assert!(elapsed < Duration::from_millis(TIMEOUT_MS / 2), "closed in {:?}", elapsed);
+    }
+
+    #[test]
+    fn close_after_chunked_large_body() {
+        let (response, _) = pipelined_responses(
+            b"POST /chunked HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+              6\r\n{\"id\":\r\n8\r\n 1, \"a\":\r\n2\r\n2}\r\n0\r\n\r\n\
+              POST /next HTTP/1.1\r\nHost: a\r\nContent-Length: 2\r\n\r\n{}"
+        );
+        assert_rejected(&response);
+
+        let (response, elapsed) = pipelined_responses(
+            b"POST /chunked HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n9\r\n{\"id\": 1,\r\n"
+        );
+        assert_rejected(&response);
+        assert!(elapsed < Duration::from_millis(TIMEOUT_MS / 2), "closed in {:?}", elapsed);
+    }
+
+    #[test]
+    fn keep_connection_after_small_body() {
+        let (response, _) = pipelined_responses(
+            b"POST /small HTTP/1.1\r\nHost: a\r\nContent-Length: 2\r\n\r\n{}\
+              POST /next HTTP/1.1\r\nHost: a\r\nContent-Length: 1\r\nConnection: close\r\n\r\n1"
+        );
+        assert_eq!(response.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+        assert!(response.ends_with("\r\n\r\n1"));
+    }
+
+    #[test]
+    fn close_idle_connection() {
+        let address = start_server();
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(b"GET /first HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        assert!(read_until_closed(&mut stream).ends_with("\r\n\r\n/first"));
+
+        let mut idle = TcpStream::connect(address).unwrap();
+        assert_eq!(read_until_closed(&mut idle), "");
+    }
+
+    #[test]
+    fn close_slow_head() {
+        let mut stream = TcpStream::connect(start_server()).unwrap();
+        let started = Instant::now();
+        for byte in b"GET /dripped HTTP/1.1\r\nHost: a\r\n\r\n".chunks(1) {
+            if stream.write_all(byte).is_err() {
+                break
+            }
+            thread::sleep(Duration::from_millis(TIMEOUT_MS / 5));
+        }
+
+        assert_eq!(read_until_closed(&mut stream), "");
+        assert!(started.elapsed() < Duration::from_millis(TIMEOUT_MS * 5));
+    }
+
+    #[test]
+    fn keep_slow_request() {
+        let mut stream = TcpStream::connect(start_server()).unwrap();
+        stream.write_all(b"GET /slow HTTP/1.1\r\nHost: a\r\n\r\nGET /next HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        let response = read_until_closed(&mut stream);
+        assert!(response.contains("\r\n\r\n/slow"));
+        assert!(response.ends_with("\r\n\r\n/next"));
+    }
+
+    fn client(ip: &str) -> Client {
+        Client::Ip(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn limit_bursts() {
+        let limiter = RateLimiter::new(Some(2), None, 2);
+        let now = Instant::now();
+
+        for _ in 0..4 {
+            assert_eq!(limiter.check_at(client("10.0.0.1"), Permission::Read, now), Ok(()));
+        }
+        assert_eq!(limiter.check_at(client("10.0.0.1"), Permission::Read, now), Err(Duration::from_secs(1)));
+        assert_eq!(limiter.check_at(client("10.0.0.2"), Permission::Read, now), Ok(()));
+        assert_eq!(limiter.check_at(Client::ApiKey("key".into()), Permission::Admin, now), Ok(()));
+
+        let later = now + Duration::from_millis(500);
+        assert_eq!(limiter.check_at(client("10.0.0.1"), Permission::Read, later), Ok(()));
+        assert_eq!(limiter.check_at(client("10.0.0.1"), Permission::Read, later), Err(Duration::from_secs(1)));
+
+        for _ in 0..100 {
+            assert_eq!(limiter.check_at(client("10.0.0.1"), Permission::Write, now), Ok(()));
+        }
+    }
+
+    #[test]
+    fn separate_budgets() {
+        let limiter = RateLimiter::new(Some(10), Some(1), 1);
+        let now = Instant::now();
+
+        assert_eq!(limiter.check_at(client("::1"), Permission::Write, now), Ok(()));
+        assert_eq!(limiter.check_at(client("::1"), Permission::Write, now), Err(Duration::from_secs(1)));
+        assert_eq!(limiter.check_at(client("::1"), Permission::Read, now), Ok(()));
+        assert_eq!(limiter.check_at(client("::1"), Permission::Write, now + Duration::from_secs(1)), Ok(()));
+    }
+
+    #[test]
+    fn prune_idle_clients() {
+        let limiter = RateLimiter::new(Some(1), None, 1);
+        let now = Instant::now();
+        for i in 0..MIN_PRUNE_AT {
+            limiter.check_at(Client::ApiKey(i.to_string()), Permission::Read, now).unwrap();
+        }
+
+        limiter.check_at(client("10.0.0.1"), Permission::Read, now + Duration::from_secs(2)).unwrap();
+        assert_eq!(limiter.buckets.lock().unwrap().buckets.len(), 1);
+    }
+
+    #[test]
+    fn limit_connections() {
+        let limit = ConnectionLimit::new(Some(2));
+        let first = limit.acquire().unwrap();
+        let second = limit.acquire().unwrap();
+        assert!(limit.acquire().is_none());
+        assert_eq!(limit.open(), 2);
+
+        drop(first);
+        assert!(limit.acquire().is_some());
+        drop(second);
+        assert_eq!(limit.open(), 0);
+        assert_eq!(ConnectionLimit::new(None).acquire().map(|_| ()), Some(()));
+    }
+}
@@ -1,11 +1,21 @@
 extern crate futures;
 extern crate hyper;
+extern crate h2;
+extern crate http;
+extern crate httparse;
+extern crate bytes;
 
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+#[cfg_attr(test, macro_use)]
 extern crate serde_json;
 extern crate serde_urlencoded;
+extern crate rmp_serde;
+extern crate serde_cbor;
+extern crate csv;
+extern crate percent_encoding;
+extern crate toml;
 
 #[macro_use]
 extern crate log;
@@ -13,8 +23,14 @@ extern crate env_logger;
 
 extern crate net2;
 extern crate tokio_core;
+extern crate tokio_io;
+extern crate tokio_uds;
+extern crate tokio_openssl;
+extern crate tokio_signal;
+extern crate openssl;
 
 extern crate zip;
+extern crate flate2;
 
 extern crate chrono;
 extern crate fnv;
@@ -23,7 +39,11 @@ extern crate fnv;
 #[macro_use]
 extern crate matches;
 
+use std::cell::Cell;
 use std::env;
+use std::fmt;
+use std::process;
+use std::rc::Rc;
 use std::str;
 use std::sync::Arc;
 use std::time;
@@ -35,19 +55,29 @@ use hyper::mime;
 use futures::{
     Future,
     future,
+    Sink,
     Stream,
 };
 
 use net2::unix::UnixTcpBuilderExt;
+use tokio_openssl::SslAcceptorExt;
 
 mod models;
 mod store;
+mod history;
+mod changes;
+mod replication;
+mod patch;
+mod strict;
+mod config;
 mod loader;
-
-const STREAM_KEEPALIVE_SECS: Option<u64> = Some(30);
-const STREAM_LINGER_SECS: Option<u64> = Some(5);
-const STREAM_SEND_BUFFER_SIZE: usize = 256 * 1024;
-const STREAM_RECV_BUFFER_SIZE: usize = 256 * 1024;
+mod tls;
+mod protocol;
+mod compression;
+mod format;
+mod auth;
+mod limits;
+mod access_log;
 
 #[derive(Debug)]
 enum AppError {
@@ -57,6 +87,14 @@ enum AppError {
     ParamsError(serde_urlencoded::de::Error),
     LockError,
     NullValue,
+    UnknownEntity(String),
+    UnsupportedMediaType,
+    NotAcceptable,
+    InvalidField(models::ValidationError),
+    FormatError(format::Error),
+    AuthError(auth::Error),
+    RateLimited(time::Duration),
+    BodyError(limits::BodyError),
 }
 
 impl From<store::StoreError> for AppError {
@@ -71,6 +109,24 @@ impl From<serde_json::Error> for AppError {
     }
 }
 
+impl From<format::Error> for AppError {
+    fn from(err: format::Error) -> AppError {
+        match err {
+            format::Error::JsonError(err) => AppError::JsonError(err),
+            err => AppError::FormatError(err),
+        }
+    }
+}
+
+impl From<limits::BodyError> for AppError {
+    fn from(err: limits::BodyError) -> AppError {
+        match err {
+            limits::BodyError::HyperError(err) => AppError::HyperError(err),
+            err => AppError::BodyError(err),
+        }
+    }
+}
+
 impl From<serde_urlencoded::de::Error> for AppError {
     fn from(err: serde_urlencoded::de::Error) -> AppError {
         AppError::ParamsError(err)
@@ -83,20 +139,44 @@ impl<'a, T> From<std::sync::PoisonError<std::sync::RwLockWriteGuard<'a, T>>> for
     }
 }
 
+/// State shared by server threads
+#[derive(Clone)]
+struct ServerState {
+    store: Arc<store::StoreWrapper>,
+    config: Arc<config::Config>,
+    rate_limiter: Arc<limits::RateLimiter>,
+    request_ids: Arc<access_log::RequestIds>,
+    access_log: Option<Arc<access_log::AccessLog>>,
+}
+
 #[derive(Clone)]
 struct Router {
     store: Arc<store::StoreWrapper>,
     handler: tokio_core::reactor::Handle,
+    remote_addr: Option<std::net::SocketAddr>, // None for Unix socket clients
+    config: Arc<config::Config>,
+    rate_limiter: Arc<limits::RateLimiter>,
+    request_ids: Arc<access_log::RequestIds>,
+    access_log: Option<Arc<access_log::AccessLog>>,
+    connection: Rc<limits::Connection>, // Released when connection and its streams are closed
 }
 
 impl Router {
     fn new(
-        store: Arc<store::StoreWrapper>,
+        state: &ServerState,
         handler: tokio_core::reactor::Handle,
+        remote_addr: Option<std::net::SocketAddr>,
+        connection: limits::Connection,
     ) -> Self {
         Self {
-            store: store,
+            store: state.store.clone(),
             handler: handler,
+            remote_addr: remote_addr,
+            config: state.config.clone(),
+            rate_limiter: state.rate_limiter.clone(),
+            request_ids: state.request_ids.clone(),
+            access_log: state.access_log.clone(),
+            connection: Rc::new(connection),
         }
     }
 
@@ -104,50 +184,255 @@ impl Router {
         Box::new(future::ok(server::Response::new().with_status(hyper::StatusCode::NotFound)))
     }
 
-    fn app_error(err: AppError) -> server::Response {
-        warn!("{:?}", err);
-        let status_code = match err {
+    fn error_status(err: &AppError) -> hyper::StatusCode {
+        match *err {
             AppError::JsonError(_) =>
                 hyper::StatusCode::BadRequest,
             AppError::StoreError(store::StoreError::EntryExists) |
             AppError::StoreError(store::StoreError::InvalidEntity(_)) |
             AppError::StoreError(store::StoreError::LockError) |
-            AppError::NullValue =>
+            AppError::StoreError(store::StoreError::ScanLimitExceeded(_)) |
+            AppError::NullValue |
+            AppError::UnknownEntity(_) |
+            AppError::InvalidField(_) =>
                 hyper::StatusCode::BadRequest,
-            AppError::ParamsError(_) =>
+            AppError::ParamsError(_) | AppError::FormatError(_) =>
                 hyper::StatusCode::BadRequest,
             AppError::StoreError(store::StoreError::EntityNotExists) =>
                 hyper::StatusCode::NotFound,
+            AppError::StoreError(store::StoreError::VersionMismatch) =>
+                hyper::StatusCode::PreconditionFailed,
+            AppError::StoreError(store::StoreError::ReadOnly) =>
+                hyper::StatusCode::Forbidden,
+            AppError::StoreError(store::StoreError::PatchFailed(patch::PatchError::TestFailed(_))) |
+            AppError::StoreError(store::StoreError::PatchFailed(patch::PatchError::PathNotFound(_))) =>
+                hyper::StatusCode::Conflict,
+            AppError::StoreError(store::StoreError::PatchFailed(patch::PatchError::InvalidPointer(_))) |
+            AppError::StoreError(store::StoreError::PatchFailed(patch::PatchError::InvalidResult(_))) =>
+                hyper::StatusCode::UnprocessableEntity,
+            AppError::UnsupportedMediaType =>
+                hyper::StatusCode::UnsupportedMediaType,
+            AppError::NotAcceptable =>
+                hyper::StatusCode::NotAcceptable,
+            AppError::AuthError(auth::Error::MissingKey) |
+            AppError::AuthError(auth::Error::UnknownKey) =>
+                hyper::StatusCode::Unauthorized,
+            AppError::AuthError(auth::Error::Forbidden(_, _)) =>
+                hyper::StatusCode::Forbidden,
+            AppError::RateLimited(_) =>
+                hyper::StatusCode::TooManyRequests,
+            AppError::BodyError(limits::BodyError::TooLarge) =>
+                hyper::StatusCode::PayloadTooLarge,
+            AppError::BodyError(limits::BodyError::TimedOut) =>
+                hyper::StatusCode::RequestTimeout,
+            AppError::BodyError(limits::BodyError::IoError(_)) |
+            AppError::BodyError(limits::BodyError::HyperError(_)) =>
+                hyper::StatusCode::InternalServerError,
             AppError::HyperError(_) | AppError::LockError =>
                 hyper::StatusCode::InternalServerError,
-        };
-        server::Response::new().with_status(status_code)
+        }
+    }
+
+    /// Offending field of invalid request
+    fn validation_error(err: AppError) -> Option<models::ValidationError> {
+        match err {
+            AppError::InvalidField(validation_error) |
+            AppError::StoreError(store::StoreError::InvalidEntity(validation_error)) =>
+                Some(validation_error),
+            AppError::AuthError(err) =>
+                Some(models::ValidationError {
+                    field: "Authorization".to_string(),
+                    message: err.to_string(),
+                }),
+            AppError::StoreError(store::StoreError::ScanLimitExceeded(max_scanned_visits)) =>
+                Some(models::ValidationError {
+                    field: "country".to_string(),
+                    message: format!("query scans more than {} visits, filter by country or city", max_scanned_visits),
+                }),
+            _ => None,
+        }
+    }
+
+    fn app_error(err: AppError, format: format::Format) -> server::Response {
+        warn!("{:?}", err);
+        let mut response = server::Response::new().with_status(Self::error_status(&err));
+        if response.status() == hyper::StatusCode::Unauthorized {
+            response.headers_mut().set_raw("WWW-Authenticate", "Bearer");
+        }
+        if let AppError::RateLimited(retry_after) = err {
+            response.headers_mut().set(hyper::header::RetryAfter::Delay(retry_after));
+        }
+        if let AppError::BodyError(_) = err {
+            // Rest of body is not read, connection can't be reused
+            response.headers_mut().set(hyper::header::Connection::close());
+        }
+        match Self::validation_error(err) {
+            Some(validation_error) => {
+                let body = format.encode(&validation_error).unwrap_or_default();
+                response
+                    .with_header(hyper::header::ContentType(format.mime()))
+                    .with_header(hyper::header::ContentLength(body.len() as u64))
+                    .with_body(body)
+            },
+            None => response,
+        }
     }
 
-    fn format_response<E>(result: Result<E, AppError>) ->
+    /// Entity encoded in format negotiated by `Accept`, `None` if no supported format is acceptable
+    fn format_response<E>(result: Result<E, AppError>, format: Option<format::Format>) ->
         Box<Future<Item = server::Response, Error = hyper::Error>>
     where
         E: serde::ser::Serialize,
     {
-        Box::new(result
-            .and_then(|user| Ok(serde_json::to_string(&user)?))
-            .map(|json| {
-                let length = json.len() as u64;
-                future::ok(server::Response::new().with_body(json)
-                    .with_header(hyper::header::ContentType(mime::APPLICATION_JSON))
-                    .with_header(hyper::header::ContentLength(length))
-                )
-            })
-            .unwrap_or_else(|err| future::ok(Self::app_error(err)))
+        let encoded = result.and_then(|entity| {
+            let format = format.ok_or(AppError::NotAcceptable)?;
+            Ok((format, format.encode(&entity)?))
+        });
+        let mut response = match encoded {
+            Ok((format, body)) => server::Response::new()
+                .with_header(hyper::header::ContentType(format.mime()))
+                .with_header(hyper::header::ContentLength(body.len() as u64))
+                .with_body(body),
+            Err(err) => Self::app_error(err, format.unwrap_or(format::Format::Json)),
+        };
+        Self::add_vary(&mut response, "Accept");
+        Box::new(future::ok(response))
+    }
+
+    /// Tag of entity version in JSON, representations in other formats have format name appended
+    fn entity_tag(version: models::Version, format: format::Format) -> hyper::header::EntityTag {
+        match format {
+            format::Format::Json => hyper::header::EntityTag::strong(version.to_string()),
+            format => hyper::header::EntityTag::strong(format!("{}-{}", version, format.name())),
+        }
+    }
+
+    /// Versions of strong tags of any format
+    fn if_match_versions(if_match: Option<hyper::header::IfMatch>) -> Option<Vec<models::Version>> {
+        match if_match {
+            Some(hyper::header::IfMatch::Items(tags)) =>
+                Some(tags.iter()
+                    .filter(|tag| !tag.weak)
+                    .filter_map(|tag| tag.tag().split('-').next().and_then(|version| version.parse().ok()))
+                    .collect()),
+            _ => None,
+        }
+    }
+
+    fn format_versioned_response<E>(
+        result: Result<models::Versioned<E>, AppError>,
+        if_none_match: Option<hyper::header::IfNoneMatch>,
+        format: Option<format::Format>,
+    ) -> Box<Future<Item = server::Response, Error = hyper::Error>>
+    where
+        E: serde::ser::Serialize + 'static,
+    {
+        use hyper::header::{ETag, IfNoneMatch};
+
+        let (versioned, format) = match (result, format) {
+            (Ok(versioned), Some(format)) => (versioned, format),
+            (result, format) => return Self::format_response(result.map(|versioned| versioned.entity), format),
+        };
+
+        let entity_tag = Self::entity_tag(versioned.version, format);
+        let not_modified = match if_none_match {
+            Some(IfNoneMatch::Any) => true,
+            Some(IfNoneMatch::Items(ref tags)) => tags.iter().any(|tag| tag.weak_eq(&entity_tag)),
+            None => false,
+        };
+
+        if not_modified {
+            let mut response = server::Response::new()
+                .with_status(hyper::StatusCode::NotModified)
+                .with_header(ETag(entity_tag));
+            Self::add_vary(&mut response, "Accept");
+            Box::new(future::ok(response))
+        } else {
+            Box::new(Self::format_response(Ok(versioned.entity), Some(format))
+                .map(move |response| response.with_header(ETag(entity_tag)))
+            )
+        }
+    }
+
+    fn format_updated_response(result: Result<models::Version, AppError>, format: Option<format::Format>) ->
+        Box<Future<Item = server::Response, Error = hyper::Error>>
+    {
+        Self::format_versioned_response(
+            result.map(|version| models::Versioned { entity: models::Empty{}, version: version }),
+            None,
+            format,
         )
     }
 
+    /// Created entity id and its location when id is allocated by store, empty object otherwise
+    fn format_created_response(
+        entity: models::EntityKind,
+        result: Result<Option<models::Id>, AppError>,
+        format: Option<format::Format>,
+    ) -> Box<Future<Item = server::Response, Error = hyper::Error>> {
+        match result {
+            Ok(Some(id)) => Box::new(
+                Self::format_response(Ok(models::CreatedEntity { id: id }), format)
+                    .map(move |response| response
+                        .with_status(hyper::StatusCode::Created)
+                        .with_header(hyper::header::Location::new(format!("/{}/{}", entity.path(), id)))
+                    )
+            ),
+            Ok(None) => Self::format_response(Ok(models::Empty{}), format),
+            Err(err) => Self::format_response::<models::Empty>(Err(err), format),
+        }
+    }
+
     fn parse_params<P>(query: Option<&str>) -> Result<P, AppError>
     where P: serde::de::DeserializeOwned
     {
         Ok(serde_urlencoded::from_str(query.unwrap_or(""))?)
     }
 
+    /// In strict mode body fields are checked against entity fields before deserialization
+    fn check_fields(strict: bool, entity: models::EntityKind, creating: bool, value: serde_json::Value) ->
+        Result<serde_json::Value, AppError>
+    {
+        if strict {
+            strict::check_fields(entity, creating, &value).map_err(AppError::InvalidField)?;
+        }
+        Ok(value)
+    }
+
+    /// CSV cells are strings, integer fields are parsed by entity fields before checks
+    fn parse_entity_body(&self, body_format: Option<format::Format>, body: hyper::Body, entity: models::EntityKind, creating: bool) ->
+        Box<Future<Item = serde_json::Value, Error = AppError>>
+    {
+        let strict = self.config.strict;
+        Box::new(
+            self.parse_body(body_format, body)
+                .map(move |value| match body_format {
+                    Some(format::Format::Csv) => strict::parse_numbers(entity, value),
+                    _ => value,
+                })
+                .and_then(move |value| Self::check_fields(strict, entity, creating, value))
+        )
+    }
+
+    /// New entity body without id gets `NEW_ID`, store allocates id for it when entity is added
+    fn with_new_id(mut value: serde_json::Value) -> Result<serde_json::Value, AppError> {
+        if let serde_json::Value::Object(ref mut map) = value {
+            if map.get("id").and_then(serde_json::Value::as_u64) == Some(u64::from(models::NEW_ID)) {
+                return Err(AppError::InvalidField(models::ValidationError {
+                    field: "id".to_string(),
+                    message: format!("Id is out of range 1..{}", models::Id::max_value()),
+                }))
+            }
+            map.entry("id").or_insert_with(|| models::NEW_ID.into());
+        }
+        Ok(value)
+    }
+
+    /// Allocated id is returned for entity created without one
+    fn allocated_id(new_id: models::Id, id: models::Id) -> Option<models::Id> {
+        if new_id == models::NEW_ID { Some(id) } else { None }
+    }
+
     fn check_json_value(map: serde_json::map::Map<String, serde_json::value::Value>) ->
         Result<serde_json::Value, AppError>
     {
@@ -158,49 +443,101 @@ impl Router {
         }
     }
 
-    fn parse_body(body: hyper::Body) -> Box<Future<Item = serde_json::Value, Error = AppError>> {
+    fn read_body(&self, body: hyper::Body) -> Box<Future<Item = Vec<u8>, Error = AppError>> {
+        Box::new(
+            limits::read_body(body, self.config.body_limits(), &self.handler)
+                .map_err(AppError::from)
+        )
+    }
+
+    /// Declared body size is checked before reading it
+    fn check_content_length(&self, headers: &hyper::Headers) -> Result<(), AppError> {
+        match (headers.get::<hyper::header::ContentLength>(), self.config.max_body_size) {
+            (Some(&hyper::header::ContentLength(length)), Some(max_body_size)) if length > max_body_size =>
+                Err(AppError::BodyError(limits::BodyError::TooLarge)),
+            _ => Ok(()),
+        }
+    }
+
+    fn parse_body(&self, body_format: Option<format::Format>, body: hyper::Body) -> Box<Future<Item = serde_json::Value, Error = AppError>> {
+        let body_format = match body_format {
+            Some(body_format) => body_format,
+            None => return Box::new(future::err(AppError::UnsupportedMediaType)),
+        };
         Box::new(
-            body.concat2()
-                .map_err(AppError::HyperError)
-                .and_then(move |chunk| Ok(serde_json::from_slice(&chunk)?))
+            self.read_body(body)
+                .and_then(move |body| Ok(body_format.decode(&body)?))
                 .and_then(Self::check_json_value)
         )
     }
 
-    fn get_location(&self, id: models::Id) -> Box<Future<Item = server::Response, Error = hyper::Error>> {
+    /// Merge patch by default, JSON Patch for `application/json-patch+json`
+    fn parse_patch(&self, content_type: Option<hyper::header::ContentType>, body: hyper::Body) ->
+        Box<Future<Item = patch::Patch, Error = AppError>>
+    {
+        let json_patch = match content_type {
+            None => false,
+            Some(hyper::header::ContentType(ref content_type)) if content_type.type_() == mime::APPLICATION =>
+                match (content_type.subtype().as_str(), content_type.suffix().map(|suffix| suffix.as_str())) {
+                    ("json", None) | ("merge-patch", Some("json")) => false,
+                    ("json-patch", Some("json")) => true,
+                    _ => return Box::new(future::err(AppError::UnsupportedMediaType)),
+                },
+            Some(_) => return Box::new(future::err(AppError::UnsupportedMediaType)),
+        };
+
+        Box::new(
+            self.read_body(body)
+                .and_then(move |chunk|
+                    if json_patch {
+                        Ok(patch::Patch::Json(serde_json::from_slice(&chunk)?))
+                    } else {
+                        Ok(patch::Patch::Merge(serde_json::from_slice(&chunk)?))
+                    }
+                )
+        )
+    }
+
+    fn get_location(&self, id: models::Id, if_none_match: Option<hyper::header::IfNoneMatch>, response_format: Option<format::Format>) ->
+        Box<Future<Item = server::Response, Error = hyper::Error>>
+    {
         Box::new(
             future::result(
                 self.store
                     .get_location(id)
                     .map_err(AppError::StoreError)
             )
-            .then(Self::format_response)
+            .then(move |result| Self::format_versioned_response(result, if_none_match, response_format))
         )
     }
 
-    fn get_user(&self, id: models::Id) -> Box<Future<Item = server::Response, Error = hyper::Error>> {
+    fn get_user(&self, id: models::Id, if_none_match: Option<hyper::header::IfNoneMatch>, response_format: Option<format::Format>) ->
+        Box<Future<Item = server::Response, Error = hyper::Error>>
+    {
         Box::new(
             future::result(
                 self.store
                     .get_user(id)
                     .map_err(AppError::StoreError)
             )
-            .then(Self::format_response)
+            .then(move |result| Self::format_versioned_response(result, if_none_match, response_format))
         )
     }
 
-    fn get_visit(&self, id: models::Id) -> Box<Future<Item = server::Response, Error = hyper::Error>> {
+    fn get_visit(&self, id: models::Id, if_none_match: Option<hyper::header::IfNoneMatch>, response_format: Option<format::Format>) ->
+        Box<Future<Item = server::Response, Error = hyper::Error>>
+    {
         Box::new(
             future::result(
                 self.store
                     .get_visit(id)
                     .map_err(AppError::StoreError)
             )
-            .then(Self::format_response)
+            .then(move |result| Self::format_versioned_response(result, if_none_match, response_format))
         )
     }
 
-    fn get_location_rating(&self, id: models::Id, query: Option<&str>) ->
+    fn get_location_rating(&self, id: models::Id, query: Option<&str>, response_format: Option<format::Format>) ->
         Box<Future<Item = server::Response, Error = hyper::Error>>
     {
         Box::new(
@@ -212,11 +549,82 @@ impl Router {
                             .map_err(AppError::StoreError)
                     )
             )
-            .then(Self::format_response)
+            .then(move |result| Self::format_response(result, response_format))
+        )
+    }
+
+    fn get_countries(&self, response_format: Option<format::Format>) -> Box<Future<Item = server::Response, Error = hyper::Error>> {
+        Box::new(
+            future::result(
+                self.store
+                    .get_countries()
+                    .map_err(AppError::StoreError)
+            )
+            .then(move |result| Self::format_response(result, response_format))
+        )
+    }
+
+    fn get_country_cities(&self, country_src: &str, response_format: Option<format::Format>) -> Box<Future<Item = server::Response, Error = hyper::Error>> {
+        let country = percent_encoding::percent_decode(country_src.as_bytes()).decode_utf8_lossy();
+        Box::new(
+            future::result(
+                self.store
+                    .get_country_cities(&country)
+                    .map_err(AppError::StoreError)
+            )
+            .then(move |result| Self::format_response(result, response_format))
+        )
+    }
+
+    fn get_top_locations(&self, query: Option<&str>, response_format: Option<format::Format>) ->
+        Box<Future<Item = server::Response, Error = hyper::Error>>
+    {
+        Box::new(
+            future::result(
+                Self::parse_params(query)
+                    .and_then(|options|
+                        self.store
+                            .get_top_locations(options)
+                            .map_err(AppError::StoreError)
+                    )
+            )
+            .then(move |result| Self::format_response(result, response_format))
+        )
+    }
+
+    fn get_location_visits(&self, id: models::Id, query: Option<&str>, response_format: Option<format::Format>) ->
+        Box<Future<Item = server::Response, Error = hyper::Error>>
+    {
+        Box::new(
+            future::result(
+                Self::parse_params(query)
+                    .and_then(|options|
+                        self.store
+                            .get_location_visits(id, options)
+                            .map_err(AppError::StoreError)
+                    )
+            )
+            .then(move |result| Self::format_response(result, response_format))
         )
     }
 
-    fn get_user_visits(&self, id: models::Id, query: Option<&str>) ->
+    fn get_location_demographics(&self, id: models::Id, query: Option<&str>, response_format: Option<format::Format>) ->
+        Box<Future<Item = server::Response, Error = hyper::Error>>
+    {
+        Box::new(
+            future::result(
+                Self::parse_params(query)
+                    .and_then(|options|
+                        self.store
+                            .get_location_demographics(id, options)
+                            .map_err(AppError::StoreError)
+                    )
+            )
+            .then(move |result| Self::format_response(result, response_format))
+        )
+    }
+
+    fn get_user_visits(&self, id: models::Id, query: Option<&str>, response_format: Option<format::Format>) ->
         Box<Future<Item = server::Response, Error = hyper::Error>>
     {
         Box::new(
@@ -229,66 +637,468 @@ impl Router {
                     )
 
             )
-            .then(Self::format_response)
+            .then(move |result| Self::format_response(result, response_format))
         )
     }
 
-    fn add_user(self, body: hyper::Body) -> Box<Future<Item = server::Response, Error = hyper::Error>> {
+    fn get_user_summary(&self, id: models::Id, response_format: Option<format::Format>) -> Box<Future<Item = server::Response, Error = hyper::Error>> {
         Box::new(
-            Self::parse_body(body)
-                .and_then(|value| Ok(serde_json::from_value(value)?))
-                .and_then(move |user| Ok(self.store.add_user(user)?))
-                .then(Self::format_response)
+            future::result(
+                self.store
+                    .get_user_summary(id)
+                    .map_err(AppError::StoreError)
+            )
+            .then(move |result| Self::format_response(result, response_format))
         )
     }
 
-    fn update_user(self, id: u32, body: hyper::Body) -> Box<Future<Item = server::Response, Error = hyper::Error>> {
+    fn get_history(&self, entity: models::EntityKind, id: models::Id, response_format: Option<format::Format>) ->
+        Box<Future<Item = server::Response, Error = hyper::Error>>
+    {
         Box::new(
-            Self::parse_body(body)
-                .and_then(|value| Ok(serde_json::from_value(value)?))
-                .and_then(move |user| Ok(self.store.update_user(id, user)?))
-                .then(Self::format_response)
+            future::result(
+                self.store
+                    .get_history(entity, id)
+                    .map_err(AppError::StoreError)
+            )
+            .then(move |result| Self::format_response(result, response_format))
+        )
+    }
+
+    fn format_change_event(change: &models::Change) -> hyper::Chunk {
+        let data = serde_json::to_string(&change.data).unwrap_or_default();
+        hyper::Chunk::from(format!("id: {}\nevent: {}\ndata: {}\n\n", change.seq, change.entity.path(), data))
+    }
+
+    /// Tells resuming client that changes were lost and it has to reload entities
+    fn format_reset_event(last_seq: u64) -> hyper::Chunk {
+        hyper::Chunk::from(format!("id: {}\nevent: reset\ndata: {{\"last_seq\":{}}}\n\n", last_seq, last_seq))
+    }
+
+    fn get_changes(&self, query: Option<&str>, last_event_id: Option<u64>) ->
+        Box<Future<Item = server::Response, Error = hyper::Error>>
+    {
+        use hyper::header::{CacheControl, CacheDirective};
+
+        let subscription = Self::parse_params::<models::GetChangesOptions>(query)
+            .and_then(|options| {
+                let entities = options.entities().map_err(AppError::UnknownEntity)?;
+                let since = options.since.or(last_event_id);
+                let (backlog, receiver) = self.store.subscribe_changes(since, self.config.changes_subscriber_buffer)?;
+                Ok((entities, backlog, receiver))
+            });
+
+        let (entities, backlog, receiver) = match subscription {
+            Ok(subscription) => subscription,
+            Err(err) => return Box::new(future::ok(Self::app_error(err, format::Format::Json))),
+        };
+
+        let heartbeat = match tokio_core::reactor::Interval::new(time::Duration::from_secs(self.config.changes_heartbeat_secs), &self.handler) {
+            Ok(heartbeat) => heartbeat,
+            Err(err) => return Box::new(future::err(hyper::Error::Io(err))),
+        };
+
+        let (reset, backlog) = match backlog {
+            changes::Backlog::Changes(changes) => (None, changes),
+            changes::Backlog::Reset(last_seq) => (Some(Self::format_reset_event(last_seq)), Vec::new()),
+        };
+
+        // Receiver ends when client falls behind, so it is reset to the latest change and stream ends
+        let store = self.store.clone();
+        let lagged = future::lazy(move || store.last_change_seq().map_err(|_| ()))
+            .map(|last_seq| Some(Self::format_reset_event(last_seq)))
+            .into_stream()
+            .chain(futures::stream::once(Ok(None)));
+
+        let events = futures::stream::iter_ok(reset)
+            .chain(
+                futures::stream::iter_ok(backlog)
+                    .chain(receiver)
+                    .filter(move |change| entities.as_ref().map_or(true, |entities| entities.contains(&change.entity)))
+                    .map(|change| Self::format_change_event(&change))
+            )
+            .map(Some)
+            .chain(lagged)
+            .select(heartbeat.map(|_| Some(hyper::Chunk::from(":\n\n"))).map_err(|_| ()))
+            .take_while(|event| Ok(event.is_some()))
+            .filter_map(|event| event)
+            .map(Ok);
+
+        let (sender, body) = hyper::Body::pair();
+        let request = self.connection.start_request();
+        self.handler.spawn(
+            events.forward(sender.sink_map_err(|_| ()))
+                .map(move |_| {
+                    drop(request);
+                    debug!("Changes subscriber disconnected")
+                })
+        );
+
+        Box::new(future::ok(server::Response::new()
+            .with_header(hyper::header::ContentType(mime::TEXT_EVENT_STREAM))
+            .with_header(CacheControl(vec![CacheDirective::NoCache]))
+            .with_body(body)
+        ))
+    }
+
+    fn add_user(self, body_format: Option<format::Format>, body: hyper::Body, response_format: Option<format::Format>) ->
+            Box<Future<Item = server::Response, Error = hyper::Error>> {
+        Box::new(
+            self.parse_entity_body(body_format, body, models::EntityKind::Users, true)
+                .and_then(move |value| {
+                    let user: models::User = serde_json::from_value(Self::with_new_id(value)?)?;
+                    let new_id = user.id;
+                    Ok(Self::allocated_id(new_id, self.store.add_user(user, self.remote_addr)?))
+                })
+                .then(move |result| Self::format_created_response(models::EntityKind::Users, result, response_format))
         )
     }
 
-    fn add_location(self, body: hyper::Body) -> Box<Future<Item = server::Response, Error = hyper::Error>> {
+    fn update_user(self, id: u32, if_match: Option<Vec<models::Version>>, body_format: Option<format::Format>, body: hyper::Body, response_format: Option<format::Format>) ->
+            Box<Future<Item = server::Response, Error = hyper::Error>> {
         Box::new(
-            Self::parse_body(body)
+            self.parse_entity_body(body_format, body, models::EntityKind::Users, false)
                 .and_then(|value| Ok(serde_json::from_value(value)?))
-                .and_then(move |location| Ok(self.store.add_location(location)?))
-                .then(Self::format_response)
+                .and_then(move |user| Ok(self.store.update_user(id, user, if_match.as_ref().map(Vec::as_slice), self.remote_addr)?))
+                .then(move |result| Self::format_updated_response(result, response_format))
+        )
+    }
+
+    fn add_location(self, body_format: Option<format::Format>, body: hyper::Body, response_format: Option<format::Format>) ->
+            Box<Future<Item = server::Response, Error = hyper::Error>> {
+        Box::new(
+            self.parse_entity_body(body_format, body, models::EntityKind::Locations, true)
+                .and_then(move |value| {
+                    let location: models::Location = serde_json::from_value(Self::with_new_id(value)?)?;
+                    let new_id = location.id;
+                    Ok(Self::allocated_id(new_id, self.store.add_location(location, self.remote_addr)?))
+                })
+                .then(move |result| Self::format_created_response(models::EntityKind::Locations, result, response_format))
         )
     }
 
-    fn update_location(self, id: models::Id, body: hyper::Body) ->
+    fn update_location(self, id: models::Id, if_match: Option<Vec<models::Version>>, body_format: Option<format::Format>, body: hyper::Body, response_format: Option<format::Format>) ->
             Box<Future<Item = server::Response, Error = hyper::Error>> {
         Box::new(
-            Self::parse_body(body)
+            self.parse_entity_body(body_format, body, models::EntityKind::Locations, false)
                 .and_then(|value| Ok(serde_json::from_value(value)?))
-                .and_then(move |location_data| Ok(self.store .update_location(id, location_data)?))
-                .then(Self::format_response)
+                .and_then(move |location_data|
+                    Ok(self.store.update_location(
+                        id,
+                        location_data,
+                        if_match.as_ref().map(Vec::as_slice),
+                        self.remote_addr,
+                    )?)
+                )
+                .then(move |result| Self::format_updated_response(result, response_format))
+        )
+    }
+
+    fn add_visit(self, body_format: Option<format::Format>, body: hyper::Body, response_format: Option<format::Format>) ->
+            Box<Future<Item = server::Response, Error = hyper::Error>> {
+        Box::new(
+            self.parse_entity_body(body_format, body, models::EntityKind::Visits, true)
+                .and_then(move |value| {
+                    let visit: models::Visit = serde_json::from_value(Self::with_new_id(value)?)?;
+                    let new_id = visit.id;
+                    Ok(Self::allocated_id(new_id, self.store.add_visit(visit, self.remote_addr)?))
+                })
+                .then(move |result| Self::format_created_response(models::EntityKind::Visits, result, response_format))
         )
     }
 
-    fn add_visit(self, body: hyper::Body) -> Box<Future<Item = server::Response, Error = hyper::Error>> {
+    fn update_visit(self, id: models::Id, if_match: Option<Vec<models::Version>>, body_format: Option<format::Format>, body: hyper::Body, response_format: Option<format::Format>) ->
+            Box<Future<Item = server::Response, Error = hyper::Error>> {
         Box::new(
-            Self::parse_body(body)
+            self.parse_entity_body(body_format, body, models::EntityKind::Visits, false)
                 .and_then(|value| Ok(serde_json::from_value(value)?))
-                .and_then(move |visit| Ok(self.store.add_visit(visit)?))
-                .then(Self::format_response)
+                .and_then(move |visit_data|
+                    Ok(self.store.update_visit(
+                        id,
+                        visit_data,
+                        if_match.as_ref().map(Vec::as_slice),
+                        self.remote_addr,
+                    )?)
+                )
+                .then(move |result| Self::format_updated_response(result, response_format))
+        )
+    }
+
+    fn patch(
+        self,
+        entity: models::EntityKind,
+        id: models::Id,
+        if_match: Option<Vec<models::Version>>,
+        content_type: Option<hyper::header::ContentType>,
+        body: hyper::Body,
+        response_format: Option<format::Format>,
+    ) -> Box<Future<Item = server::Response, Error = hyper::Error>> {
+        Box::new(
+            self.parse_patch(content_type, body)
+                .and_then(move |patch|
+                    Ok(self.store.patch(
+                        entity,
+                        id,
+                        patch,
+                        self.config.strict,
+                        if_match.as_ref().map(Vec::as_slice),
+                        self.remote_addr,
+                    )?)
+                )
+                .then(move |result| Self::format_updated_response(result, response_format))
         )
     }
 
-    fn update_visit(self, id: models::Id, body: hyper::Body) ->
+    fn batch_operation(batch_operation: models::BatchOperation, strict: bool) -> Result<models::Operation, AppError> {
+        use models::Operation::*;
+
+        let body = Self::check_json_value(batch_operation.body)?;
+        let body = match models::EntityKind::from_path(&batch_operation.entity) {
+            Some(entity) => Self::check_fields(strict, entity, batch_operation.id.is_none(), body)?,
+            None => body,
+        };
+        let body = if batch_operation.id.is_none() { Self::with_new_id(body)? } else { body };
+        match (batch_operation.entity.as_str(), batch_operation.id) {
+            ("users", None) => Ok(AddUser(serde_json::from_value(body)?)),
+            ("users", Some(id)) => Ok(UpdateUser(id, serde_json::from_value(body)?)),
+            ("locations", None) => Ok(AddLocation(serde_json::from_value(body)?)),
+            ("locations", Some(id)) => Ok(UpdateLocation(id, serde_json::from_value(body)?)),
+            ("visits", None) => Ok(AddVisit(serde_json::from_value(body)?)),
+            ("visits", Some(id)) => Ok(UpdateVisit(id, serde_json::from_value(body)?)),
+            _ => Err(AppError::StoreError(store::StoreError::EntityNotExists)),
+        }
+    }
+
+    fn batch_item_result(result: Result<Option<models::Id>, AppError>) -> models::BatchItemResult {
+        let status = match result {
+            Ok(_) => hyper::StatusCode::Ok,
+            Err(ref err) => Self::error_status(err),
+        };
+        models::BatchItemResult {
+            status: status.as_u16(),
+            id: result.as_ref().ok().and_then(|id| *id),
+            error: result.err().and_then(Self::validation_error),
+        }
+    }
+
+    fn apply_batch(self, batch_request: models::BatchRequest) -> Result<models::BatchResult, AppError> {
+        let operations = batch_request.operations.into_iter()
+            .map(|operation| Self::batch_operation(operation, self.config.strict))
+            .collect::<Vec<Result<models::Operation, AppError>>>();
+        let new_ids = operations.iter()
+            .map(|operation| operation.as_ref().map_or(0, |operation| operation.key().1))
+            .collect::<Vec<models::Id>>();
+
+        if batch_request.atomic.unwrap_or(false) {
+            // Malformed operations never reach the store, so the whole batch is rejected up front
+            if let Some(index) = operations.iter().position(|o| o.is_err()) {
+                return Ok(models::BatchResult {
+                    committed: false,
+                    results: operations.into_iter()
+                        .take(index + 1)
+                        .map(|operation| Self::batch_item_result(operation.map(|_| None)))
+                        .collect(),
+                })
+            }
+
+            let mut transaction = store::Transaction::new();
+            for operation in operations {
+                transaction.push(operation.unwrap());
+            }
+
+            return Ok(match self.store.commit(transaction, self.remote_addr)? {
+                Ok(ids) => models::BatchResult {
+                    committed: true,
+                    results: new_ids.into_iter().zip(ids)
+                        .map(|(new_id, id)| Self::batch_item_result(Ok(Self::allocated_id(new_id, id))))
+                        .collect(),
+                },
+                Err(store::TransactionError { index, error }) => models::BatchResult {
+                    committed: false,
+                    results: (0..index)
+                        .map(|_| Ok(None))
+                        .chain(Some(Err(AppError::StoreError(error))))
+                        .map(Self::batch_item_result)
+                        .collect(),
+                },
+            })
+        }
+
+        let valid_operations = operations.iter()
+            .filter_map(|operation| operation.as_ref().ok().cloned())
+            .collect::<Vec<models::Operation>>();
+
+        // Store returns exactly one result per applied operation
+        let mut store_results = self.store.apply_batch(valid_operations, self.remote_addr)?.into_iter();
+
+        Ok(models::BatchResult {
+            committed: true,
+            results: operations.into_iter().zip(new_ids)
+                .map(|(operation, new_id)| match operation {
+                    Ok(_) => store_results.next().unwrap()
+                        .map(|id| Self::allocated_id(new_id, id))
+                        .map_err(AppError::StoreError),
+                    Err(err) => Err(err),
+                })
+                .map(Self::batch_item_result)
+                .collect(),
+        })
+    }
+
+    fn batch(self, body_format: Option<format::Format>, body: hyper::Body, response_format: Option<format::Format>) ->
             Box<Future<Item = server::Response, Error = hyper::Error>> {
         Box::new(
-            Self::parse_body(body)
+            self.parse_body(body_format, body)
                 .and_then(|value| Ok(serde_json::from_value(value)?))
-                .and_then(move |visit_data| Ok(self.store.update_visit(id, visit_data)?))
-                .then(Self::format_response)
+                .and_then(move |batch_request| self.apply_batch(batch_request))
+                .then(move |result| Self::format_response(result, response_format))
         )
     }
 
+    fn add_vary(response: &mut server::Response, header: &str) {
+        let vary = match response.headers().get_raw("Vary").and_then(|raw| raw.one()).and_then(|vary| str::from_utf8(vary).ok()) {
+            Some(vary) => format!("{}, {}", vary, header),
+            None => header.to_string(),
+        };
+        response.headers_mut().set_raw("Vary", vary);
+    }
+
+    /// Compress complete body of at least `threshold` bytes. Streamed responses are sent as is.
+    fn compress_response(
+        response: server::Response,
+        encoding: Option<hyper::header::Encoding>,
+        threshold: Option<u64>,
+    ) -> Box<Future<Item = server::Response, Error = hyper::Error>> {
+        use hyper::header::{ContentEncoding, ContentLength, ETag, EntityTag};
+
+        let (threshold, length) = match (threshold, response.headers().get::<ContentLength>()) {
+            (Some(threshold), Some(&ContentLength(length))) if !response.headers().has::<ContentEncoding>() =>
+                (threshold, length),
+            _ => return Box::new(future::ok(response)),
+        };
+
+        let mut response = response;
+        Self::add_vary(&mut response, "Accept-Encoding");
+        let encoding = match encoding {
+            Some(encoding) if length >= threshold => encoding,
+            _ => return Box::new(future::ok(response)),
+        };
+
+        let status = response.status();
+        let headers = response.headers().clone();
+        Box::new(response.body().concat2().map(move |body| {
+            let mut compressed_response = server::Response::new().with_status(status);
+            *compressed_response.headers_mut() = headers;
+            match compression::compress(&encoding, &body) {
+                Ok(compressed) => {
+                    // Compressed body differs byte by byte from identity one, so their tags can be equal only weakly
+                    let weak_tag = compressed_response.headers().get::<ETag>()
+                        .map(|&ETag(ref tag)| EntityTag::weak(tag.tag().to_string()));
+                    if let Some(weak_tag) = weak_tag {
+                        compressed_response.headers_mut().set(ETag(weak_tag));
+                    }
+                    compressed_response
+                        .with_header(ContentEncoding(vec![encoding]))
+                        .with_header(ContentLength(compressed.len() as u64))
+                        .with_body(compressed)
+                },
+                Err(err) => {
+                    warn!("Compression error {:?}", err);
+                    compressed_response.with_body(body)
+                },
+            }
+        }))
+    }
+
+    /// Body is passed on through a counting task, so entry is logged with bytes actually sent
+    /// whether body is sized, chunked, compressed or an event stream. Entry is logged once body
+    /// ends or client is gone.
+    fn log_response(
+        response: server::Response,
+        mut entry: access_log::Entry,
+        access_log: Arc<access_log::AccessLog>,
+        handle: &tokio_core::reactor::Handle,
+    ) -> server::Response {
+        let log = move |entry: &access_log::Entry| if let Err(err) = access_log.write(entry) {
+            error!("Access log error {:?}", err);
+        };
+
+        if response.body_ref().is_none() {
+            log(&entry);
+            return response
+        }
+
+        let mut counted_response = server::Response::new().with_status(response.status());
+        *counted_response.headers_mut() = response.headers().clone();
+        let (sender, body) = hyper::Body::pair();
+        let sent = Rc::new(Cell::new(0));
+        let counter = sent.clone();
+        handle.spawn(response.body()
+            .then(|chunk| Ok::<_, ()>(chunk))
+            .fold(sender, move |sender, chunk| {
+                let size = chunk.as_ref().map_or(0, |chunk| chunk.len() as u64);
+                let counter = counter.clone();
+                sender.send(chunk)
+                    .map(move |sender| {
+                        counter.set(counter.get() + size);
+                        sender
+                    })
+                    .map_err(|_| ())
+            })
+            .then(move |_| {
+                entry.bytes = sent.get();
+                log(&entry);
+                Ok(())
+            }));
+        counted_response.with_body(body)
+    }
+
+    /// Key from `Authorization: Bearer` or `X-Api-Key` header
+    fn api_key(headers: &hyper::Headers) -> Option<&str> {
+        use hyper::header::{Authorization, Bearer};
+
+        headers.get::<Authorization<Bearer>>()
+            .map(|authorization| authorization.token.as_str())
+            .or_else(|| headers.get_raw("X-Api-Key")
+                .and_then(|raw| raw.one())
+                .and_then(|value| str::from_utf8(value).ok()))
+    }
+
+    fn client_name(&self) -> String {
+        self.remote_addr.map_or("Unix socket".to_string(), |addr| addr.to_string())
+    }
+
+    /// Key must grant access when API keys are configured
+    fn authorize(&self, method: &hyper::Method, path: &str, api_key: Option<&str>) -> Result<(), AppError> {
+        if self.config.api_keys.is_empty() {
+            return Ok(())
+        }
+
+        auth::authorize(&self.config.api_keys, api_key, auth::Permission::of(method, path))
+            .map(|_| ())
+            .map_err(|err| {
+                warn!("Denied {} {} from {}: {}", method, path, self.client_name(), err);
+                AppError::AuthError(err)
+            })
+    }
+
+    /// Budget of known API key, otherwise of client IP. Unix socket clients are local and not limited.
+    fn rate_limit(&self, method: &hyper::Method, path: &str, api_key: Option<&str>) -> Result<(), AppError> {
+        let client = match (api_key, self.remote_addr) {
+            (Some(key), _) if auth::role(&self.config.api_keys, key).is_some() =>
+                limits::Client::ApiKey(key.to_string()),
+            (_, Some(addr)) => limits::Client::Ip(addr.ip()),
+            (_, None) => return Ok(()),
+        };
+
+        self.rate_limiter.check(client, auth::Permission::of(method, path))
+            .map_err(|retry_after| {
+                warn!("Rate limited {} {} from {}", method, path, self.client_name());
+                AppError::RateLimited(retry_after)
+            })
+    }
+
+    /// HTTP/2 has no connection headers
     fn connection_header(http_version: hyper::HttpVersion, headers: &hyper::Headers) ->
         Option<hyper::header::Connection>
     {
@@ -312,140 +1122,640 @@ impl server::Service for Router {
     type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
 
     fn call(&self, req: Self::Request) -> Self::Future {
+        let started = time::Instant::now();
+        let handle = self.handler.clone();
         let (method, uri, http_version, headers, body) = req.deconstruct();
+        let request_id = self.request_ids.get(headers.get_raw("X-Request-Id").and_then(|raw| raw.one()));
+        let logged_request = self.access_log.clone()
+            .map(|access_log| (access_log, method.to_string(), uri.path().to_string(), self.client_name()));
         let mut path_parts = uri.path().split('/').skip(1);
 
         let connection_header = Self::connection_header(http_version, &headers);
+        let encoding = headers.get::<hyper::header::AcceptEncoding>().and_then(compression::negotiate);
+        let body_format = format::Format::from_content_type(headers.get::<hyper::header::ContentType>());
+        let response_format = format::Format::negotiate(headers.get::<hyper::header::Accept>());
+        let compression_threshold = self.config.compression_threshold;
+        let if_none_match = headers.get::<hyper::header::IfNoneMatch>().cloned();
+        let if_match = Self::if_match_versions(headers.get::<hyper::header::IfMatch>().cloned());
+        let last_event_id = headers.get_raw("Last-Event-ID")
+            .and_then(|raw| raw.one())
+            .and_then(|value| str::from_utf8(value).ok())
+            .and_then(|value| value.parse().ok());
+
+        let api_key = Self::api_key(&headers);
+        let access = self.rate_limit(&method, uri.path(), api_key)
+            .and_then(|_| self.authorize(&method, uri.path(), api_key))
+            .and_then(|_| self.check_content_length(&headers));
+        let request = self.connection.start_request();
+        let connection = self.connection.clone();
 
         let result = match (method, path_parts.next(), path_parts.next(), path_parts.next(),
                 path_parts.next()) {
+            _ if access.is_err() =>
+                Box::new(future::ok(Self::app_error(access.unwrap_err(), response_format.unwrap_or(format::Format::Json)))),
             (_, _, _, _, Some(_)) => Self::not_found(),
+            (hyper::Method::Get, Some("countries"), None, None, None) =>
+                self.clone().get_countries(response_format),
+            (hyper::Method::Get, Some("countries"), Some(country), Some("cities"), None) =>
+                self.clone().get_country_cities(country, response_format),
+            (hyper::Method::Get, Some("changes"), None, None, None) =>
+                self.clone().get_changes(uri.query(), last_event_id),
+            (hyper::Method::Get, Some("locations"), Some("top"), None, None) =>
+                self.clone().get_top_locations(uri.query(), response_format),
             (hyper::Method::Get, Some(entity), Some(id_src), action, None) =>
                 match (entity, id_src.parse(), action) {
                     ("users", Ok(id), None) =>
-                        self.clone().get_user(id),
+                        self.clone().get_user(id, if_none_match, response_format),
                     ("users", Ok(id), Some("visits")) =>
-                        self.clone().get_user_visits(id, uri.query()),
+                        self.clone().get_user_visits(id, uri.query(), response_format),
+                    ("users", Ok(id), Some("summary")) =>
+                        self.clone().get_user_summary(id, response_format),
                     ("locations", Ok(id), None) =>
-                        self.clone().get_location(id),
+                        self.clone().get_location(id, if_none_match, response_format),
                     ("locations", Ok(id), Some("avg")) =>
-                        self.clone().get_location_rating(id, uri.query()),
+                        self.clone().get_location_rating(id, uri.query(), response_format),
+                    ("locations", Ok(id), Some("visits")) =>
+                        self.clone().get_location_visits(id, uri.query(), response_format),
+                    ("locations", Ok(id), Some("demographics")) =>
+                        self.clone().get_location_demographics(id, uri.query(), response_format),
                     ("visits", Ok(id), None) =>
-                        self.clone().get_visit(id),
+                        self.clone().get_visit(id, if_none_match, response_format),
+                    (entity, Ok(id), Some("history")) =>
+                        match models::EntityKind::from_path(entity) {
+                            Some(entity) => self.clone().get_history(entity, id, response_format),
+                            None => Self::not_found(),
+                        },
                     _ => Self::not_found(),
                 }
+            (hyper::Method::Post, Some("batch"), None, None, None) =>
+                self.clone().batch(body_format, body, response_format),
             (hyper::Method::Post, Some(entity), Some("new"), None, None) =>
                 match entity {
-                    "users" => self.clone().add_user(body),
-                    "locations" => self.clone().add_location(body),
-                    "visits" => self.clone().add_visit(body),
+                    "users" => self.clone().add_user(body_format, body, response_format),
+                    "locations" => self.clone().add_location(body_format, body, response_format),
+                    "visits" => self.clone().add_visit(body_format, body, response_format),
                     _ => Self::not_found(),
                 },
             (hyper::Method::Post, Some(entity), Some(id_src), None, None) =>
                 match (entity, id_src.parse()) {
-                    ("users", Ok(id)) => self.clone().update_user(id, body),
-                    ("locations", Ok(id)) => self.clone().update_location(id, body),
-                    ("visits", Ok(id)) => self.clone().update_visit(id, body),
+                    ("users", Ok(id)) => self.clone().update_user(id, if_match, body_format, body, response_format),
+                    ("locations", Ok(id)) => self.clone().update_location(id, if_match, body_format, body, response_format),
+                    ("visits", Ok(id)) => self.clone().update_visit(id, if_match, body_format, body, response_format),
                     _ => Self::not_found(),
                 }
+            (hyper::Method::Patch, Some(entity), Some(id_src), None, None) =>
+                match (models::EntityKind::from_path(entity), id_src.parse()) {
+                    (Some(entity), Ok(id)) =>
+                        self.clone().patch(entity, id, if_match, headers.get::<hyper::header::ContentType>().cloned(), body, response_format),
+                    _ => Self::not_found(),
+                },
             _ => Self::not_found(),
-        }.map(move |response|
-            if let Some(connection_header) =  connection_header {
-                response.with_header(connection_header)
-            } else {
-                response
+        }.map(move |response| {
+            drop(request);
+            // Rest of rejected HTTP/1 request is not read, HTTP/2 resets only its stream
+            let http1 = http_version == hyper::HttpVersion::Http10 || http_version == hyper::HttpVersion::Http11;
+            if http1 && response.headers().has::<hyper::header::Connection>() {
+                connection.close();
             }
-        );
+            match connection_header {
+                Some(ref connection_header) if !response.headers().has::<hyper::header::Connection>() =>
+                    response.with_header(connection_header.clone()),
+                _ => response,
+            }
+        }).and_then(move |response| Self::compress_response(response, encoding, compression_threshold))
+            .map(move |mut response| {
+                response.headers_mut().set_raw("X-Request-Id", request_id.clone());
+                match logged_request {
+                    Some((access_log, method, path, client)) => {
+                        let entry = access_log::Entry {
+                            request_id: request_id,
+                            method: method,
+                            path: path,
+                            status: response.status().as_u16(),
+                            bytes: 0,
+                            latency_ms: access_log::Entry::latency_ms(started.elapsed()),
+                            client: client,
+                        };
+                        Self::log_response(response, entry, access_log, &handle)
+                    },
+                    None => response,
+                }
+            });
 
         Box::new(result)
     }
 }
 
-const DEFAULT_LISTEN: &'static str = "127.0.0.1:9999";
-const DEFAULT_BACKLOG: &'static str = "1024";
-const DEFAULT_DATA_PATH: &'static str = "data";
-const DEFAULT_THREADS: &'static str = "4";
+/// Bind TCP listener of server thread. Threads share address with SO_REUSEPORT.
+fn bind_tcp(address: &std::net::SocketAddr, config: &config::Config) -> std::io::Result<std::net::TcpListener> {
+    let builder = if address.is_ipv4() {
+        net2::TcpBuilder::new_v4()?
+    } else {
+        let builder = net2::TcpBuilder::new_v6()?;
+        builder.only_v6(config.ipv6_only)?;
+        builder
+    };
+    builder.reuse_port(true)?;
+    builder.bind(address)?;
+
+    let listener = builder.listen(config.backlog)?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Bind Unix socket listener shared by all server threads. Stale socket of previous run is removed.
+fn bind_unix(path: &std::path::Path) -> std::io::Result<std::os::unix::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if let Ok(metadata) = std::fs::metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+
+    let listener = std::os::unix::net::UnixListener::bind(path)?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
 
-struct Config {
-    address: std::net::SocketAddr,
-    backlog: i32,
-    data_path: String,
-    threads: usize,
+fn serve_connection<I>(
+    io: I,
+    transport: protocol::Transport,
+    remote_addr: Option<std::net::SocketAddr>,
+    state: &ServerState,
+    connection: limits::Connection,
+    handle: &tokio_core::reactor::Handle,
+)
+where I: tokio_io::AsyncRead + tokio_io::AsyncWrite + 'static
+{
+    let router = Router::new(state, handle.clone(), remote_addr, connection);
+    let body_limits = state.config.body_limits();
+    protocol::serve(io, transport, remote_addr.unwrap_or_else(|| ([0, 0, 0, 0], 0).into()), router, body_limits, handle);
 }
 
-fn start_server(store: Arc<store::StoreWrapper>, config: &Config) {
-    let keepalive = STREAM_KEEPALIVE_SECS.map(time::Duration::from_secs);
-    let linger = STREAM_LINGER_SECS.map(time::Duration::from_secs);
+/// Close connections of idle clients and ones sending request head too slowly
+fn limit_stream<I>(
+    io: I,
+    connection: &limits::Connection,
+    config: &config::Config,
+    handle: &tokio_core::reactor::Handle,
+) -> limits::TimeoutIo<I> {
+    limits::TimeoutIo::new(
+        io,
+        connection,
+        config.header_read_timeout_secs.map(time::Duration::from_secs),
+        config.keep_alive_timeout_secs.map(time::Duration::from_secs),
+        handle,
+    )
+}
 
-    info!("Start listen on {} with backlog {}", config.address, config.backlog);
+fn configure_stream(stream: &tokio_core::net::TcpStream, config: &config::Config) {
+    debug!("Keepalive: {:?}", stream.keepalive().unwrap());
+    debug!("Linger: {:?}", stream.linger().unwrap());
+    debug!("Nodelay: {}", stream.nodelay().unwrap());
+    debug!("Send buffer size: {}", stream.send_buffer_size().unwrap());
+    debug!("Recv buffer size: {}", stream.recv_buffer_size().unwrap());
 
-    let net_listener = net2::TcpBuilder::new_v4().unwrap()
-        .reuse_port(true).unwrap()
-        .bind(config.address).unwrap()
-        .listen(config.backlog).unwrap();
+    stream.set_keepalive(config.stream_keepalive_secs.map(time::Duration::from_secs)).unwrap();
+    stream.set_linger(config.stream_linger_secs.map(time::Duration::from_secs)).unwrap();
+    stream.set_nodelay(config.stream_nodelay).unwrap();
+    stream.set_send_buffer_size(config.stream_send_buffer_size).unwrap();
+    stream.set_recv_buffer_size(config.stream_recv_buffer_size).unwrap();
+}
 
-    net_listener.set_nonblocking(true).unwrap();
+/// Report startup failure like configuration error and exit
+fn exit_on_error<T, E: fmt::Debug>(result: Result<T, E>, action: fmt::Arguments) -> T {
+    result.unwrap_or_else(|err| {
+        eprintln!("Startup error: {} failed: {:?}", action, err);
+        process::exit(2)
+    })
+}
 
-    let mut core = tokio_core::reactor::Core::new().unwrap();
+/// Listeners of one server thread bound before it is started
+struct Listeners {
+    tcp: Vec<(std::net::SocketAddr, std::net::TcpListener)>,
+    tls: Vec<(std::net::SocketAddr, std::net::TcpListener)>,
+    unix: Vec<std::os::unix::net::UnixListener>,
+}
+
+impl Listeners {
+    fn bind(config: &config::Config, tls: bool, unix: &[std::os::unix::net::UnixListener]) -> Self {
+        let bind = |address: &std::net::SocketAddr| {
+            (*address, exit_on_error(bind_tcp(address, config), format_args!("Bind {}", address)))
+        };
+        let tcp_addresses = config.listen.iter()
+            .filter_map(|listen| match *listen {
+                config::Listen::Tcp(ref address) => Some(address),
+                config::Listen::Unix(_) => None,
+            });
+        Self {
+            tcp: tcp_addresses.map(&bind).collect(),
+            tls: if tls { config.tls_listen.iter().map(&bind).collect() } else { Vec::new() },
+            unix: unix.iter()
+                .map(|listener| exit_on_error(listener.try_clone(), format_args!("Clone Unix listener")))
+                .collect(),
+        }
+    }
+}
+
+fn start_server(
+    state: ServerState,
+    listeners: Listeners,
+    tls: Option<Arc<tls::TlsAcceptor>>,
+) {
+    let config = state.config.clone();
+    let mut core = exit_on_error(tokio_core::reactor::Core::new(), format_args!("Create event loop"));
     let handle = core.handle();
+    let connections = limits::ConnectionLimit::new(config.max_connections);
+
+    let mut servers: Vec<Box<Future<Item = (), Error = std::io::Error>>> = Vec::new();
+
+    for (address, net_listener) in listeners.tcp {
+        info!("Start listen on {} with backlog {}", address, config.backlog);
+
+        let core_listener = exit_on_error(
+            tokio_core::net::TcpListener::from_listener(net_listener, &address, &handle),
+            format_args!("Listen {}", address),
+        );
 
-    let core_listener = tokio_core::net::TcpListener::from_listener(net_listener, &config.address, &handle).unwrap();
-
-    core.run(
-        core_listener.incoming().for_each(move |(stream, socket_addr)| {
-            debug!("Keepalive: {:?}", stream.keepalive().unwrap());
-            debug!("Linger: {:?}", stream.linger().unwrap());
-            debug!("Nodelay: {}", stream.nodelay().unwrap());
-            debug!("Send buffer size: {}", stream.send_buffer_size().unwrap());
-            debug!("Recv buffer size: {}", stream.recv_buffer_size().unwrap());
-
-            stream.set_keepalive(keepalive).unwrap();
-            stream.set_linger(linger).unwrap();
-            stream.set_nodelay(true).unwrap();
-            stream.set_send_buffer_size(STREAM_SEND_BUFFER_SIZE).unwrap();
-            stream.set_recv_buffer_size(STREAM_RECV_BUFFER_SIZE).unwrap();
-
-            info!("Connection from {}", socket_addr);
-            let router = Router::new(store.clone(), handle.clone());
-            hyper::server::Http::new()
-                .keep_alive(true)
-                .bind_connection(&handle, stream, socket_addr, router);
+        let (state, connections, handle) = (state.clone(), connections.clone(), handle.clone());
+        servers.push(Box::new(core_listener.incoming().for_each(move |(stream, socket_addr)| {
+            let connection = match connections.acquire() {
+                Some(connection) => connection,
+                None => {
+                    warn!("Refused connection from {}, {} connections open", socket_addr, connections.open());
+                    return Ok(())
+                },
+            };
+            configure_stream(&stream, &state.config);
+            debug!("Connection from {}", socket_addr);
+            let stream = limit_stream(stream, &connection, &state.config, &handle);
+            serve_connection(stream, protocol::Transport::Cleartext, Some(socket_addr), &state, connection, &handle);
             Ok(())
-        })
-    ).unwrap();
+        })));
+    }
+
+    if let Some(tls) = tls {
+        for (address, net_listener) in listeners.tls {
+            info!("Start TLS listen on {} with backlog {}", address, config.backlog);
+
+            let core_listener = exit_on_error(
+                tokio_core::net::TcpListener::from_listener(net_listener, &address, &handle),
+                format_args!("Listen {}", address),
+            );
+
+            let (state, connections, handle, tls) = (state.clone(), connections.clone(), handle.clone(), tls.clone());
+            servers.push(Box::new(core_listener.incoming().for_each(move |(stream, socket_addr)| {
+                let connection = match connections.acquire() {
+                    Some(connection) => connection,
+                    None => {
+                        warn!("Refused TLS connection from {}, {} connections open", socket_addr, connections.open());
+                        return Ok(())
+                    },
+                };
+                configure_stream(&stream, &state.config);
+                debug!("TLS connection from {}", socket_addr);
+                // Handshake has to complete within header read timeout as well
+                let stream = limit_stream(stream, &connection, &state.config, &handle);
+
+                let acceptor = match tls.acceptor() {
+                    Ok(acceptor) => acceptor,
+                    Err(err) => {
+                        error!("TLS acceptor error {:?}", err);
+                        return Ok(())
+                    },
+                };
+                let (state, connection_handle) = (state.clone(), handle.clone());
+                handle.spawn(acceptor.accept_async(stream)
+                    .map(move |stream|
+                        serve_connection(stream, protocol::Transport::Tls, Some(socket_addr), &state, connection, &connection_handle)
+                    )
+                    .map_err(move |err| warn!("TLS handshake with {} failed: {:?}", socket_addr, err))
+                );
+                Ok(())
+            })));
+        }
+    }
+
+    for unix_listener in listeners.unix {
+        let core_listener = exit_on_error(
+            tokio_uds::UnixListener::from_listener(unix_listener, &handle),
+            format_args!("Listen Unix socket"),
+        );
+
+        let (state, connections, handle) = (state.clone(), connections.clone(), handle.clone());
+        servers.push(Box::new(core_listener.incoming().for_each(move |(stream, _)| {
+            let connection = match connections.acquire() {
+                Some(connection) => connection,
+                None => {
+                    warn!("Refused connection from Unix socket, {} connections open", connections.open());
+                    return Ok(())
+                },
+            };
+            debug!("Connection from Unix socket");
+            let stream = limit_stream(stream, &connection, &state.config, &handle);
+            serve_connection(stream, protocol::Transport::Cleartext, None, &state, connection, &handle);
+            Ok(())
+        })));
+    }
+
+    core.run(future::join_all(servers)).unwrap();
 }
 
 fn main() {
     env_logger::init().unwrap();
 
-    let config = Arc::new(Config {
-        address: env::var("LISTEN").unwrap_or(DEFAULT_LISTEN.to_string())
-            .parse().unwrap(),
-        backlog: env::var("BACKLOG").unwrap_or(DEFAULT_BACKLOG.to_string())
-            .parse::<i32>().unwrap(),
-        data_path: env::var("DATA_PATH").unwrap_or(DEFAULT_DATA_PATH.to_string()),
-        threads: env::var("THREADS").unwrap_or(DEFAULT_THREADS.to_string())
-            .parse::<usize>().unwrap(),
+    let config = match config::load(env::args().skip(1).collect(), env::vars().collect()) {
+        Ok(config::Command::Run(config)) => Arc::new(config),
+        Ok(config::Command::PrintConfig(config)) => {
+            print!("{}", config.to_toml());
+            return
+        },
+        Ok(config::Command::Help) => {
+            print!("{}", config::usage());
+            return
+        },
+        Err(err) => {
+            eprintln!("Configuration error: {}", err);
+            process::exit(2)
+        },
+    };
+
+    let mut store_wrapper = if config.replicate_from.is_some() {
+        // Replica data comes from primary snapshot
+        store::StoreWrapper::new(store::Store::new(0)).as_replica()
+    } else {
+        let options = exit_on_error(loader::load_options(&config.data_path), format_args!("Load options"));
+        let mut store = store::Store::new(options.generated_at);
+        exit_on_error(loader::load_data(&mut store, &config.data_path), format_args!("Load data"));
+        store::StoreWrapper::new(store)
+    };
+    store_wrapper = store_wrapper.with_changes_buffer(config.changes_buffer);
+    if config.history_retention > 0 {
+        store_wrapper = store_wrapper.with_history(config.history_retention);
+    }
+    if let Some(threshold_ms) = config.slow_query_threshold_ms {
+        store_wrapper = store_wrapper.with_slow_query_threshold(time::Duration::from_millis(threshold_ms));
+    }
+    if let Some(max_scanned_visits) = config.max_scanned_visits {
+        store_wrapper = store_wrapper.with_max_scanned_visits(max_scanned_visits);
+    }
+    let store_wrapper = Arc::new(store_wrapper);
+
+    let replication_listener = config.replication_listen.map(|address| {
+        info!("Listen replicas on {}", address);
+        exit_on_error(std::net::TcpListener::bind(address), format_args!("Bind {}", address))
+    });
+
+    let unix_listeners = config.listen.iter()
+        .filter_map(|listen| match *listen {
+            config::Listen::Unix(ref path) => {
+                info!("Start listen on {}", listen);
+                Some(exit_on_error(bind_unix(path), format_args!("Bind {}", listen)))
+            },
+            config::Listen::Tcp(_) => None,
+        })
+        .collect::<Vec<std::os::unix::net::UnixListener>>();
+
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (&Some(ref cert), &Some(ref key)) if !config.tls_listen.is_empty() => {
+            Some(Arc::new(exit_on_error(tls::TlsAcceptor::new(cert.clone(), key.clone()), format_args!("Load TLS certificate"))))
+        },
+        _ => None,
+    };
+
+    let access_log = config.access_log.as_ref().map(|path| {
+        info!("Write access log to {}", path.display());
+        Arc::new(exit_on_error(
+            access_log::AccessLog::open(path.clone(), config.access_log_max_size, config.access_log_files),
+            format_args!("Open access log {}", path.display()),
+        ))
     });
 
-    let options = loader::load_options(&config.data_path).unwrap();
-    let mut store = store::Store::new(options.generated_at);
-    loader::load_data(&mut store, &config.data_path).unwrap();
+    // All listeners are bound before any thread is started, so startup fails as a whole
+    let thread_listeners = (0..config.threads)
+        .map(|_| Listeners::bind(&config, tls.is_some(), &unix_listeners))
+        .collect::<Vec<Listeners>>();
 
-    let store_wrapper = Arc::new(store::StoreWrapper::new(store));
+    if let Some(listener) = replication_listener {
+        let store_wrapper = store_wrapper.clone();
+        let (api_keys, buffer) = (config.api_keys.clone(), config.replication_buffer);
+        thread::Builder::new()
+            .name("Replication listener".to_string())
+            .spawn(move || replication::serve(store_wrapper, listener, api_keys, buffer))
+            .unwrap();
+    }
 
-    let threads = (0..config.threads).map(|thread_index| {
+    if let (Some(address), Some(api_key)) = (config.replicate_from, config.replication_api_key.clone()) {
         let store_wrapper = store_wrapper.clone();
-        let config = config.clone();
+        thread::Builder::new()
+            .name("Replication follower".to_string())
+            .spawn(move || replication::follow(store_wrapper, address, api_key))
+            .unwrap();
+    }
+
+    if let Some(ref tls) = tls {
+        let reloading_tls = tls.clone();
+        thread::Builder::new()
+            .name("TLS reloader".to_string())
+            .spawn(move || tls::reload_on_hangup(&reloading_tls))
+            .unwrap();
+    }
+
+    let state = ServerState {
+        store: store_wrapper,
+        config: config.clone(),
+        rate_limiter: Arc::new(limits::RateLimiter::new(
+            config.read_rate_limit,
+            config.write_rate_limit,
+            config.rate_limit_burst_secs,
+        )),
+        request_ids: Arc::new(access_log::RequestIds::new()),
+        access_log: access_log,
+    };
+
+    let threads = thread_listeners.into_iter().enumerate().map(|(thread_index, listeners)| {
+        let state = state.clone();
+        let tls = tls.clone();
         thread::Builder::new()
             .name(format!("Server thread {}", thread_index))
             .spawn(move ||
-                start_server(store_wrapper, &config)
+                start_server(state, listeners, tls)
             )
             .unwrap()
-    });
+    }).collect::<Vec<thread::JoinHandle<()>>>();
 
     for thread in threads {
         thread.join().unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Stream;
+    use hyper::header::*;
+    use hyper::{Method, StatusCode};
+    use tokio_core::reactor::Core;
+
+    const USER: &'static str = r#"{"email":"vasia.pupkin@mail.com","first_name":"Vasia","last_name":"Pupkin","gender":"m","birth_date":0}"#;
+
+    fn state(vars: &[(&str, &str)]) -> ServerState {
+        let env_vars = vars.iter().map(|&(name, value)| (name.to_string(), value.to_string())).collect();
+        let config = match config::load(Vec::new(), env_vars).unwrap() {
+            config::Command::Run(config) => config,
+            command => panic!("Unexpected command {:?}", command),
+        };
+
+        ServerState {
+            store: Arc::new(store::StoreWrapper::new(store::Store::new(0))),
+            rate_limiter: Arc::new(limits::RateLimiter::new(
+                config.read_rate_limit,
+                config.write_rate_limit,
+                config.rate_limit_burst_secs,
+            )),
+            config: Arc::new(config),
+            request_ids: Arc::new(access_log::RequestIds::new()),
+            access_log: None,
+        }
+    }
+
+    fn request(method: Method, path: &str, headers: &[(&str, &str)], body: Option<&str>) -> server::Request {
+        let mut request = server::Request::new(method, path.parse().unwrap());
+        for &(name, value) in headers {
+            request.headers_mut().set_raw(name.to_string(), value.to_string());
+        }
+        if let Some(body) = body {
+            request.headers_mut().set(ContentLength(body.len() as u64));
+            request.set_body(body.to_string());
+        }
+        request
+    }
+
+    /// Request served by router of new connection from local TCP client
+    fn call(core: &mut Core, state: &ServerState, request: server::Request) -> (server::Response, String) {
+        let connection = limits::ConnectionLimit::new(None).acquire().unwrap();
+        let router = Router::new(state, core.handle(), Some(([127, 0, 0, 1], 40000).into()), connection);
+        let response = core.run(server::Service::call(&router, request)).unwrap();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = core.run(response.body().concat2()).unwrap();
+        let response = server::Response::new().with_status(status).with_headers(headers);
+        (response, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    fn header<'a>(response: &'a server::Response, name: &str) -> Option<&'a str> {
+        response.headers().get_raw(name)
+            .and_then(|raw| raw.one())
+            .map(|value| str::from_utf8(value).unwrap())
+    }
+
+    #[test]
+    fn reject_missing_and_insufficient_keys() {
+        let mut core = Core::new().unwrap();
+        let state = state(&[("API_KEYS", "reader:read-only,writer:writer")]);
+
+        let (response, body) = call(&mut core, &state, request(Method::Get, "/users/1", &[], None));
+        assert_eq!(response.status(), StatusCode::Unauthorized);
+        assert_eq!(header(&response, "WWW-Authenticate"), Some("Bearer"));
+        assert_eq!(header(&response, "Content-Type"), Some("application/json"));
+        assert_eq!(body, r#"{"field":"Authorization","message":"API key is required"}"#);
+
+        let reader = [("Authorization", "Bearer reader")];
+        let (response, _) = call(&mut core, &state, request(Method::Get, "/users/1", &reader, None));
+        assert_eq!(response.status(), StatusCode::NotFound);
+
+        let (response, body) = call(&mut core, &state, request(Method::Post, "/users/new", &reader, Some(USER)));
+        assert_eq!(response.status(), StatusCode::Forbidden);
+        assert_eq!(header(&response, "WWW-Authenticate"), None);
+        let error: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(error["field"], "Authorization");
+
+        let writer = [("X-Api-Key", "writer")];
+        let (response, _) = call(&mut core, &state, request(Method::Post, "/users/new", &writer, Some(USER)));
+        assert_eq!(response.status(), StatusCode::Created);
+    }
+
+    #[test]
+    fn limit_request_rate() {
+        let mut core = Core::new().unwrap();
+        let state = state(&[("READ_RATE_LIMIT", "1")]);
+
+        let (response, _) = call(&mut core, &state, request(Method::Get, "/users/1", &[], None));
+        assert_eq!(response.status(), StatusCode::NotFound);
+
+        let (response, _) = call(&mut core, &state, request(Method::Get, "/users/1", &[], None));
+        assert_eq!(response.status(), StatusCode::TooManyRequests);
+        assert_eq!(response.headers().get::<RetryAfter>(), Some(&RetryAfter::Delay(time::Duration::from_secs(1))));
+
+        // Writes have own budget
+        let (response, _) = call(&mut core, &state, request(Method::Post, "/users/new", &[], Some(USER)));
+        assert_eq!(response.status(), StatusCode::Created);
+    }
+
+    #[test]
+    fn create_and_update_conditionally() {
+        let mut core = Core::new().unwrap();
+        let state = state(&[]);
+
+        let (response, body) = call(&mut core, &state, request(Method::Post, "/users/new", &[], Some(USER)));
+        assert_eq!(response.status(), StatusCode::Created);
+        assert_eq!(header(&response, "Location"), Some("/users/1"));
+        assert_eq!(body, r#"{"id":1}"#);
+
+        let (response, _) = call(&mut core, &state, request(Method::Get, "/users/1", &[], None));
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(header(&response, "ETag"), Some(r#""1""#));
+
+        let (response, body) = call(&mut core, &state, request(Method::Get, "/users/1", &[("If-None-Match", r#""1""#)], None));
+        assert_eq!(response.status(), StatusCode::NotModified);
+        assert_eq!(body, "");
+
+        // Tag of other format doesn't match
+        let cbor = [("If-None-Match", r#""1""#), ("Accept", "application/cbor")];
+        let (response, _) = call(&mut core, &state, request(Method::Get, "/users/1", &cbor, None));
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(header(&response, "ETag"), Some(r#""1-cbor""#));
+
+        let update = r#"{"first_name":"Vasilii"}"#;
+        let (response, _) = call(&mut core, &state, request(Method::Post, "/users/1", &[("If-Match", r#""2""#)], Some(update)));
+        assert_eq!(response.status(), StatusCode::PreconditionFailed);
+
+        let (response, _) = call(&mut core, &state, request(Method::Post, "/users/1", &[("If-Match", r#""1""#)], Some(update)));
+        assert_eq!(response.status(), StatusCode::Ok);
+
+        let (response, _) = call(&mut core, &state, request(Method::Get, "/users/1", &[("If-None-Match", r#""1""#)], None));
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(header(&response, "ETag"), Some(r#""2""#));
+    }
+
+    #[test]
+    fn negotiate_formats() {
+        let mut core = Core::new().unwrap();
+        let state = state(&[]);
+
+        let text = [("Content-Type", "text/plain")];
+        let (response, _) = call(&mut core, &state, request(Method::Post, "/users/new", &text, Some(USER)));
+        assert_eq!(response.status(), StatusCode::UnsupportedMediaType);
+
+        let html = [("Accept", "text/html")];
+        let (response, _) = call(&mut core, &state, request(Method::Get, "/countries", &html, None));
+        assert_eq!(response.status(), StatusCode::NotAcceptable);
+    }
+
+    #[test]
+    fn propagate_request_id() {
+        let mut core = Core::new().unwrap();
+        let state = state(&[]);
+
+        let (response, _) = call(&mut core, &state, request(Method::Get, "/users/1", &[("X-Request-Id", "client-42")], None));
+        assert_eq!(header(&response, "X-Request-Id"), Some("client-42"));
+
+        let (first, _) = call(&mut core, &state, request(Method::Get, "/users/1", &[], None));
+        let (second, _) = call(&mut core, &state, request(Method::Get, "/users/1", &[("X-Request-Id", "bad id")], None));
+        let (first, second) = (header(&first, "X-Request-Id").unwrap(), header(&second, "X-Request-Id").unwrap());
+        assert!(!first.is_empty());
+        assert_ne!(first, second);
+        assert_ne!(second, "bad id");
+    }
+}
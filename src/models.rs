@@ -1,8 +1,20 @@
+use std::collections::BTreeMap;
+
+use serde_json;
+use serde_json::{
+    Map,
+    Value,
+};
+
 pub type Id = u32;
 pub type Timestamp = i64;
 pub type Mark = u8;
+pub type Version = u64;
+
+/// Id of new entity posted without one until id is allocated for it
+pub const NEW_ID: Id = 0;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct ValidationError {
     pub field: String,
     pub message: String,
@@ -14,7 +26,7 @@ pub trait Validate {
     fn valid(&self) -> ValidationResult;
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct User {
     pub id: Id,
     pub email: String,
@@ -24,7 +36,7 @@ pub struct User {
     pub birth_date: Timestamp,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Default)]
 pub struct UserData {
     pub email: Option<String>,
     pub first_name: Option<String>,
@@ -33,6 +45,18 @@ pub struct UserData {
     pub birth_date: Option<Timestamp>,
 }
 
+impl From<User> for UserData {
+    fn from(user: User) -> Self {
+        Self {
+            email: Some(user.email),
+            first_name: Some(user.first_name),
+            last_name: Some(user.last_name),
+            gender: Some(user.gender),
+            birth_date: Some(user.birth_date),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct Location {
     pub id: Id,
@@ -50,6 +74,17 @@ pub struct LocationData {
     pub distance: Option<u32>,
 }
 
+impl From<Location> for LocationData {
+    fn from(location: Location) -> Self {
+        Self {
+            place: Some(location.place),
+            country: Some(location.country),
+            city: Some(location.city),
+            distance: Some(location.distance),
+        }
+    }
+}
+
 #[derive(
     Clone,
     Debug,
@@ -78,6 +113,107 @@ pub struct VisitData {
     pub mark: Option<u8>,
 }
 
+impl From<Visit> for VisitData {
+    fn from(visit: Visit) -> Self {
+        Self {
+            location: Some(visit.location),
+            user: Some(visit.user),
+            visited_at: Some(visit.visited_at),
+            mark: Some(visit.mark),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum EntityKind {
+    Users,
+    Locations,
+    Visits,
+}
+
+impl EntityKind {
+    pub fn from_path(path: &str) -> Option<Self> {
+        match path {
+            "users" => Some(EntityKind::Users),
+            "locations" => Some(EntityKind::Locations),
+            "visits" => Some(EntityKind::Visits),
+            _ => None,
+        }
+    }
+
+    pub fn path(&self) -> &'static str {
+        match *self {
+            EntityKind::Users => "users",
+            EntityKind::Locations => "locations",
+            EntityKind::Visits => "visits",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Operation {
+    AddUser(User),
+    UpdateUser(Id, UserData),
+    AddLocation(Location),
+    UpdateLocation(Id, LocationData),
+    AddVisit(Visit),
+    UpdateVisit(Id, VisitData),
+}
+
+impl Operation {
+    /// Add of new entity posted without id gets the allocated one, other operations are unchanged
+    pub fn with_id(self, id: Id) -> Self {
+        match self {
+            Operation::AddUser(user) => Operation::AddUser(User { id: id, ..user }),
+            Operation::AddLocation(location) => Operation::AddLocation(Location { id: id, ..location }),
+            Operation::AddVisit(visit) => Operation::AddVisit(Visit { id: id, ..visit }),
+            operation => operation,
+        }
+    }
+
+    /// Entity changed by operation
+    pub fn key(&self) -> (EntityKind, Id) {
+        match *self {
+            Operation::AddUser(ref user) => (EntityKind::Users, user.id),
+            Operation::UpdateUser(id, _) => (EntityKind::Users, id),
+            Operation::AddLocation(ref location) => (EntityKind::Locations, location.id),
+            Operation::UpdateLocation(id, _) => (EntityKind::Locations, id),
+            Operation::AddVisit(ref visit) => (EntityKind::Visits, visit.id),
+            Operation::UpdateVisit(id, _) => (EntityKind::Visits, id),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct BatchOperation {
+    pub entity: String,
+    pub id: Option<Id>,
+    pub body: Map<String, Value>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct BatchRequest {
+    pub atomic: Option<bool>,
+    pub operations: Vec<BatchOperation>,
+}
+
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct BatchItemResult {
+    pub status: u16,
+    /// Allocated id of entity created without one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Id>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ValidationError>,
+}
+
+#[derive(Clone, Debug, Serialize, Default, PartialEq)]
+pub struct BatchResult {
+    pub committed: bool,
+    pub results: Vec<BatchItemResult>,
+}
+
 #[derive(
     Clone,
     Debug,
@@ -89,19 +225,35 @@ pub struct GetUserVisitsOptions {
     pub from_date: Option<Timestamp>,
     pub to_date: Option<Timestamp>,
     pub country: Option<String>,
+    pub city: Option<String>,
+    pub from_distance: Option<u32>,
     pub to_distance: Option<u32>,
+    pub min_mark: Option<Mark>,
+    pub max_mark: Option<Mark>,
+    pub location: Option<Id>,
+    /// Include visit and location details into each `UserVisit`
+    pub expand: Option<bool>,
 }
 
 #[derive(
     Clone,
     Debug,
     Serialize,
+    Default,
     PartialEq,
 )]
 pub struct UserVisit {
     pub mark: Mark,
     pub visited_at: Timestamp,
     pub place: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Id>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<Id>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub city: Option<String>,
 }
 
 #[derive(
@@ -115,6 +267,17 @@ pub struct UserVisits {
     pub visits: Vec<UserVisit>
 }
 
+#[derive(Clone, Debug, Serialize, Default, PartialEq)]
+pub struct UserSummary {
+    pub visits: u64,
+    pub locations: u64,
+    pub countries: u64,
+    pub first_visit: Option<Timestamp>,
+    pub last_visit: Option<Timestamp>,
+    pub avg_mark: f64,
+    pub age: i32,
+}
+
 #[derive(Clone, Debug, Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct GetLocationAvgOptions {
@@ -138,6 +301,111 @@ pub struct LocationRate {
     pub avg: f64,
 }
 
+#[derive(Clone, Debug, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct GetTopLocationsOptions {
+    pub country: Option<String>,
+    pub city: Option<String>,
+    pub from_date: Option<Timestamp>,
+    pub to_date: Option<Timestamp>,
+    pub from_age: Option<i32>,
+    pub to_age: Option<i32>,
+    pub gender: Option<char>,
+    pub min_visits: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+impl GetTopLocationsOptions {
+    pub fn avg_options(&self) -> GetLocationAvgOptions {
+        GetLocationAvgOptions {
+            from_date: self.from_date,
+            to_date: self.to_date,
+            from_age: self.from_age,
+            to_age: self.to_age,
+            gender: self.gender,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct TopLocation {
+    pub id: Id,
+    pub place: String,
+    pub country: String,
+    pub city: String,
+    pub avg: f64,
+    pub visits: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Default, PartialEq)]
+pub struct TopLocations {
+    pub locations: Vec<TopLocation>,
+}
+
+#[derive(
+    Clone,
+    Debug,
+    Serialize,
+    PartialEq,
+)]
+pub struct LocationVisit {
+    pub id: Id,
+    pub mark: Mark,
+    pub visited_at: Timestamp,
+    pub user: User,
+}
+
+#[derive(
+    Clone,
+    Debug,
+    Serialize,
+    Default,
+    PartialEq,
+)]
+pub struct LocationVisits {
+    pub visits: Vec<LocationVisit>,
+}
+
+#[derive(Clone, Debug, Serialize, Default, PartialEq)]
+pub struct GenderCount {
+    pub f: u64,
+    pub m: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Default, PartialEq)]
+pub struct AgeBand {
+    pub from_age: i32,
+    pub to_age: Option<i32>,
+    pub visitors: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Default, PartialEq)]
+pub struct LocationDemographics {
+    pub visits: u64,
+    pub visitors: u64,
+    pub genders: GenderCount,
+    pub ages: Vec<AgeBand>,
+}
+
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct AreaStats {
+    pub name: String,
+    pub locations: u64,
+    pub visits: u64,
+    pub avg_mark: f64,
+    pub avg_distance: f64,
+}
+
+#[derive(Clone, Debug, Serialize, Default, PartialEq)]
+pub struct Countries {
+    pub countries: Vec<AreaStats>,
+}
+
+#[derive(Clone, Debug, Serialize, Default, PartialEq)]
+pub struct Cities {
+    pub cities: Vec<AreaStats>,
+}
+
 impl User {
     const MAX_EMAIL_LEN: usize = 100;
     const MAX_NAME_LEN: usize = 500;
@@ -212,5 +480,122 @@ impl Validate for Visit {
     }
 }
 
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct FieldChange {
+    pub before: Value,
+    pub after: Value,
+}
+
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct HistoryEntry {
+    pub entity: EntityKind,
+    pub id: Id,
+    pub time: Timestamp,
+    pub client: Option<String>,
+    pub changes: BTreeMap<String, FieldChange>,
+}
+
+#[derive(Clone, Debug, Serialize, Default, PartialEq)]
+pub struct EntityHistory {
+    pub history: Vec<HistoryEntry>,
+}
+
+/// Accepted mutation with entity state after it
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Change {
+    pub seq: u64,
+    pub entity: EntityKind,
+    pub id: Id,
+    pub version: Version,
+    pub data: Value,
+}
+
+#[derive(Clone, Debug, Deserialize, Default)]
+pub struct GetChangesOptions {
+    pub since: Option<u64>,
+    pub entity: Option<String>, // Comma separated entity kinds
+}
+
+impl GetChangesOptions {
+    /// Parsed entity filter. Unknown kind is returned as error.
+    pub fn entities(&self) -> Result<Option<Vec<EntityKind>>, String> {
+        match self.entity {
+            Some(ref entity) => entity.split(',')
+                .map(|kind| EntityKind::from_path(kind).ok_or_else(|| kind.to_string()))
+                .collect::<Result<Vec<EntityKind>, String>>()
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Full state of a replicated entity
+#[derive(Clone, Debug, PartialEq)]
+pub enum Entity {
+    User(User),
+    Location(Location),
+    Visit(Visit),
+}
+
+impl Entity {
+    pub fn from_value(entity: EntityKind, value: Value) -> Result<Self, serde_json::Error> {
+        Ok(match entity {
+            EntityKind::Users => Entity::User(serde_json::from_value(value)?),
+            EntityKind::Locations => Entity::Location(serde_json::from_value(value)?),
+            EntityKind::Visits => Entity::Visit(serde_json::from_value(value)?),
+        })
+    }
+
+    pub fn from_change(change: Change) -> Result<Self, serde_json::Error> {
+        Self::from_value(change.entity, change.data)
+    }
+
+    pub fn key(&self) -> (EntityKind, Id) {
+        match *self {
+            Entity::User(ref user) => (EntityKind::Users, user.id),
+            Entity::Location(ref location) => (EntityKind::Locations, location.id),
+            Entity::Visit(ref visit) => (EntityKind::Visits, visit.id),
+        }
+    }
+}
+
+/// Full store state. `seq` is the last change included in it.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Snapshot {
+    pub seq: u64,
+    pub now: Timestamp,
+    pub users: Vec<Versioned<User>>,
+    pub locations: Vec<Versioned<Location>>,
+    pub visits: Vec<Versioned<Visit>>,
+}
+
+/// First line of replica to primary stream
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ReplicationRequest {
+    pub api_key: String,
+}
+
+/// Line of primary to replica stream: snapshot first, then changes after it.
+/// Replica with rejected request gets only the reason.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplicationMessage {
+    Snapshot(Snapshot),
+    Change(Change),
+    Rejected(String),
+}
+
+/// Entity with its version counter, which is increased on each update
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Versioned<T> {
+    pub entity: T,
+    pub version: Version,
+}
+
 #[derive(Serialize, Debug, Clone, PartialEq)]
 pub struct Empty{}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CreatedEntity {
+    pub id: Id,
+}
@@ -0,0 +1,232 @@
+use serde_json::{
+    Map,
+    Value,
+};
+
+/// RFC 6902 operation
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum PatchOperation {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+    Move { from: String, path: String },
+    Copy { from: String, path: String },
+    Test { path: String, value: Value },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Patch {
+    Merge(Value), // RFC 7396
+    Json(Vec<PatchOperation>), // RFC 6902
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PatchError {
+    InvalidPointer(String),
+    PathNotFound(String),
+    TestFailed(String),
+    InvalidResult(String),
+}
+
+impl Patch {
+    /// Patched document. Document is left unchanged on any failed operation.
+    pub fn apply(&self, mut document: Value) -> Result<Value, PatchError> {
+        match *self {
+            Patch::Merge(ref patch) => merge(&mut document, patch),
+            Patch::Json(ref operations) =>
+                for operation in operations {
+                    apply_operation(&mut document, operation)?;
+                },
+        }
+        Ok(document)
+    }
+}
+
+fn merge(target: &mut Value, patch: &Value) {
+    let patch = match *patch {
+        Value::Object(ref patch) => patch,
+        _ => {
+            *target = patch.clone();
+            return
+        }
+    };
+
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    if let Value::Object(ref mut target) = *target {
+        for (key, value) in patch {
+            if value.is_null() {
+                target.remove(key);
+            } else {
+                merge(target.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
+    }
+}
+
+fn apply_operation(document: &mut Value, operation: &PatchOperation) -> Result<(), PatchError> {
+    match *operation {
+        PatchOperation::Add { ref path, ref value } =>
+            add(document, path, value.clone()),
+        PatchOperation::Remove { ref path } =>
+            remove(document, path).map(|_| ()),
+        PatchOperation::Replace { ref path, ref value } => {
+            let target = document.pointer_mut(path).ok_or_else(|| PatchError::PathNotFound(path.clone()))?;
+            *target = value.clone();
+            Ok(())
+        },
+        PatchOperation::Move { ref from, ref path } => {
+            if path.starts_with(&format!("{}/", from)) {
+                return Err(PatchError::InvalidPointer(path.clone()))
+            }
+            let value = remove(document, from)?;
+            add(document, path, value)
+        },
+        PatchOperation::Copy { ref from, ref path } => {
+            let value = document.pointer(from).cloned().ok_or_else(|| PatchError::PathNotFound(from.clone()))?;
+            add(document, path, value)
+        },
+        PatchOperation::Test { ref path, ref value } =>
+            match document.pointer(path) {
+                Some(actual) if actual == value => Ok(()),
+                _ => Err(PatchError::TestFailed(path.clone())),
+            },
+    }
+}
+
+/// Pointer of parent container and unescaped last reference token
+fn split_pointer(path: &str) -> Result<(&str, String), PatchError> {
+    match path.rfind('/') {
+        Some(index) if path.starts_with('/') =>
+            Ok((&path[..index], path[index + 1..].replace("~1", "/").replace("~0", "~"))),
+        _ => Err(PatchError::InvalidPointer(path.to_string())),
+    }
+}
+
+fn array_index(token: &str, len: usize, path: &str) -> Result<usize, PatchError> {
+    match token.parse::<usize>() {
+        Ok(index) if index < len && (token == "0" || !token.starts_with('0')) => Ok(index),
+        _ => Err(PatchError::PathNotFound(path.to_string())),
+    }
+}
+
+fn add(document: &mut Value, path: &str, value: Value) -> Result<(), PatchError> {
+    if path.is_empty() {
+        *document = value;
+        return Ok(())
+    }
+
+    let (parent_path, token) = split_pointer(path)?;
+    match document.pointer_mut(parent_path) {
+        Some(&mut Value::Object(ref mut parent)) => {
+            parent.insert(token, value);
+            Ok(())
+        },
+        Some(&mut Value::Array(ref mut parent)) => {
+            let index = if token == "-" {
+                parent.len()
+            } else {
+                array_index(&token, parent.len() + 1, path)?
+            };
+            parent.insert(index, value);
+            Ok(())
+        },
+        _ => Err(PatchError::PathNotFound(path.to_string())),
+    }
+}
+
+fn remove(document: &mut Value, path: &str) -> Result<Value, PatchError> {
+    let (parent_path, token) = split_pointer(path)?;
+    match document.pointer_mut(parent_path) {
+        Some(&mut Value::Object(ref mut parent)) =>
+            parent.remove(&token).ok_or_else(|| PatchError::PathNotFound(path.to_string())),
+        Some(&mut Value::Array(ref mut parent)) => {
+            let index = array_index(&token, parent.len(), path)?;
+            Ok(parent.remove(index))
+        },
+        _ => Err(PatchError::PathNotFound(path.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    fn json_patch(operations: Value) -> Patch {
+        Patch::Json(serde_json::from_value(operations).unwrap())
+    }
+
+    #[test]
+    fn merge_patch() {
+        let document = json!({
+            "title": "Goodbye!",
+            "author": {"givenName": "John", "familyName": "Doe"},
+            "tags": ["example", "sample"],
+            "content": "This will be unchanged",
+        });
+        let patch = Patch::Merge(json!({
+            "title": "Hello!",
+            "phoneNumber": "+01-123-456-7890",
+            "author": {"familyName": null},
+            "tags": ["example"],
+        }));
+
+        assert_eq!(patch.apply(document), Ok(json!({
+            "title": "Hello!",
+            "author": {"givenName": "John"},
+            "tags": ["example"],
+            "content": "This will be unchanged",
+            "phoneNumber": "+01-123-456-7890",
+        })));
+        assert_eq!(Patch::Merge(json!({"a": {"b": "c"}})).apply(json!({"a": "b"})), Ok(json!({"a": {"b": "c"}})));
+        assert_eq!(Patch::Merge(json!(["c"])).apply(json!({"a": "b"})), Ok(json!(["c"])));
+    }
+
+    #[test]
+    fn json_patch_operations() {
+        let patch = json_patch(json!([
+            {"op": "test", "path": "/a~1b", "value": 1},
+            {"op": "add", "path": "/list/1", "value": "x"},
+            {"op": "add", "path": "/list/-", "value": "z"},
+            {"op": "remove", "path": "/list/0"},
+            {"op": "replace", "path": "/a~1b", "value": 2},
+            {"op": "copy", "from": "/a~1b", "path": "/c"},
+            {"op": "move", "from": "/c", "path": "/d"},
+        ]));
+
+        assert_eq!(patch.apply(json!({"a/b": 1, "list": ["w", "y"]})), Ok(json!({
+            "a/b": 2,
+            "list": ["x", "y", "z"],
+            "d": 2,
+        })));
+    }
+
+    #[test]
+    fn json_patch_errors() {
+        let document = json!({"a": {"b": [1]}});
+
+        assert_eq!(
+            json_patch(json!([{"op": "test", "path": "/a/b/0", "value": 2}])).apply(document.clone()),
+            Err(PatchError::TestFailed("/a/b/0".into()))
+        );
+        assert_eq!(
+            json_patch(json!([{"op": "replace", "path": "/x", "value": 2}])).apply(document.clone()),
+            Err(PatchError::PathNotFound("/x".into()))
+        );
+        assert_eq!(
+            json_patch(json!([{"op": "add", "path": "/a/b/2", "value": 2}])).apply(document.clone()),
+            Err(PatchError::PathNotFound("/a/b/2".into()))
+        );
+        assert_eq!(
+            json_patch(json!([{"op": "remove", "path": "a"}])).apply(document.clone()),
+            Err(PatchError::InvalidPointer("a".into()))
+        );
+        assert_eq!(
+            json_patch(json!([{"op": "move", "from": "/a", "path": "/a/c"}])).apply(document.clone()),
+            Err(PatchError::InvalidPointer("/a/c".into()))
+        );
+    }
+}
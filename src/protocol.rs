@@ -0,0 +1,797 @@
+use std::cmp;
+use std::io;
+use std::io::{
+    Read,
+    Write,
+};
+use std::mem;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::str;
+
+use bytes::Bytes;
+use futures::{
+    future,
+    Async,
+    Future,
+    Poll,
+    Stream,
+};
+use h2;
+use http;
+use httparse;
+use hyper;
+use hyper::server::{
+    Http,
+    Service,
+};
+use tokio_core::reactor::Handle;
+use tokio_io;
+use tokio_io::{
+    AsyncRead,
+    AsyncWrite,
+};
+
+use super::limits;
+use super::limits::{
+    BodyError,
+    BodyLimits,
+};
+
+/// Client connection preface of HTTP/2
+const PREFACE: &'static [u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Larger request head is left to HTTP/1 server to reject
+const MAX_HEAD_SIZE: usize = 8192;
+
+/// Initial SETTINGS_MAX_FRAME_SIZE
+const MAX_FRAME_SIZE: usize = 16384;
+
+const FRAME_HEADER_SIZE: usize = 9;
+/// Identifier and value of SETTINGS parameter
+const SETTING_SIZE: usize = 6;
+const HEADERS_FRAME: u8 = 0x1;
+const SETTINGS_FRAME: u8 = 0x4;
+const END_STREAM: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+
+/// Stream of request sent with upgrade
+const UPGRADE_STREAM_ID: u32 = 1;
+
+const SWITCHING_PROTOCOLS: &'static [u8] = b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n";
+
+/// Headers of single HTTP/1 connection, forbidden in HTTP/2
+const CONNECTION_HEADERS: &'static [&'static str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+    "http2-settings",
+];
+
+/// Transport of connection. HTTP/2 is upgraded to on cleartext only, TLS connections negotiate it instead.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transport {
+    Cleartext,
+    Tls,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    IoError(io::Error),
+    H2Error(h2::Error),
+    HttpError(http::Error),
+    HyperError(hyper::Error),
+    InvalidPreface,
+    StreamClosed,
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::IoError(err)
+    }
+}
+
+impl From<h2::Error> for Error {
+    fn from(err: h2::Error) -> Self {
+        Error::H2Error(err)
+    }
+}
+
+impl From<http::Error> for Error {
+    fn from(err: http::Error) -> Self {
+        Error::HttpError(err)
+    }
+}
+
+impl From<hyper::Error> for Error {
+    fn from(err: hyper::Error) -> Self {
+        Error::HyperError(err)
+    }
+}
+
+/// Connection with bytes read ahead put back in front of it
+pub struct Prefixed<I> {
+    prefix: Vec<u8>,
+    position: usize,
+    io: I,
+}
+
+impl<I> Prefixed<I> {
+    fn new(prefix: Vec<u8>, io: I) -> Self {
+        Self {
+            prefix: prefix,
+            position: 0,
+            io: io,
+        }
+    }
+
+    fn consume(&mut self, size: usize) {
+        self.position = cmp::min(self.position + size, self.prefix.len());
+    }
+
+    fn prepend(&mut self, bytes: &[u8]) {
+        let mut prefix = bytes.to_vec();
+        prefix.extend_from_slice(&self.prefix[self.position..]);
+        self.prefix = prefix;
+        self.position = 0;
+    }
+}
+
+impl<I: Read> Read for Prefixed<I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position < self.prefix.len() {
+            let size = cmp::min(buf.len(), self.prefix.len() - self.position);
+            buf[..size].copy_from_slice(&self.prefix[self.position..self.position + size]);
+            self.position += size;
+            Ok(size)
+        } else {
+            self.io.read(buf)
+        }
+    }
+}
+
+impl<I: Write> Write for Prefixed<I> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.io.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<I: AsyncRead> AsyncRead for Prefixed<I> {}
+
+impl<I: AsyncWrite> AsyncWrite for Prefixed<I> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.io.shutdown()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Protocol {
+    Http1,
+    Http2,
+    /// HTTP/1.1 request asking for h2c. Request is resent to HTTP/2 server as HEADERS frame,
+    /// SETTINGS payload of `HTTP2-Settings` is applied before ones of client preface.
+    Upgrade {
+        head_size: usize,
+        settings: Vec<u8>,
+        headers_frame: Vec<u8>,
+    },
+}
+
+fn frame_header(length: usize, kind: u8, flags: u8, stream_id: u32) -> Vec<u8> {
+    vec![
+        (length >> 16) as u8, (length >> 8) as u8, length as u8,
+        kind, flags,
+        (stream_id >> 24) as u8, (stream_id >> 16) as u8, (stream_id >> 8) as u8, stream_id as u8,
+    ]
+}
+
+/// HPACK string without Huffman coding
+fn encode_string(block: &mut Vec<u8>, value: &[u8]) {
+    const PREFIX_MAX: usize = 0x7f;
+    let mut length = value.len();
+    if length < PREFIX_MAX {
+        block.push(length as u8);
+    } else {
+        block.push(PREFIX_MAX as u8);
+        length -= PREFIX_MAX;
+        while length >= 0x80 {
+            block.push((length % 0x80) as u8 | 0x80);
+            length /= 0x80;
+        }
+        block.push(length as u8);
+    }
+    block.extend_from_slice(value);
+}
+
+/// HPACK literal header field without indexing, so decoder table stays untouched
+fn encode_header(block: &mut Vec<u8>, name: &[u8], value: &[u8]) {
+    block.push(0);
+    encode_string(block, name);
+    encode_string(block, value);
+}
+
+/// Unpadded base64url, `None` if malformed
+fn decode_base64url(value: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(value.len() * 3 / 4);
+    let (mut bits, mut bit_count) = (0u32, 0);
+    for &c in value {
+        let sextet = match c {
+            c if c.is_ascii_uppercase() => c - b'A',
+            c if c.is_ascii_lowercase() => c - b'a' + 26,
+            c if c.is_ascii_digit() => c - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+        bits = bits << 6 | u32::from(sextet);
+        bit_count += 6;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            decoded.push((bits >> bit_count) as u8);
+            bits &= (1 << bit_count) - 1;
+        }
+    }
+    // Single character left over can not encode a byte
+    if bit_count >= 6 {
+        return None
+    }
+    Some(decoded)
+}
+
+/// SETTINGS payload of `HTTP2-Settings` header, `None` if it is not a list of parameters
+fn decode_settings(value: &[u8]) -> Option<Vec<u8>> {
+    decode_base64url(value).and_then(|settings| if settings.len() % SETTING_SIZE == 0 { Some(settings) } else { None })
+}
+
+/// SETTINGS payload and HEADERS frame of request with valid h2c upgrade. Requests with body stay on HTTP/1.1.
+/// Upgrade is accepted on cleartext connections only, so scheme is always `http`.
+fn upgrade_frames(request: &httparse::Request) -> Option<(Vec<u8>, Vec<u8>)> {
+    let header = |name: &str| request.headers.iter()
+        .find(|header| header.name.eq_ignore_ascii_case(name))
+        .map(|header| header.value);
+    let has_token = |name: &str, token: &str| header(name)
+        .and_then(|value| str::from_utf8(value).ok())
+        .map_or(false, |value| value.split(',').any(|item| item.trim().eq_ignore_ascii_case(token)));
+
+    if request.version != Some(1) || !has_token("upgrade", "h2c") || !has_token("connection", "upgrade") ||
+        header("transfer-encoding").is_some() || header("content-length").map_or(false, |length| length != b"0")
+    {
+        return None
+    }
+    let settings = decode_settings(header("http2-settings")?)?;
+
+    let mut block = Vec::new();
+    encode_header(&mut block, b":method", request.method?.as_bytes());
+    encode_header(&mut block, b":scheme", b"http");
+    encode_header(&mut block, b":authority", header("host")?);
+    encode_header(&mut block, b":path", request.path?.as_bytes());
+    for header in request.headers.iter() {
+        let name = header.name.to_lowercase();
+        if name != "host" && !CONNECTION_HEADERS.contains(&name.as_str()) {
+            encode_header(&mut block, name.as_bytes(), header.value);
+        }
+    }
+
+    if block.len() > MAX_FRAME_SIZE {
+        return None
+    }
+    let mut frame = frame_header(block.len(), HEADERS_FRAME, END_STREAM | END_HEADERS, UPGRADE_STREAM_ID);
+    frame.extend(block);
+    Some((settings, frame))
+}
+
+/// Protocol of connection by its first bytes or `None` if more bytes are required
+fn detect_protocol(buffer: &[u8], transport: Transport) -> Option<Protocol> {
+    if buffer.starts_with(PREFACE) {
+        return Some(Protocol::Http2)
+    }
+    if PREFACE.starts_with(buffer) {
+        return None
+    }
+
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut request = httparse::Request::new(&mut headers);
+    match request.parse(buffer) {
+        Ok(httparse::Status::Complete(_)) if transport != Transport::Cleartext => Some(Protocol::Http1),
+        Ok(httparse::Status::Complete(head_size)) =>
+            Some(upgrade_frames(&request).map_or(Protocol::Http1, |(settings, headers_frame)| Protocol::Upgrade {
+                head_size: head_size,
+                settings: settings,
+                headers_frame: headers_frame,
+            })),
+        Ok(httparse::Status::Partial) if buffer.len() < MAX_HEAD_SIZE => None,
+        _ => Some(Protocol::Http1),
+    }
+}
+
+/// Reads connection until its protocol is known
+struct Detect<I> {
+    io: Option<I>,
+    transport: Transport,
+    buffer: Vec<u8>,
+}
+
+impl<I: AsyncRead> Future for Detect<I> {
+    type Item = (Protocol, Prefixed<I>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let mut chunk = [0; 4096];
+            let size = match self.io.as_mut().expect("Detect polled after completion").read(&mut chunk) {
+                Ok(size) => size,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(err) => return Err(err),
+            };
+            self.buffer.extend_from_slice(&chunk[..size]);
+
+            let protocol = if size == 0 { Some(Protocol::Http1) } else { detect_protocol(&self.buffer, self.transport) };
+            if let Some(protocol) = protocol {
+                let io = self.io.take().unwrap();
+                let buffer = mem::replace(&mut self.buffer, Vec::new());
+                return Ok(Async::Ready((protocol, Prefixed::new(buffer, io))))
+            }
+        }
+    }
+}
+
+/// Sends response body as DATA frames within flow control window of stream
+struct SendBody {
+    body: hyper::Body,
+    stream: h2::SendStream<Bytes>,
+    pending: Option<Bytes>,
+}
+
+impl Future for SendBody {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if let Some(mut data) = self.pending.take() {
+                self.stream.reserve_capacity(data.len());
+                match self.stream.poll_capacity()? {
+                    Async::Ready(Some(capacity)) if capacity > 0 => {
+                        let rest = data.split_off(cmp::min(capacity, data.len()));
+                        self.stream.send_data(data, false)?;
+                        if !rest.is_empty() {
+                            self.pending = Some(rest);
+                        }
+                    },
+                    Async::Ready(None) => return Err(Error::StreamClosed),
+                    _ => {
+                        self.pending = Some(data);
+                        return Ok(Async::NotReady)
+                    },
+                }
+                continue
+            }
+
+            match self.body.poll()? {
+                Async::Ready(Some(chunk)) =>
+                    if !chunk.is_empty() {
+                        self.pending = Some(Bytes::from(chunk));
+                    },
+                Async::Ready(None) => {
+                    self.stream.send_data(Bytes::new(), true)?;
+                    return Ok(Async::Ready(()))
+                },
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+fn hyper_request(parts: http::request::Parts, body: Vec<u8>) -> Result<hyper::Request, Error> {
+    let method = parts.method.as_str().parse()?;
+    let uri = parts.uri.to_string().parse().map_err(hyper::Error::from)?;
+
+    let mut request = hyper::Request::new(method, uri);
+    request.set_version(hyper::HttpVersion::H2c);
+    for (name, value) in &parts.headers {
+        request.headers_mut().append_raw(name.as_str().to_string(), value.as_bytes().to_vec());
+    }
+    request.set_body(body);
+    Ok(request)
+}
+
+fn h2_response(response: hyper::Response) -> Result<(http::Response<()>, hyper::Body), Error> {
+    let mut head = http::Response::builder();
+    head.status(response.status().as_u16());
+    for header in response.headers().iter() {
+        if !CONNECTION_HEADERS.contains(&header.name().to_lowercase().as_str()) {
+            for value in header.raw().iter() {
+                head.header(header.name(), value);
+            }
+        }
+    }
+    let head = head.body(())?;
+    Ok((head, response.body()))
+}
+
+/// Too large or too slow body is answered with 413 or 408. Stream is reset once its handles are dropped,
+/// so the rest of body is refused.
+fn reject_h2_body(mut respond: h2::server::SendResponse<Bytes>, err: BodyError) -> Result<(), Error> {
+    let status = match err {
+        BodyError::TooLarge => 413,
+        BodyError::TimedOut => 408,
+        BodyError::HyperError(err) => return Err(Error::HyperError(err)),
+        BodyError::IoError(err) => return Err(Error::IoError(err)),
+    };
+    respond.send_response(http::Response::builder().status(status).body(())?, true)?;
+    Ok(())
+}
+
+fn respond_h2<S>(
+    service: Rc<S>,
+    request: http::Request<h2::RecvStream>,
+    mut respond: h2::server::SendResponse<Bytes>,
+    body_limits: BodyLimits,
+    handle: &Handle,
+) -> Box<Future<Item = (), Error = Error>>
+where S: Service<Request = hyper::Request, Response = hyper::Response, Error = hyper::Error> + 'static
+{
+    let (parts, mut body) = request.into_parts();
+    let mut release_capacity = body.release_capacity().clone();
+    let body = body
+        .and_then(move |chunk| {
+            release_capacity.release_capacity(chunk.len())?;
+            Ok(chunk)
+        })
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err));
+
+    let response = limits::read_body(body, body_limits, handle)
+        .then(move |content| match content {
+            Ok(content) => future::Either::A(
+                future::result(hyper_request(parts, content))
+                    .and_then(move |request| service.call(request).map_err(Error::from))
+                    .and_then(move |response| {
+                        let (head, body) = h2_response(response)?;
+                        Ok(SendBody {
+                            body: body,
+                            stream: respond.send_response(head, false)?,
+                            pending: None,
+                        })
+                    })
+                    .flatten()
+            ),
+            Err(err) => future::Either::B(future::result(reject_h2_body(respond, err))),
+        });
+
+    Box::new(response)
+}
+
+/// Every stream is answered by own task, so slow response does not delay others
+fn serve_h2<I, S>(io: I, service: S, body_limits: BodyLimits, handle: Handle) -> Box<Future<Item = (), Error = Error>>
+where I: AsyncRead + AsyncWrite + 'static,
+      S: Service<Request = hyper::Request, Response = hyper::Response, Error = hyper::Error> + 'static
+{
+    let service = Rc::new(service);
+    Box::new(h2::server::handshake(io)
+        .and_then(move |connection| connection.for_each(move |(request, respond)| {
+            handle.spawn(respond_h2(service.clone(), request, respond, body_limits, &handle)
+                .map_err(|err| debug!("HTTP/2 stream failed: {:?}", err)));
+            Ok(())
+        }))
+        .map_err(Error::from))
+}
+
+/// Answer upgrade request and continue connection as HTTP/2. Client preface and SETTINGS are read first
+/// to put upgrade request after them as stream 1. Upgrade `settings` are prepended to SETTINGS of preface,
+/// so they apply first and client gets single acknowledgement.
+fn upgrade_h2<I>(mut io: Prefixed<I>, head_size: usize, settings: Vec<u8>, headers_frame: Vec<u8>) ->
+    Box<Future<Item = Prefixed<I>, Error = Error>>
+where I: AsyncRead + AsyncWrite + 'static
+{
+    io.consume(head_size);
+    Box::new(tokio_io::io::write_all(io, SWITCHING_PROTOCOLS)
+        .and_then(|(io, _)| tokio_io::io::read_exact(io, vec![0; PREFACE.len() + FRAME_HEADER_SIZE]))
+        .map_err(Error::from)
+        .and_then(move |(io, start)| {
+            let header = &start[PREFACE.len()..];
+            let payload_size = (header[0] as usize) << 16 | (header[1] as usize) << 8 | header[2] as usize;
+            if !start.starts_with(PREFACE) || header[3] != SETTINGS_FRAME || settings.len() + payload_size > MAX_FRAME_SIZE {
+                return Err(Error::InvalidPreface)
+            }
+            let flags = header[4];
+            Ok(tokio_io::io::read_exact(io, vec![0; payload_size])
+                .map_err(Error::from)
+                .map(move |(mut io, payload)| {
+                    let settings_frame = frame_header(settings.len() + payload.len(), SETTINGS_FRAME, flags, 0);
+                    io.prepend(&[PREFACE, &settings_frame[..], &settings[..], &payload[..], &headers_frame[..]].concat());
+                    io
+                }))
+        })
+        .flatten())
+}
+
+/// Serve HTTP/1.x, HTTP/2 with prior knowledge and, on cleartext `transport`, HTTP/2 upgraded from HTTP/1.1
+/// on connection. Body of HTTP/1 request is read by service, one of HTTP/2 stream within `body_limits`
+/// before calling it.
+pub fn serve<I, S>(io: I, transport: Transport, remote_addr: SocketAddr, service: S, body_limits: BodyLimits, handle: &Handle)
+where I: AsyncRead + AsyncWrite + 'static,
+      S: Service<Request = hyper::Request, Response = hyper::Response, Error = hyper::Error> + 'static
+{
+    let connection_handle = handle.clone();
+    let detect = Detect {
+        io: Some(io),
+        transport: transport,
+        buffer: Vec::new(),
+    };
+
+    handle.spawn(detect
+        .map_err(Error::from)
+        .and_then(move |(protocol, io)| -> Box<Future<Item = (), Error = Error>> {
+            debug!("Protocol of {}: {:?}", remote_addr, protocol);
+            match protocol {
+                Protocol::Http1 => {
+                    Http::new()
+                        .keep_alive(true)
+                        .bind_connection(&connection_handle, io, remote_addr, service);
+                    Box::new(future::ok(()))
+                },
+                Protocol::Http2 =>
+                    serve_h2(io, service, body_limits, connection_handle),
+                Protocol::Upgrade { head_size, settings, headers_frame } =>
+                    Box::new(upgrade_h2(io, head_size, settings, headers_frame)
+                        .and_then(move |io| serve_h2(io, service, body_limits, connection_handle))),
+            }
+        })
+        .map_err(move |err| debug!("Connection from {} failed: {:?}", remote_addr, err)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use tokio_core;
+    use tokio_core::reactor::{
+        Core,
+        Timeout,
+    };
+
+    const SLOW_DELAY_MS: u64 = 200;
+    const MAX_BODY_SIZE: u64 = 16;
+    const BODY_TIMEOUT_MS: u64 = 300;
+
+    /// Responds with request path, paths starting with `/slow` after delay
+    struct PathService {
+        handle: Handle,
+    }
+
+    impl Service for PathService {
+        type Request = hyper::Request;
+        type Response = hyper::Response;
+        type Error = hyper::Error;
+        type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+
+        fn call(&self, request: Self::Request) -> Self::Future {
+            let path = request.path().to_string();
+            let response = hyper::Response::new()
+                .with_header(hyper::header::ContentLength(path.len() as u64))
+                .with_body(path);
+            let delay = if request.path().starts_with("/slow") { SLOW_DELAY_MS } else { 0 };
+            Box::new(Timeout::new(Duration::from_millis(delay), &self.handle).unwrap()
+                .map(move |_| response)
+                .map_err(hyper::Error::from))
+        }
+    }
+
+    fn start_server() -> SocketAddr {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut core = Core::new().unwrap();
+            let handle = core.handle();
+            let listener = tokio_core::net::TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+            sender.send(listener.local_addr().unwrap()).unwrap();
+
+            core.run(listener.incoming().for_each(|(stream, remote_addr)| {
+                let body_limits = BodyLimits {
+                    max_size: Some(MAX_BODY_SIZE),
+                    timeout: Some(Duration::from_millis(BODY_TIMEOUT_MS)),
+                };
+                serve(stream, Transport::Cleartext, remote_addr, PathService { handle: handle.clone() }, body_limits, &handle);
+                Ok(())
+            })).unwrap();
+        });
+        receiver.recv().unwrap()
+    }
+
+    fn read_to_end(mut stream: TcpStream) -> String {
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    fn bodies(response: &str) -> Vec<&str> {
+        response.split("HTTP/1.1 200 OK").skip(1)
+            .map(|response| response.split("\r\n\r\n").nth(1).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn detect_protocols() {
+        let detect = |buffer: &[u8]| detect_protocol(buffer, Transport::Cleartext);
+        assert_eq!(detect(b""), None);
+        assert_eq!(detect(b"PRI * HTTP/2"), None);
+        assert_eq!(detect(PREFACE), Some(Protocol::Http2));
+        assert_eq!(detect(b"GET /users/1 HTTP/1.1\r\nHost: a\r\n"), None);
+        assert_eq!(detect(b"GET /users/1 HTTP/1.1\r\nHost: a\r\n\r\n"), Some(Protocol::Http1));
+        assert_eq!(detect(b"PUT / HTTP/1.1"), None);
+        assert_eq!(detect(b"\x16\x03\x01"), Some(Protocol::Http1));
+
+        let upgrade = b"GET /users/1 HTTP/1.1\r\nHost: a\r\nConnection: Upgrade, HTTP2-Settings\r\n\
+                        Upgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQAoAAAAAIAAAAA\r\n\r\n";
+        assert_matches!(
+            detect(upgrade),
+            Some(Protocol::Upgrade { head_size, ref settings, .. })
+                if head_size == upgrade.len() && settings[..] == [0, 3, 0, 0, 0, 100, 0, 4, 0, 160, 0, 0, 0, 2, 0, 0, 0, 0][..]
+        );
+        assert_eq!(detect_protocol(upgrade, Transport::Tls), Some(Protocol::Http1));
+
+        let upgrade_with_body = b"POST /users/1 HTTP/1.1\r\nHost: a\r\nConnection: Upgrade, HTTP2-Settings\r\n\
+                                  Upgrade: h2c\r\nHTTP2-Settings: \r\nContent-Length: 2\r\n\r\n{}";
+        assert_eq!(detect(upgrade_with_body), Some(Protocol::Http1));
+
+        for settings in &["AAMAAABk=", "AAMAAA", "AAMAAABkA", ""] {
+            let upgrade = format!("GET / HTTP/1.1\r\nHost: a\r\nConnection: Upgrade, HTTP2-Settings\r\n\
+                                   Upgrade: h2c\r\nHTTP2-Settings: {}\r\n\r\n", settings);
+            let upgraded = match detect(upgrade.as_bytes()) {
+                Some(Protocol::Upgrade { .. }) => true,
+                _ => false,
+            };
+            assert_eq!(upgraded, settings.is_empty(), "HTTP2-Settings: {}", settings);
+        }
+        let without_settings = b"GET / HTTP/1.1\r\nHost: a\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n";
+        assert_eq!(detect(without_settings), Some(Protocol::Http1));
+    }
+
+    #[test]
+    fn decode_upgrade_settings() {
+        assert_eq!(decode_base64url(b"AAQAAAAE"), Some(vec![0, 4, 0, 0, 0, 4]));
+        assert_eq!(decode_base64url(b"-_8"), Some(vec![0xfb, 0xff]));
+        assert_eq!(decode_base64url(b"AAQAAAAEA"), None);
+        assert_eq!(decode_base64url(b"AA+/"), None);
+        assert_eq!(decode_settings(b"AAQAAAAE"), Some(vec![0, 4, 0, 0, 0, 4]));
+        assert_eq!(decode_settings(b"AAQAAA"), None);
+    }
+
+    #[test]
+    fn encode_long_string() {
+        let mut block = Vec::new();
+        encode_string(&mut block, &[b'a'; 300]);
+        assert_eq!(&block[..3], &[0x7f, 0xad, 0x01]);
+        assert_eq!(block.len(), 303);
+    }
+
+    #[test]
+    fn pipelined_responses_in_order() {
+        let mut stream = TcpStream::connect(start_server()).unwrap();
+        stream.write_all(
+            b"GET /slow HTTP/1.1\r\nHost: a\r\n\r\n\
+              GET /fast HTTP/1.1\r\nHost: a\r\n\r\n\
+              GET /slow/last HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n"
+        ).unwrap();
+
+        assert_eq!(bodies(&read_to_end(stream)), vec!["/slow", "/fast", "/slow/last"]);
+    }
+
+    #[test]
+    fn multiplexed_responses_with_prior_knowledge() {
+        let address = start_server();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let responses = tokio_core::net::TcpStream::connect(&address, &handle)
+            .map_err(h2::Error::from)
+            .and_then(h2::client::handshake)
+            .and_then(move |(mut client, connection)| {
+                handle.spawn(connection.map_err(|_| ()));
+                let requests = ["/slow", "/fast"].iter()
+                    .map(|path| {
+                        let request = http::Request::get(format!("http://localhost{}", path)).body(()).unwrap();
+                        let (response, _) = client.send_request(request, true).unwrap();
+                        response
+                            .and_then(|response| response.into_body().concat2())
+                            .map(|body| String::from_utf8(body.to_vec()).unwrap())
+                    })
+                    .collect::<Vec<_>>();
+                future::select_all(requests)
+                    .map_err(|(err, _, _)| err)
+                    .and_then(|(first, _, rest)| future::join_all(rest).map(move |rest| (first, rest)))
+            });
+
+        // Fast stream is answered before slow one requested earlier
+        let (first, rest) = core.run(responses).unwrap();
+        assert_eq!(first, "/fast");
+        assert_eq!(rest, vec!["/slow"]);
+    }
+
+    /// Status of HTTP/2 request with `body`, stream is left open unless `end_of_stream`
+    fn h2_post_status(body: &'static [u8], end_of_stream: bool) -> u16 {
+        let address = start_server();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let status = tokio_core::net::TcpStream::connect(&address, &handle)
+            .map_err(h2::Error::from)
+            .and_then(h2::client::handshake)
+            .and_then(move |(mut client, connection)| {
+                handle.spawn(connection.map_err(|_| ()));
+                let request = http::Request::post("http://localhost/body").body(()).unwrap();
+                let (response, mut stream) = client.send_request(request, false).unwrap();
+                stream.send_data(Bytes::from_static(body), end_of_stream).unwrap();
+                response.map(move |response| {
+                    drop((client, stream));
+                    response.status().as_u16()
+                })
+            });
+        core.run(status).unwrap()
+    }
+
+    #[test]
+    fn limit_h2_body() {
+        assert_eq!(h2_post_status(b"{\"id\":1}", true), 200);
+        assert_eq!(h2_post_status(b"{\"id\":1, \"place\": \"Musei\"}", true), 413);
+        assert_eq!(h2_post_status(b"{\"id\":", false), 408);
+    }
+
+    /// DATA frames of response to upgrade request with `settings` until end of stream or `max_frames` of them
+    fn upgraded_data_frames(settings: &str, max_frames: usize) -> Vec<Vec<u8>> {
+        let mut stream = TcpStream::connect(start_server()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        write!(
+            stream,
+            "GET /upgraded HTTP/1.1\r\nHost: a\r\nConnection: Upgrade, HTTP2-Settings\r\n\
+             Upgrade: h2c\r\nHTTP2-Settings: {}\r\n\r\n",
+            settings
+        ).unwrap();
+
+        let mut response = vec![0; SWITCHING_PROTOCOLS.len()];
+        stream.read_exact(&mut response).unwrap();
+        assert_eq!(response, SWITCHING_PROTOCOLS);
+
+        stream.write_all(PREFACE).unwrap();
+        stream.write_all(&frame_header(0, SETTINGS_FRAME, 0, 0)).unwrap();
+
+        // Response to upgrade request comes as DATA frames of stream 1
+        let mut frames = Vec::new();
+        while frames.len() < max_frames {
+            let mut header = [0; FRAME_HEADER_SIZE];
+            stream.read_exact(&mut header).unwrap();
+            let mut payload = vec![0; (header[0] as usize) << 16 | (header[1] as usize) << 8 | header[2] as usize];
+            stream.read_exact(&mut payload).unwrap();
+
+            const DATA_FRAME: u8 = 0x0;
+            if header[3] == DATA_FRAME && header[8] == UPGRADE_STREAM_ID as u8 {
+                frames.push(payload);
+                if header[4] & END_STREAM != 0 {
+                    break
+                }
+            }
+        }
+        frames
+    }
+
+    #[test]
+    fn upgrade_to_h2c() {
+        assert_eq!(upgraded_data_frames("", usize::max_value()).concat(), b"/upgraded");
+    }
+
+    #[test]
+    fn apply_upgrade_settings() {
+        // SETTINGS_INITIAL_WINDOW_SIZE of 4 bytes limits first DATA frame
+        assert_eq!(upgraded_data_frames("AAQAAAAE", 1), vec![b"/upg".to_vec()]);
+    }
+}
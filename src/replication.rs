@@ -0,0 +1,280 @@
+use std::io;
+use std::io::{
+    BufRead,
+    Read,
+    Write,
+};
+use std::net::{
+    SocketAddr,
+    TcpListener,
+    TcpStream,
+};
+use std::sync::Arc;
+use std::thread;
+use std::time;
+
+use futures::Stream;
+use serde_json;
+
+use super::auth;
+use super::models::*;
+use super::store;
+
+const RECONNECT_DELAY_SECS: u64 = 1;
+
+/// Time connected replica has to send its request
+const REQUEST_TIMEOUT_SECS: u64 = 10;
+
+const MAX_REQUEST_SIZE: u64 = 4096;
+
+#[derive(Debug)]
+pub enum Error {
+    IoError(io::Error),
+    JsonError(serde_json::Error),
+    StoreError(store::StoreError),
+    AuthError(auth::Error),
+    Rejected(String),
+    UnexpectedSeq {
+        expected: u64,
+        actual: u64,
+    },
+    SubscriptionClosed,
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::IoError(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::JsonError(err)
+    }
+}
+
+impl From<store::StoreError> for Error {
+    fn from(err: store::StoreError) -> Self {
+        Error::StoreError(err)
+    }
+}
+
+fn write_message(stream: &mut io::BufWriter<TcpStream>, message: &ReplicationMessage) -> Result<(), Error> {
+    serde_json::to_writer(&mut *stream, message)?;
+    stream.write_all(b"\n")?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Check API key of replica, send it snapshot and then stream all following changes.
+/// Replica is disconnected when more than `buffer` changes are not sent to it yet.
+fn serve_replica(store: &store::StoreWrapper, stream: TcpStream, api_keys: &[auth::ApiKey], buffer: usize) -> Result<(), Error> {
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(time::Duration::from_secs(REQUEST_TIMEOUT_SECS)))?;
+
+    let mut line = String::new();
+    io::BufReader::new((&stream).take(MAX_REQUEST_SIZE)).read_line(&mut line)?;
+    let request: ReplicationRequest = serde_json::from_str(&line)?;
+
+    let mut stream = io::BufWriter::new(stream);
+    if let Err(err) = auth::authorize(api_keys, Some(&request.api_key), auth::Permission::Admin) {
+        write_message(&mut stream, &ReplicationMessage::Rejected(err.to_string()))?;
+        return Err(Error::AuthError(err))
+    }
+
+    let (snapshot, receiver) = store.subscribe_snapshot(buffer)?;
+    info!("Send snapshot with seq {}", snapshot.seq);
+    write_message(&mut stream, &ReplicationMessage::Snapshot(snapshot))?;
+
+    for change in receiver.wait() {
+        let change = change.map_err(|_| Error::SubscriptionClosed)?;
+        write_message(&mut stream, &ReplicationMessage::Change(change))?;
+    }
+
+    Err(Error::SubscriptionClosed)
+}
+
+/// Accept replicas presenting admin API key on primary. Each replica is served by own thread.
+pub fn serve(store: Arc<store::StoreWrapper>, listener: TcpListener, api_keys: Vec<auth::ApiKey>, buffer: usize) {
+    let api_keys = Arc::new(api_keys);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!("Replica accept error {:?}", err);
+                continue
+            }
+        };
+        let replica_addr = stream.peer_addr().ok();
+        info!("Replica connected from {:?}", replica_addr);
+
+        let store = store.clone();
+        let api_keys = api_keys.clone();
+        thread::Builder::new()
+            .name(format!("Replication to {:?}", replica_addr))
+            .spawn(move || {
+                match serve_replica(&store, stream, &api_keys, buffer) {
+                    Err(Error::AuthError(err)) => warn!("Replica {:?} rejected: {}", replica_addr, err),
+                    Err(err) => info!("Replica {:?} disconnected: {:?}", replica_addr, err),
+                    Ok(()) => (),
+                }
+            })
+            .unwrap();
+    }
+}
+
+/// Apply stream of primary until it is broken
+fn replicate(store: &store::StoreWrapper, stream: TcpStream, api_key: &str) -> Result<(), Error> {
+    let mut request = serde_json::to_vec(&ReplicationRequest { api_key: api_key.to_string() })?;
+    request.push(b'\n');
+    (&stream).write_all(&request)?;
+
+    let mut last_seq = None;
+
+    for line in io::BufReader::new(stream).lines() {
+        match serde_json::from_str(&line?)? {
+            ReplicationMessage::Snapshot(snapshot) => {
+                info!("Restore snapshot with seq {}", snapshot.seq);
+                last_seq = Some(snapshot.seq);
+                store.restore(snapshot)?;
+            },
+            ReplicationMessage::Change(change) => {
+                let expected = last_seq.map_or(change.seq, |seq| seq + 1);
+                if change.seq != expected {
+                    return Err(Error::UnexpectedSeq {
+                        expected: expected,
+                        actual: change.seq,
+                    })
+                }
+                last_seq = Some(change.seq);
+                let version = change.version;
+                store.replicate(Entity::from_change(change)?, version)?;
+            },
+            ReplicationMessage::Rejected(reason) => return Err(Error::Rejected(reason)),
+        }
+    }
+
+    Ok(())
+}
+
+/// Follow primary forever. Store is restored from fresh snapshot on each reconnect.
+pub fn follow(store: Arc<store::StoreWrapper>, primary: SocketAddr, api_key: String) {
+    loop {
+        info!("Replicate from {}", primary);
+        match TcpStream::connect(primary).map_err(Error::from).and_then(|stream| replicate(&store, stream, &api_key)) {
+            Ok(()) => warn!("Primary {} closed replication stream", primary),
+            Err(err) => warn!("Replication from {} failed: {:?}", primary, err),
+        }
+        thread::sleep(time::Duration::from_secs(RECONNECT_DELAY_SECS));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn user(id: Id, email: &str) -> User {
+        User {
+            id: id,
+            email: email.into(),
+            first_name: "Vasia".into(),
+            last_name: "Pupkin".into(),
+            gender: 'm',
+            birth_date: 0,
+        }
+    }
+
+    fn location(id: Id) -> Location {
+        Location {
+            id: id,
+            place: "Musei".into(),
+            city: "Krasnodar".into(),
+            country: "Russia".into(),
+            distance: 10,
+        }
+    }
+
+    fn visit(id: Id, mark: Mark) -> Visit {
+        Visit {
+            id: id,
+            user: 1,
+            location: 1,
+            visited_at: 0,
+            mark: mark,
+        }
+    }
+
+    fn start_primary() -> (Arc<store::StoreWrapper>, SocketAddr) {
+        let primary = Arc::new(store::StoreWrapper::new(store::Store::new(Utc::now().timestamp())));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let serving_store = primary.clone();
+        let api_keys = vec!["replica:admin".parse().unwrap(), "reader:read-only".parse().unwrap()];
+        thread::spawn(move || serve(serving_store, listener, api_keys, 100));
+        (primary, address)
+    }
+
+    fn wait_for<F: Fn() -> bool>(condition: F) {
+        for _ in 0..100 {
+            if condition() {
+                return
+            }
+            thread::sleep(time::Duration::from_millis(20));
+        }
+        panic!("Replica is not synchronized");
+    }
+
+    #[test]
+    fn replicate_snapshot_and_changes() {
+        let (primary, address) = start_primary();
+        primary.add_user(user(1, "old@mail.com"), None).unwrap();
+        primary.add_location(location(1), None).unwrap();
+        primary.add_visit(visit(1, 3), None).unwrap();
+        primary.update_visit(1, VisitData { mark: Some(4), ..Default::default() }, None, None).unwrap();
+
+        let replica = Arc::new(store::StoreWrapper::new(store::Store::new(0)).as_replica());
+        let following_store = replica.clone();
+        thread::spawn(move || follow(following_store, address, "replica".into()));
+
+        wait_for(|| replica.get_visit(1).is_ok());
+        assert_eq!(replica.get_visit(1), primary.get_visit(1));
+
+        primary.update_user(1, UserData { email: Some("new@mail.com".into()), ..Default::default() }, None, None).unwrap();
+        primary.add_visit(visit(2, 5), None).unwrap();
+
+        wait_for(|| replica.get_visit(2).is_ok());
+        assert_eq!(replica.get_user(1), primary.get_user(1));
+        assert_eq!(replica.get_user(1).unwrap().version, 2);
+        wait_for(|| replica.last_change_seq() == primary.last_change_seq());
+        assert_eq!(replica.get_location_avg(1, Default::default()), primary.get_location_avg(1, Default::default()));
+    }
+
+    #[test]
+    fn reject_replica_without_admin_key() {
+        let (_primary, address) = start_primary();
+
+        for &(api_key, reason) in &[("unknown", "Unknown API key"), ("reader", "Role read-only has no admin access")] {
+            let mut stream = TcpStream::connect(address).unwrap();
+            writeln!(stream, "{}", json!({"api_key": api_key})).unwrap();
+
+            let lines = io::BufReader::new(stream).lines().collect::<Result<Vec<String>, io::Error>>().unwrap();
+            assert_eq!(lines.len(), 1);
+            assert_eq!(serde_json::from_str::<ReplicationMessage>(&lines[0]).unwrap(), ReplicationMessage::Rejected(reason.into()));
+        }
+    }
+
+    #[test]
+    fn replica_rejects_writes() {
+        let replica = store::StoreWrapper::new(store::Store::new(0)).as_replica();
+
+        assert_eq!(replica.add_user(user(1, "a@mail.com"), None), Err(store::StoreError::ReadOnly));
+        assert_eq!(replica.replicate(Entity::User(user(1, "a@mail.com")), 3), Ok(Empty{}));
+        assert_eq!(replica.replicate(Entity::User(user(1, "b@mail.com")), 7), Ok(Empty{}));
+
+        let replicated = replica.get_user(1).unwrap();
+        assert_eq!(replicated.entity.email, "b@mail.com");
+        assert_eq!(replicated.version, 7);
+    }
+}
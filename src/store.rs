@@ -1,15 +1,59 @@
+use std::cmp::Ordering;
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+    HashSet,
+};
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{
+    Duration,
+    Instant,
+};
+use futures::sync::mpsc;
+
 use std::sync::{
+    Mutex,
     RwLock,
     PoisonError,
 };
 
+use chrono;
 use chrono::prelude::*;
 use fnv;
+use log::LogLevel;
+use serde_json;
 
 use super::models::*;
+use super::history::History;
+use super::changes::{
+    Backlog,
+    ChangeFeed,
+};
+use super::strict;
+use super::patch::{
+    Patch,
+    PatchError,
+};
 
 const AVG_ACCURACY: f64 = 5.0_f64;
 
+const INITIAL_VERSION: Version = 1;
+
+const DEFAULT_TOP_LIMIT: usize = 10;
+const MAX_TOP_LIMIT: usize = 1000;
+
+// (from_age, to_age) with inclusive from and exclusive to
+const AGE_BANDS: &'static [(i32, Option<i32>)] = &[
+    (0, Some(18)),
+    (18, Some(25)),
+    (25, Some(35)),
+    (35, Some(45)),
+    (45, Some(55)),
+    (55, Some(65)),
+    (65, None),
+];
+
 type Hash<Value> = fnv::FnvHashMap<Id, Value>;
 
 #[derive(Debug, PartialEq, Clone)]
@@ -18,6 +62,17 @@ pub enum StoreError {
     EntityNotExists,
     InvalidEntity(ValidationError),
     LockError,
+    VersionMismatch,
+    ReadOnly,
+    PatchFailed(PatchError),
+    /// Query would scan more visits than allowed
+    ScanLimitExceeded(usize),
+}
+
+impl From<PatchError> for StoreError {
+    fn from(err: PatchError) -> Self {
+        StoreError::PatchFailed(err)
+    }
 }
 
 impl<Guard> From<PoisonError<Guard>> for StoreError {
@@ -26,11 +81,137 @@ impl<Guard> From<PoisonError<Guard>> for StoreError {
     }
 }
 
+/// Expected versions from precondition. `None` accept any version.
+fn check_version(version: Version, expected: Option<&[Version]>) -> Result<(), StoreError> {
+    match expected {
+        Some(expected) if !expected.contains(&version) => Err(StoreError::VersionMismatch),
+        _ => Ok(()),
+    }
+}
+
+fn avg(sum: u64, count: u64) -> f64 {
+    if 0 == count {
+        return 0_f64;
+    }
+
+    let delimiter = 10_f64.powf(AVG_ACCURACY);
+    ((sum as f64 / count as f64) * delimiter).round() / delimiter
+}
+
+/// Visits of location and sum of their marks
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct LocationMarks {
+    visits: u64,
+    mark_sum: u64,
+}
+
+#[derive(Debug, Default)]
+struct AreaIndex {
+    locations: BTreeMap<Id, LocationMarks>,
+    visits: u64,
+    mark_sum: u64,
+    distance_sum: u64,
+}
+
+fn area_stats<'a, I>(name: &str, areas: I) -> AreaStats
+where I: Iterator<Item = &'a AreaIndex>
+{
+    let (locations, visits, mark_sum, distance_sum) = areas
+        .fold((0u64, 0u64, 0u64, 0u64), |(locations, visits, mark_sum, distance_sum), area| (
+            locations + area.locations.len() as u64,
+            visits + area.visits,
+            mark_sum + area.mark_sum,
+            distance_sum + area.distance_sum,
+        ));
+
+    AreaStats {
+        name: name.to_string(),
+        locations: locations,
+        visits: visits,
+        avg_mark: avg(mark_sum, visits),
+        avg_distance: avg(distance_sum, locations),
+    }
+}
+
+struct LocationVisitFilter {
+    from_date: Option<Timestamp>,
+    to_date: Option<Timestamp>,
+    gender: Option<char>,
+    born_before: Option<Timestamp>,
+    born_after: Option<Timestamp>,
+}
+
+impl LocationVisitFilter {
+    /// No visit is filtered out, so location marks can be used without scanning visits
+    fn is_empty(&self) -> bool {
+        self.from_date.is_none() && self.to_date.is_none() && self.gender.is_none() &&
+            self.born_before.is_none() && self.born_after.is_none()
+    }
+
+    fn matches(&self, v: &Visit, u: &User) -> bool {
+        (if let Some(from_date) = self.from_date { v.visited_at > from_date } else { true })
+        && if let Some(to_date) = self.to_date { v.visited_at < to_date } else { true }
+        && if let Some(gender) = self.gender { u.gender == gender } else { true }
+        && if let Some(born_before) = self.born_before { u.birth_date < born_before } else { true }
+        && if let Some(born_after) = self.born_after { u.birth_date > born_after } else { true }
+    }
+}
+
+/// Mutations staged to be committed to `Store` as a unit
+#[derive(Debug, Default, Clone)]
+pub struct Transaction {
+    operations: Vec<Operation>,
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, operation: Operation) {
+        self.operations.push(operation);
+    }
+
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+}
+
+impl From<Vec<Operation>> for Transaction {
+    fn from(operations: Vec<Operation>) -> Self {
+        Self {
+            operations: operations,
+        }
+    }
+}
+
+/// Failed operation position and its error. Nothing of the transaction is applied.
+#[derive(Debug, PartialEq, Clone)]
+pub struct TransactionError {
+    pub index: usize,
+    pub error: StoreError,
+}
+
+// Inverse of an applied operation, used to roll back transactions
+#[derive(Debug)]
+enum Undo {
+    RemoveUser(Id),
+    RestoreUser(User, Version),
+    RemoveLocation(Id),
+    RestoreLocation(Location, Version),
+    RemoveVisit(Id),
+    RestoreVisit(Visit, Version),
+}
+
 pub struct Store {
     now: DateTime<Utc>,
-    users: Hash<(User, Vec<(Id, Id)>)>, // (Visit.id, Location.id)
-    locations: Hash<(Location, Vec<(Id, Id)>)>, // (Visit.id, User.id)
-    visits: Hash<Visit>,
+    users: Hash<(User, Vec<(Id, Id)>, Version)>, // (Visit.id, Location.id)
+    locations: Hash<(Location, Vec<(Id, Id)>, Version)>, // (Visit.id, User.id)
+    visits: Hash<(Visit, Version)>,
+    areas: BTreeMap<String, BTreeMap<String, AreaIndex>>, // Location.country -> Location.city -> index
+    cities: BTreeMap<String, BTreeSet<String>>, // Location.city -> Location.country of areas
+    changes: Option<Vec<(EntityKind, Id)>>, // Entities changed by applied operations, when recording
+    last_ids: fnv::FnvHashMap<EntityKind, Id>, // Max added or allocated id of each entity kind
 }
 
 impl Store {
@@ -44,12 +225,22 @@ impl Store {
             users: Hash::default(),
             locations: Hash::default(),
             visits: Hash::default(),
+            areas: BTreeMap::new(),
+            cities: BTreeMap::new(),
+            changes: None,
+            last_ids: fnv::FnvHashMap::default(),
         }
     }
 
     pub fn get_user(&self, id: Id) -> Result<User, StoreError> {
         self.users.get(&id)
-            .map(|&(ref u, _)| u.clone())
+            .map(|&(ref u, _, _)| u.clone())
+            .ok_or(StoreError::EntityNotExists)
+    }
+
+    pub fn get_user_version(&self, id: Id) -> Result<Version, StoreError> {
+        self.users.get(&id)
+            .map(|&(_, _, version)| version)
             .ok_or(StoreError::EntityNotExists)
     }
 
@@ -64,7 +255,8 @@ impl Store {
             return Err(StoreError::InvalidEntity(error))
         }
 
-        self.users.insert(user.id, (user, Vec::new()));
+        self.note_id(EntityKind::Users, user.id);
+        self.users.insert(user.id, (user, Vec::new(), INITIAL_VERSION));
         Ok(Empty{})
     }
 
@@ -93,13 +285,20 @@ impl Store {
         }
 
         user_record.0 = updated_user;
+        user_record.2 += 1;
 
         Ok(Empty{})
     }
 
     pub fn get_location(&self, id: Id) -> Result<Location, StoreError> {
         self.locations.get(&id)
-            .map(|&(ref l, _)| l.clone())
+            .map(|&(ref l, _, _)| l.clone())
+            .ok_or(StoreError::EntityNotExists)
+    }
+
+    pub fn get_location_version(&self, id: Id) -> Result<Version, StoreError> {
+        self.locations.get(&id)
+            .map(|&(_, _, version)| version)
             .ok_or(StoreError::EntityNotExists)
     }
 
@@ -114,17 +313,21 @@ impl Store {
             return Err(StoreError::InvalidEntity(error))
         }
 
-        self.locations.insert(location.id, (location, Vec::new()));
+        self.index_location(&location, 0, 0);
+        self.note_id(EntityKind::Locations, location.id);
+        self.locations.insert(location.id, (location, Vec::new(), INITIAL_VERSION));
         Ok(Empty{})
     }
 
     pub fn update_location(&mut self, id: Id, location_data: LocationData) -> Result<Empty, StoreError> {
         debug!("Update location {} {:?}", id, location_data);
 
-        let location_record = self.locations.get_mut(&id)
-            .ok_or(StoreError::EntityNotExists)?;
+        let original_location = self.locations.get(&id)
+            .ok_or(StoreError::EntityNotExists)?
+            .0
+            .clone();
 
-        let mut updated_location = location_record.0.clone();
+        let mut updated_location = original_location.clone();
 
         if let Some(distance) = location_data.distance {
             updated_location.distance = distance;
@@ -143,17 +346,140 @@ impl Store {
             return Err(StoreError::InvalidEntity(error))
         }
 
+        if original_location.country != updated_location.country ||
+                original_location.city != updated_location.city ||
+                original_location.distance != updated_location.distance {
+            let (visits, mark_sum) = self.location_marks(id)?;
+            self.unindex_location(&original_location, visits, mark_sum);
+            self.index_location(&updated_location, visits, mark_sum);
+        }
+
+        let location_record = self.locations.get_mut(&id).unwrap();
         location_record.0 = updated_location;
+        location_record.2 += 1;
 
         Ok(Empty{})
     }
 
+    fn location_marks(&self, location_id: Id) -> Result<(u64, u64), StoreError> {
+        let location_visits = &self.locations.get(&location_id)
+            .ok_or(StoreError::EntityNotExists)?
+            .1;
+
+        location_visits.iter()
+            .map(|&(visit_id, _)|
+                self.visits.get(&visit_id)
+                    .map(|&(ref v, _)| v.mark as u64)
+                    .ok_or(StoreError::EntityNotExists)
+            )
+            .fold(Ok((0, 0)), |result, mark|
+                result.and_then(|(visits, mark_sum)| Ok((visits + 1, mark_sum + mark?)))
+            )
+    }
+
+    fn area_mut(&mut self, location: &Location) -> &mut AreaIndex {
+        if !self.cities.get(&location.city).map_or(false, |countries| countries.contains(&location.country)) {
+            self.cities.entry(location.city.clone()).or_insert_with(BTreeSet::new).insert(location.country.clone());
+        }
+        self.areas
+            .entry(location.country.clone())
+            .or_insert_with(BTreeMap::new)
+            .entry(location.city.clone())
+            .or_insert_with(AreaIndex::default)
+    }
+
+    fn index_location(&mut self, location: &Location, visits: u64, mark_sum: u64) {
+        let area = self.area_mut(location);
+        area.locations.insert(location.id, LocationMarks { visits: visits, mark_sum: mark_sum });
+        area.visits += visits;
+        area.mark_sum += mark_sum;
+        area.distance_sum += location.distance as u64;
+    }
+
+    fn unindex_location(&mut self, location: &Location, visits: u64, mark_sum: u64) {
+        let country_is_empty = {
+            let cities = match self.areas.get_mut(&location.country) {
+                Some(cities) => cities,
+                None => return,
+            };
+
+            let city_is_empty = match cities.get_mut(&location.city) {
+                Some(area) => {
+                    area.locations.remove(&location.id);
+                    area.visits -= visits;
+                    area.mark_sum -= mark_sum;
+                    area.distance_sum -= location.distance as u64;
+                    area.locations.is_empty()
+                },
+                None => false,
+            };
+
+            if city_is_empty {
+                cities.remove(&location.city);
+            }
+
+            cities.is_empty()
+        };
+
+        if !self.areas.get(&location.country).map_or(false, |cities| cities.contains_key(&location.city)) {
+            let city_is_unused = match self.cities.get_mut(&location.city) {
+                Some(countries) => {
+                    countries.remove(&location.country);
+                    countries.is_empty()
+                },
+                None => false,
+            };
+            if city_is_unused {
+                self.cities.remove(&location.city);
+            }
+        }
+
+        if country_is_empty {
+            self.areas.remove(&location.country);
+        }
+    }
+
+    fn index_visit(&mut self, location: &Location, mark: Mark) {
+        let area = self.area_mut(location);
+        area.visits += 1;
+        area.mark_sum += mark as u64;
+        if let Some(marks) = area.locations.get_mut(&location.id) {
+            marks.visits += 1;
+            marks.mark_sum += mark as u64;
+        }
+    }
+
+    fn unindex_visit(&mut self, location: &Location, mark: Mark) {
+        let area = self.area_mut(location);
+        area.visits -= 1;
+        area.mark_sum -= mark as u64;
+        if let Some(marks) = area.locations.get_mut(&location.id) {
+            marks.visits -= 1;
+            marks.mark_sum -= mark as u64;
+        }
+    }
+
     pub fn get_visit(&self, visit_id: Id) -> Result<Visit, StoreError> {
         self.visits.get(&visit_id)
-            .map(|v| v.clone())
+            .map(|&(ref v, _)| v.clone())
+            .ok_or(StoreError::EntityNotExists)
+    }
+
+    pub fn get_visit_version(&self, visit_id: Id) -> Result<Version, StoreError> {
+        self.visits.get(&visit_id)
+            .map(|&(_, version)| version)
             .ok_or(StoreError::EntityNotExists)
     }
 
+    /// Entity as JSON object or `None` when it not exists
+    pub fn entity_value(&self, entity: EntityKind, id: Id) -> Option<serde_json::Value> {
+        match entity {
+            EntityKind::Users => self.get_user(id).ok().and_then(|u| serde_json::to_value(u).ok()),
+            EntityKind::Locations => self.get_location(id).ok().and_then(|l| serde_json::to_value(l).ok()),
+            EntityKind::Visits => self.get_visit(id).ok().and_then(|v| serde_json::to_value(v).ok()),
+        }
+    }
+
     fn add_visit_to_user(
         &mut self,
         visit: &Visit,
@@ -167,7 +493,7 @@ impl Store {
                 .map(|&(visit_id, _)|
                     self.visits
                         .get(&visit_id)
-                        .map(|v| v.visited_at)
+                        .map(|&(ref v, _)| v.visited_at)
                 )
                 .collect::<Option<Vec<Timestamp>>>()
                 .ok_or(StoreError::EntityNotExists)?
@@ -238,7 +564,7 @@ impl Store {
                     field: "user".to_string(),
                     message: format!("User with ID {} not exists", user_id),
                 })),
-            Some(&(ref user, _)) => Ok(user.clone()),
+            Some(&(ref user, _, _)) => Ok(user.clone()),
         }
     }
 
@@ -249,7 +575,7 @@ impl Store {
                     field: "location".to_string(),
                     message: format!("Location with ID {} not exists", location_id),
                 })),
-            Some(&(ref location, _)) =>
+            Some(&(ref location, _, _)) =>
                 Ok(location.clone()),
         }
     }
@@ -270,8 +596,10 @@ impl Store {
 
         self.add_visit_to_user(&visit, &location)?;
         self.add_visit_to_location(&visit, &user)?;
+        self.index_visit(&location, visit.mark);
 
-        self.visits.insert(visit.id, visit);
+        self.note_id(EntityKind::Visits, visit.id);
+        self.visits.insert(visit.id, (visit, INITIAL_VERSION));
 
         Ok(Empty{})
     }
@@ -282,6 +610,7 @@ impl Store {
         let original_visit = self.visits
             .get(&id)
             .ok_or(StoreError::EntityNotExists)?
+            .0
             .clone()
         ;
 
@@ -310,8 +639,9 @@ impl Store {
         let location = self.get_visit_location(updated_visit.location)?.clone();
         let user = self.get_visit_user(updated_visit.user)?.clone();
 
-        debug!("Replace visit {:?} wiht {:?}", original_visit, updated_visit);
-        *self.visits.get_mut(&id).unwrap() = updated_visit.clone();
+        // Resolve everything fallible before the first mutation, so an error never leaves the visit half-updated
+        let original_location = self.get_visit_location(original_visit.location)?;
+        self.get_visit_user(original_visit.user)?;
 
         if original_visit.user != updated_visit.user ||
                 original_visit.visited_at != updated_visit.visited_at ||
@@ -325,10 +655,299 @@ impl Store {
             self.remove_visit_from_location(&original_visit)?;
             self.add_visit_to_location(&updated_visit, &user)?;
         }
+        if original_visit.location != updated_visit.location || original_visit.mark != updated_visit.mark {
+            self.unindex_visit(&original_location, original_visit.mark);
+            self.index_visit(&location, updated_visit.mark);
+        }
+
+        debug!("Replace visit {:?} wiht {:?}", original_visit, updated_visit);
+        let visit_record = self.visits.get_mut(&id).unwrap();
+        visit_record.0 = updated_visit;
+        visit_record.1 += 1;
 
         Ok(Empty{})
     }
 
+    /// Full state with versions. Snapshot `seq` is left zero.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            seq: 0,
+            now: self.now.timestamp(),
+            users: self.users.values()
+                .map(|&(ref user, _, version)| Versioned { entity: user.clone(), version: version })
+                .collect(),
+            locations: self.locations.values()
+                .map(|&(ref location, _, version)| Versioned { entity: location.clone(), version: version })
+                .collect(),
+            visits: self.visits.values()
+                .map(|&(ref visit, version)| Versioned { entity: visit.clone(), version: version })
+                .collect(),
+        }
+    }
+
+    pub fn from_snapshot(snapshot: Snapshot) -> Result<Self, StoreError> {
+        let mut store = Self::new(snapshot.now);
+
+        for Versioned { entity: location, version } in snapshot.locations {
+            let id = location.id;
+            store.add_location(location)?;
+            store.set_version(EntityKind::Locations, id, version)?;
+        }
+        for Versioned { entity: user, version } in snapshot.users {
+            let id = user.id;
+            store.add_user(user)?;
+            store.set_version(EntityKind::Users, id, version)?;
+        }
+        for Versioned { entity: visit, version } in snapshot.visits {
+            let id = visit.id;
+            store.add_visit(visit)?;
+            store.set_version(EntityKind::Visits, id, version)?;
+        }
+
+        Ok(store)
+    }
+
+    /// Add entity or replace all fields of existing one
+    pub fn upsert(&mut self, entity: Entity) -> Result<Empty, StoreError> {
+        let operation = match entity {
+            Entity::User(user) =>
+                if self.users.contains_key(&user.id) {
+                    Operation::UpdateUser(user.id, user.into())
+                } else {
+                    Operation::AddUser(user)
+                },
+            Entity::Location(location) =>
+                if self.locations.contains_key(&location.id) {
+                    Operation::UpdateLocation(location.id, location.into())
+                } else {
+                    Operation::AddLocation(location)
+                },
+            Entity::Visit(visit) =>
+                if self.visits.contains_key(&visit.id) {
+                    Operation::UpdateVisit(visit.id, visit.into())
+                } else {
+                    Operation::AddVisit(visit)
+                },
+        };
+        self.apply(operation)
+    }
+
+    fn note_id(&mut self, entity: EntityKind, id: Id) {
+        let last_id = self.last_ids.entry(entity).or_insert(0);
+        if id > *last_id {
+            *last_id = id;
+        }
+    }
+
+    /// Id after max one of entity kind. It is used up only when entity is added with it.
+    fn next_id(&self, entity: EntityKind) -> Result<Id, StoreError> {
+        match self.last_ids.get(&entity).cloned().unwrap_or(0) {
+            last_id if last_id == Id::max_value() => Err(StoreError::EntryExists),
+            last_id => Ok(last_id + 1),
+        }
+    }
+
+    /// Replace version of existing entity with one assigned by primary
+    pub fn set_version(&mut self, entity: EntityKind, id: Id, version: Version) -> Result<(), StoreError> {
+        let record_version = match entity {
+            EntityKind::Users => self.users.get_mut(&id).map(|record| &mut record.2),
+            EntityKind::Locations => self.locations.get_mut(&id).map(|record| &mut record.2),
+            EntityKind::Visits => self.visits.get_mut(&id).map(|record| &mut record.1),
+        };
+        *record_version.ok_or(StoreError::EntityNotExists)? = version;
+        Ok(())
+    }
+
+    pub fn get_version(&self, entity: EntityKind, id: Id) -> Result<Version, StoreError> {
+        match entity {
+            EntityKind::Users => self.get_user_version(id),
+            EntityKind::Locations => self.get_location_version(id),
+            EntityKind::Visits => self.get_visit_version(id),
+        }
+    }
+
+    /// Apply patch to entity document and replace entity with validated result
+    /// In strict mode patched entity must not have unknown fields or values of wrong type
+    pub fn patch(&mut self, entity: EntityKind, id: Id, patch: &Patch, strict: bool) -> Result<Empty, StoreError> {
+        debug!("Patch {:?} {} {:?}", entity, id, patch);
+        let document = self.entity_value(entity, id).ok_or(StoreError::EntityNotExists)?;
+
+        let patched = patch.apply(document)?;
+        if strict {
+            strict::check_fields(entity, true, &patched).map_err(StoreError::InvalidEntity)?;
+        }
+        let patched = Entity::from_value(entity, patched)
+            .map_err(|err| PatchError::InvalidResult(err.to_string()))?;
+        if patched.key() != (entity, id) {
+            return Err(StoreError::PatchFailed(PatchError::InvalidResult("id can not be changed".into())))
+        }
+
+        self.upsert(patched)
+    }
+
+    /// Start recording entities changed by `apply`
+    pub fn record_changes(&mut self) {
+        self.changes = Some(Vec::new());
+    }
+
+    pub fn take_changes(&mut self) -> Vec<(EntityKind, Id)> {
+        match self.changes {
+            Some(ref mut changes) => ::std::mem::replace(changes, Vec::new()),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn apply(&mut self, operation: Operation) -> Result<Empty, StoreError> {
+        let key = operation.key();
+
+        let result = match operation {
+            Operation::AddUser(user) => self.add_user(user),
+            Operation::UpdateUser(id, user_data) => self.update_user(id, user_data),
+            Operation::AddLocation(location) => self.add_location(location),
+            Operation::UpdateLocation(id, location_data) => self.update_location(id, location_data),
+            Operation::AddVisit(visit) => self.add_visit(visit),
+            Operation::UpdateVisit(id, visit_data) => self.update_visit(id, visit_data),
+        };
+
+        if let (true, Some(ref mut changes)) = (result.is_ok(), self.changes.as_mut()) {
+            changes.push(key);
+        }
+
+        result
+    }
+
+    /// Add of entity with `NEW_ID` gets next id of entity kind
+    fn with_allocated_id(&self, operation: Operation) -> Result<Operation, StoreError> {
+        let entity = match operation {
+            Operation::AddUser(User { id: NEW_ID, .. }) => EntityKind::Users,
+            Operation::AddLocation(Location { id: NEW_ID, .. }) => EntityKind::Locations,
+            Operation::AddVisit(Visit { id: NEW_ID, .. }) => EntityKind::Visits,
+            _ => return Ok(operation),
+        };
+        Ok(operation.with_id(self.next_id(entity)?))
+    }
+
+    /// Apply operation allocating id of entity added with `NEW_ID`. Returns id of changed entity.
+    pub fn apply_new(&mut self, operation: Operation) -> Result<Id, StoreError> {
+        let operation = self.with_allocated_id(operation)?;
+        let (_, id) = operation.key();
+        self.apply(operation).map(|_| id)
+    }
+
+    /// Apply operations in order independently of each other
+    pub fn apply_batch(&mut self, operations: Vec<Operation>) -> Vec<Result<Id, StoreError>> {
+        debug!("Apply batch of {} operations", operations.len());
+
+        operations.into_iter()
+            .map(|operation| self.apply_new(operation))
+            .collect()
+    }
+
+    /// Apply all staged operations or none of them. Returns ids of changed entities.
+    pub fn commit(&mut self, transaction: Transaction) -> Result<Vec<Id>, TransactionError> {
+        debug!("Commit transaction {:?}", transaction);
+
+        let mut undo_log = Vec::with_capacity(transaction.operations.len());
+        let mut ids = Vec::with_capacity(transaction.operations.len());
+        let changes_len = self.changes.as_ref().map_or(0, Vec::len);
+        let last_ids = self.last_ids.clone();
+
+        for (index, operation) in transaction.operations.into_iter().enumerate() {
+            let result = self.with_allocated_id(operation).and_then(|operation| {
+                let undo = self.undo_for(&operation)?;
+                let (_, id) = operation.key();
+                self.apply(operation).map(|_| (undo, id))
+            });
+
+            match result {
+                Ok((undo, id)) => {
+                    undo_log.push(undo);
+                    ids.push(id);
+                },
+                Err(error) => {
+                    debug!("Transaction operation {} failed: {:?}", index, error);
+                    self.rollback(undo_log);
+                    if let Some(ref mut changes) = self.changes {
+                        changes.truncate(changes_len);
+                    }
+                    self.last_ids = last_ids;
+                    return Err(TransactionError {
+                        index: index,
+                        error: error,
+                    })
+                },
+            }
+        }
+
+        Ok(ids)
+    }
+
+    fn undo_for(&self, operation: &Operation) -> Result<Undo, StoreError> {
+        Ok(match *operation {
+            Operation::AddUser(ref user) => Undo::RemoveUser(user.id),
+            Operation::UpdateUser(id, _) => Undo::RestoreUser(self.get_user(id)?, self.get_user_version(id)?),
+            Operation::AddLocation(ref location) => Undo::RemoveLocation(location.id),
+            Operation::UpdateLocation(id, _) =>
+                Undo::RestoreLocation(self.get_location(id)?, self.get_location_version(id)?),
+            Operation::AddVisit(ref visit) => Undo::RemoveVisit(visit.id),
+            Operation::UpdateVisit(id, _) => Undo::RestoreVisit(self.get_visit(id)?, self.get_visit_version(id)?),
+        })
+    }
+
+    fn rollback(&mut self, undo_log: Vec<Undo>) {
+        for undo in undo_log.into_iter().rev() {
+            debug!("Rollback {:?}", undo);
+            if let Err(error) = self.revert(undo) {
+                error!("Rollback error {:?}", error);
+            }
+        }
+    }
+
+    fn revert(&mut self, undo: Undo) -> Result<(), StoreError> {
+        match undo {
+            Undo::RemoveUser(id) => {
+                self.users.remove(&id).ok_or(StoreError::EntityNotExists)?;
+            },
+            Undo::RestoreUser(user, version) => {
+                let user_record = self.users.get_mut(&user.id).ok_or(StoreError::EntityNotExists)?;
+                user_record.0 = user;
+                user_record.2 = version;
+            },
+            Undo::RemoveLocation(id) => {
+                let (location, _, _) = self.locations.remove(&id).ok_or(StoreError::EntityNotExists)?;
+                self.unindex_location(&location, 0, 0);
+            },
+            Undo::RestoreLocation(location, version) => {
+                let id = location.id;
+                self.update_location(id, LocationData {
+                    place: Some(location.place),
+                    country: Some(location.country),
+                    city: Some(location.city),
+                    distance: Some(location.distance),
+                })?;
+                self.locations.get_mut(&id).ok_or(StoreError::EntityNotExists)?.2 = version;
+            },
+            Undo::RemoveVisit(id) => {
+                let visit = self.get_visit(id)?;
+                let location = self.get_visit_location(visit.location)?;
+                self.remove_visit_from_user(&visit)?;
+                self.remove_visit_from_location(&visit)?;
+                self.unindex_visit(&location, visit.mark);
+                self.visits.remove(&id);
+            },
+            Undo::RestoreVisit(visit, version) => {
+                self.update_visit(visit.id, VisitData {
+                    location: Some(visit.location),
+                    user: Some(visit.user),
+                    visited_at: Some(visit.visited_at),
+                    mark: Some(visit.mark),
+                })?;
+                self.visits.get_mut(&visit.id).ok_or(StoreError::EntityNotExists)?.1 = version;
+            },
+        }
+        Ok(())
+    }
+
     pub fn get_user_visits(&self, user_id: Id, options: GetUserVisitsOptions) ->
             Result<UserVisits, StoreError> {
         debug!("Get user {} visits by {:?}", user_id, options);
@@ -336,11 +955,13 @@ impl Store {
         let user_record = self.users.get(&user_id)
             .ok_or(StoreError::EntityNotExists)?;
 
+        let expand = options.expand.unwrap_or(false);
+
         let user_visits = user_record.1
             .iter()
             .map(|&(visit_id, location_id)|
-                self.visits.get(&visit_id).and_then(|visit|
-                    self.locations.get(&location_id).map(|&(ref location, _)|
+                self.visits.get(&visit_id).and_then(|&(ref visit, _)|
+                    self.locations.get(&location_id).map(|&(ref location, _, _)|
                         (visit.clone(), location.clone())
                     )
                 )
@@ -352,13 +973,31 @@ impl Store {
                 (if let Some(from_date) = options.from_date { from_date < v.visited_at  } else { true })
                 && if let Some(to_date) = options.to_date { v.visited_at < to_date } else { true }
                 && if let Some(ref country) = options.country { &l.country == country } else { true }
+                && if let Some(ref city) = options.city { &l.city == city } else { true }
+                && if let Some(from_distance) = options.from_distance { from_distance < l.distance } else { true }
                 && if let Some(to_distance) = options.to_distance { l.distance < to_distance  } else { true }
+                && if let Some(min_mark) = options.min_mark { min_mark <= v.mark } else { true }
+                && if let Some(max_mark) = options.max_mark { v.mark <= max_mark } else { true }
+                && if let Some(location_id) = options.location { l.id == location_id } else { true }
             })
             .map(|(ref v, ref l)| {
-                UserVisit {
-                    mark: v.mark,
-                    place: l.place.clone(),
-                    visited_at: v.visited_at,
+                if expand {
+                    UserVisit {
+                        mark: v.mark,
+                        place: l.place.clone(),
+                        visited_at: v.visited_at,
+                        id: Some(v.id),
+                        location: Some(l.id),
+                        country: Some(l.country.clone()),
+                        city: Some(l.city.clone()),
+                    }
+                } else {
+                    UserVisit {
+                        mark: v.mark,
+                        place: l.place.clone(),
+                        visited_at: v.visited_at,
+                        ..Default::default()
+                    }
                 }
             })
             .collect::<Vec<UserVisit>>();
@@ -368,16 +1007,52 @@ impl Store {
         })
     }
 
-    pub fn get_location_avg(&self, location_id: Id, options: GetLocationAvgOptions) ->
-            Result<LocationRate, StoreError> {
-        debug!("Find location {} avg by {:?}", location_id, options);
+    pub fn get_user_summary(&self, user_id: Id) -> Result<UserSummary, StoreError> {
+        debug!("Get user {} summary", user_id);
 
-        let location_visits = &self.locations.get(&location_id)
-            .ok_or(StoreError::EntityNotExists)?
-            .1;
+        let &(ref user, ref user_visits, _) = self.users.get(&user_id)
+            .ok_or(StoreError::EntityNotExists)?;
 
-        debug!("Location visits: {:?}", location_visits);
+        let mut locations = HashSet::new();
+        let mut countries = HashSet::new();
+        let mut sum_mark = 0u64;
+
+        for &(visit_id, location_id) in user_visits.iter() {
+            let visit = &self.visits.get(&visit_id).ok_or(StoreError::EntityNotExists)?.0;
+            let location = &self.locations.get(&location_id).ok_or(StoreError::EntityNotExists)?.0;
+
+            locations.insert(location.id);
+            countries.insert(&location.country);
+            sum_mark += visit.mark as u64;
+        }
+
+        // User visits are kept ordered by visited_at
+        let visited_at = |&(visit_id, _): &(Id, Id)| self.visits.get(&visit_id).map(|&(ref v, _)| v.visited_at);
+
+        Ok(UserSummary {
+            visits: user_visits.len() as u64,
+            locations: locations.len() as u64,
+            countries: countries.len() as u64,
+            first_visit: user_visits.first().and_then(&visited_at),
+            last_visit: user_visits.last().and_then(&visited_at),
+            avg_mark: avg(sum_mark, user_visits.len() as u64),
+            age: self.age(user.birth_date),
+        })
+    }
+
+    /// Birth dates out of calendar range are clamped to its first or last day
+    fn age(&self, birth_date: Timestamp) -> i32 {
+        let birth_date = NaiveDateTime::from_timestamp_opt(birth_date, 0)
+            .map(|t| t.date())
+            .unwrap_or(if birth_date < 0 { chrono::naive::MIN_DATE } else { chrono::naive::MAX_DATE });
+        let mut age = self.now.year() - birth_date.year();
+        if (self.now.month(), self.now.day()) < (birth_date.month(), birth_date.day()) {
+            age -= 1;
+        }
+        age
+    }
 
+    fn location_visit_filter(&self, options: &GetLocationAvgOptions) -> LocationVisitFilter {
         debug!("Now {}", self.now);
 
         let from_age = options.from_age
@@ -390,100 +1065,608 @@ impl Store {
             .map(|t| t.timestamp());
         debug!("Age to {:?}", to_age);
 
-       let filtered_location_visits: Vec<(Visit, User)>  = location_visits
+        LocationVisitFilter {
+            from_date: options.from_date,
+            to_date: options.to_date,
+            gender: options.gender,
+            born_before: from_age,
+            born_after: to_age,
+        }
+    }
+
+    /// Visits scanned by user visits and user summary queries
+    fn user_visit_count(&self, user_id: Id) -> usize {
+        self.users.get(&user_id).map_or(0, |&(_, ref visits, _)| visits.len())
+    }
+
+    /// Visits scanned by location queries
+    fn location_visit_count(&self, location_id: Id) -> usize {
+        self.locations.get(&location_id).map_or(0, |&(_, ref visits, _)| visits.len())
+    }
+
+    fn filter_location_visits(&self, location_id: Id, options: &GetLocationAvgOptions) ->
+            Result<Vec<(&Visit, &User)>, StoreError> {
+        let location_visits = &self.locations.get(&location_id)
+            .ok_or(StoreError::EntityNotExists)?
+            .1;
+
+        debug!("Location visits: {:?}", location_visits);
+
+        let filter = self.location_visit_filter(options);
+
+        let filtered_location_visits = location_visits
             .iter()
             .map(|&(visit_id, user_id)|
-                self.visits.get(&visit_id).and_then(|visit|
-                    self.users.get(&user_id).map(|&(ref user, _)|
-                        (visit.clone(), user.clone())
+                self.visits.get(&visit_id).and_then(|&(ref visit, _)|
+                    self.users.get(&user_id).map(|&(ref user, _, _)|
+                        (visit, user)
                     )
                 )
             )
-            .collect::<Option<Vec<(Visit, User)>>>()
+            .collect::<Option<Vec<(&Visit, &User)>>>()
             .ok_or(StoreError::EntityNotExists)?
             .into_iter()
-            .filter(|&(ref v, ref u)| {
-                (if let Some(from_date) = options.from_date { v.visited_at > from_date } else { true })
-                && if let Some(to_date) = options.to_date { v.visited_at < to_date } else { true }
-                && if let Some(gender) = options.gender { u.gender == gender } else { true }
-                && if let Some(from_age) = from_age { u.birth_date < from_age } else { true }
-                && if let Some(to_age) = to_age { u.birth_date > to_age } else { true }
-            })
-            .collect::<Vec<(Visit, User)>>();
+            .filter(|&(v, u)| filter.matches(v, u))
+            .collect::<Vec<(&Visit, &User)>>();
 
         debug!("Filtered location vistis: {:?}", filtered_location_visits);
 
-        let (sum_mark, count_mark) = filtered_location_visits.iter()
-            .fold((0u64, 0u64), |(sum, count), &(ref v, ref _v)| (sum + v.mark as u64, count + 1));
+        Ok(filtered_location_visits)
+    }
 
-        debug!("Sum/count: {}/{}", sum_mark, count_mark);
+    pub fn get_location_avg(&self, location_id: Id, options: GetLocationAvgOptions) ->
+            Result<LocationRate, StoreError> {
+        debug!("Find location {} avg by {:?}", location_id, options);
 
-        if 0 == count_mark {
-            return Ok(LocationRate::default());
-        }
+        let filtered_location_visits = self.filter_location_visits(location_id, &options)?;
+
+        let (sum_mark, count_mark) = filtered_location_visits.iter()
+            .fold((0u64, 0u64), |(sum, count), &(v, _u)| (sum + v.mark as u64, count + 1));
 
-        let delimiter = 10_f64.powf(AVG_ACCURACY);
-        let avg_mark = ((sum_mark as f64 / count_mark as f64) * delimiter).round() / delimiter;
+        debug!("Sum/count: {}/{}", sum_mark, count_mark);
 
         Ok(LocationRate {
-            avg: avg_mark,
+            avg: avg(sum_mark, count_mark),
         })
     }
-}
-
-pub struct StoreWrapper {
-    store: RwLock<Store>,
-}
 
-impl StoreWrapper {
-    pub fn new(store: Store) -> Self {
-        Self {
-            store: RwLock::new(store),
+    /// Indexed areas of locations ranked by top locations query
+    fn top_location_areas(&self, options: &GetTopLocationsOptions) -> Vec<&AreaIndex> {
+        match (options.country.as_ref(), options.city.as_ref()) {
+            (Some(country), Some(city)) =>
+                self.areas.get(country).and_then(|cities| cities.get(city)).into_iter().collect(),
+            (Some(country), None) =>
+                self.areas.get(country).into_iter().flat_map(|cities| cities.values()).collect(),
+            (None, Some(city)) =>
+                self.cities.get(city).into_iter()
+                    .flat_map(|countries| countries.iter())
+                    .filter_map(|country| self.areas.get(country).and_then(|cities| cities.get(city)))
+                    .collect(),
+            (None, None) =>
+                self.areas.values().flat_map(|cities| cities.values()).collect(),
         }
     }
 
-    pub fn get_user(&self, user_id: Id) -> Result<User, StoreError> {
-        self.store.read()?.get_user(user_id)
-    }
-
-    pub fn add_user(&self, user: User) -> Result<Empty, StoreError> {
-        self.store.write()?.add_user(user)
+    /// Visits to scan by top locations query, counted from area index. Without visit filter
+    /// indexed marks of locations are used.
+    fn top_locations_visit_count(&self, options: &GetTopLocationsOptions) -> usize {
+        if self.location_visit_filter(&options.avg_options()).is_empty() {
+            return 0
+        }
+        self.top_location_areas(options).iter().map(|area| area.visits as usize).sum()
     }
 
-    pub fn update_user(&self, user_id: Id, user_data: UserData) -> Result<Empty, StoreError> {
-        self.store.write()?.update_user(user_id, user_data)
-    }
+    /// Ranked locations with number of visits scanned. Query scanning more than `max_scanned_visits` visits is rejected.
+    pub fn get_top_locations(&self, options: GetTopLocationsOptions, max_scanned_visits: Option<usize>) ->
+            Result<(TopLocations, usize), StoreError> {
+        debug!("Find top locations by {:?}", options);
 
-    pub fn get_location(&self, location_id: Id) -> Result<Location, StoreError> {
-        self.store.read()?.get_location(location_id)
-    }
+        let filter = self.location_visit_filter(&options.avg_options());
+        let min_visits = options.min_visits.unwrap_or(1).max(1);
+        let limit = options.limit.unwrap_or(DEFAULT_TOP_LIMIT).min(MAX_TOP_LIMIT);
 
-    pub fn add_location(&self, location: Location) -> Result<Empty, StoreError> {
-        self.store.write()?.add_location(location)
-    }
+        if let Some(max_scanned_visits) = max_scanned_visits {
+            if self.top_locations_visit_count(&options) > max_scanned_visits {
+                return Err(StoreError::ScanLimitExceeded(max_scanned_visits))
+            }
+        }
 
-    pub fn update_location(&self, location_id: Id, location_data: LocationData) -> Result<Empty, StoreError> {
-        self.store.write()?.update_location(location_id, location_data)
-    }
+        let mut ranking = Vec::new();
+        let mut scanned_visits = 0;
+        for area in self.top_location_areas(&options) {
+            for (&location_id, marks) in &area.locations {
+                let (sum_mark, count_mark) = if filter.is_empty() {
+                    (marks.mark_sum, marks.visits)
+                } else {
+                    let location_visits = &self.locations.get(&location_id).ok_or(StoreError::EntityNotExists)?.1;
+                    scanned_visits += location_visits.len();
+                    let mut sum_mark = 0u64;
+                    let mut count_mark = 0u64;
+                    for &(visit_id, user_id) in location_visits.iter() {
+                        let visit = &self.visits.get(&visit_id).ok_or(StoreError::EntityNotExists)?.0;
+                        let user = &self.users.get(&user_id).ok_or(StoreError::EntityNotExists)?.0;
+                        if filter.matches(visit, user) {
+                            sum_mark += visit.mark as u64;
+                            count_mark += 1;
+                        }
+                    }
+                    (sum_mark, count_mark)
+                };
 
-    pub fn get_visit(&self, visit_id: Id) -> Result<Visit, StoreError> {
-        self.store.read()?.get_visit(visit_id)
-    }
+                if count_mark >= min_visits {
+                    ranking.push((avg(sum_mark, count_mark), count_mark, location_id));
+                }
+            }
+        }
 
-    pub fn add_visit(&self, visit: Visit) -> Result<Empty, StoreError> {
-        self.store.write()?.add_visit(visit)
-    }
+        ranking.sort_by(|left, right|
+            right.0.partial_cmp(&left.0).unwrap_or(Ordering::Equal)
+                .then(right.1.cmp(&left.1))
+                .then(left.2.cmp(&right.2))
+        );
+        ranking.truncate(limit);
+
+        let top_locations = ranking.into_iter()
+            .map(|(avg, visits, location_id)| {
+                let location = &self.locations.get(&location_id).ok_or(StoreError::EntityNotExists)?.0;
+                Ok(TopLocation {
+                    id: location.id,
+                    place: location.place.clone(),
+                    country: location.country.clone(),
+                    city: location.city.clone(),
+                    avg: avg,
+                    visits: visits,
+                })
+            })
+            .collect::<Result<Vec<TopLocation>, StoreError>>()?;
 
-    pub fn update_visit(&self, visit_id: Id, visit_data: VisitData) -> Result<Empty, StoreError> {
-        self.store.write()?.update_visit(visit_id, visit_data)
+        Ok((TopLocations { locations: top_locations }, scanned_visits))
     }
 
-    pub fn get_user_visits(&self, user_id: Id, options: GetUserVisitsOptions) -> Result<UserVisits, StoreError> {
-        self.store.read()?.get_user_visits(user_id, options)
-    }
+    pub fn get_location_visits(&self, location_id: Id, options: GetLocationAvgOptions) ->
+            Result<LocationVisits, StoreError> {
+        debug!("Find location {} visits by {:?}", location_id, options);
+
+        let mut location_visits = self.filter_location_visits(location_id, &options)?
+            .into_iter()
+            .map(|(v, u)|
+                LocationVisit {
+                    id: v.id,
+                    mark: v.mark,
+                    visited_at: v.visited_at,
+                    user: u.clone(),
+                }
+            )
+            .collect::<Vec<LocationVisit>>();
+
+        location_visits.sort_by_key(|v| (v.visited_at, v.id));
+
+        Ok(LocationVisits {
+            visits: location_visits,
+        })
+    }
+
+    pub fn get_location_demographics(&self, location_id: Id, options: GetLocationAvgOptions) ->
+            Result<LocationDemographics, StoreError> {
+        debug!("Find location {} demographics by {:?}", location_id, options);
+
+        let filtered_location_visits = self.filter_location_visits(location_id, &options)?;
+
+        let mut demographics = LocationDemographics {
+            visits: filtered_location_visits.len() as u64,
+            ages: AGE_BANDS.iter()
+                .map(|&(from_age, to_age)| AgeBand { from_age: from_age, to_age: to_age, visitors: 0 })
+                .collect(),
+            ..Default::default()
+        };
+
+        let visitors = filtered_location_visits.into_iter()
+            .map(|(_v, u)| (u.id, u))
+            .collect::<Hash<&User>>();
+
+        for user in visitors.values() {
+            demographics.visitors += 1;
+
+            match user.gender {
+                'f' => demographics.genders.f += 1,
+                'm' => demographics.genders.m += 1,
+                _ => (),
+            }
+
+            let age = self.age(user.birth_date);
+            if let Some(band) = demographics.ages.iter_mut()
+                    .find(|b| b.from_age <= age && b.to_age.map_or(true, |to_age| age < to_age)) {
+                band.visitors += 1;
+            }
+        }
+
+        Ok(demographics)
+    }
+
+    pub fn get_countries(&self) -> Result<Countries, StoreError> {
+        debug!("Get countries stats");
+
+        Ok(Countries {
+            countries: self.areas.iter()
+                .map(|(country, cities)| area_stats(country, cities.values()))
+                .collect(),
+        })
+    }
+
+    pub fn get_country_cities(&self, country: &str) -> Result<Cities, StoreError> {
+        debug!("Get country {} cities stats", country);
+
+        let cities = self.areas.get(country)
+            .ok_or(StoreError::EntityNotExists)?;
+
+        Ok(Cities {
+            cities: cities.iter()
+                .map(|(city, area)| area_stats(city, Some(area).into_iter()))
+                .collect(),
+        })
+    }
+}
+
+/// Time of query spent waiting for read lock and computing under it
+#[derive(Debug)]
+struct QueryTiming {
+    lock_wait: Duration,
+    compute: Duration,
+    scanned_visits: usize,
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + f64::from(duration.subsec_nanos()) / 1_000_000.0
+}
+
+pub struct StoreWrapper {
+    store: RwLock<Store>,
+    history: Option<Mutex<History>>,
+    changes: Mutex<ChangeFeed>,
+    replica: bool,
+    slow_query_threshold: Option<Duration>,
+    max_scanned_visits: Option<usize>,
+}
+
+impl StoreWrapper {
+    pub fn new(mut store: Store) -> Self {
+        store.record_changes();
+        Self {
+            store: RwLock::new(store),
+            history: None,
+            changes: Mutex::new(ChangeFeed::new(0)),
+            replica: false,
+            slow_query_threshold: None,
+            max_scanned_visits: None,
+        }
+    }
+
+    /// Reject direct mutations. Store is changed by replication only.
+    pub fn as_replica(mut self) -> Self {
+        self.replica = true;
+        self
+    }
+
+    pub fn with_changes_buffer(mut self, capacity: usize) -> Self {
+        self.changes = Mutex::new(ChangeFeed::new(capacity));
+        self
+    }
+
+    pub fn with_history(mut self, retention: usize) -> Self {
+        self.history = Some(Mutex::new(History::new(retention)));
+        self
+    }
+
+    /// Log queries taking longer as warnings of `slow_query` target, others are logged there as debug
+    pub fn with_slow_query_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_query_threshold = Some(threshold);
+        self
+    }
+
+    /// Reject top locations queries which would scan more visits
+    pub fn with_max_scanned_visits(mut self, max_scanned_visits: usize) -> Self {
+        self.max_scanned_visits = Some(max_scanned_visits);
+        self
+    }
+
+    /// Run query returning its result and number of visits scanned under read lock
+    fn timed_read<T, F>(&self, query: F) -> Result<(T, QueryTiming), StoreError>
+    where F: FnOnce(&Store) -> (T, usize)
+    {
+        let started = Instant::now();
+        let store = self.store.read()?;
+        let locked = Instant::now();
+        let (result, scanned_visits) = query(&store);
+
+        Ok((result, QueryTiming {
+            lock_wait: locked.duration_since(started),
+            compute: locked.elapsed(),
+            scanned_visits: scanned_visits,
+        }))
+    }
+
+    fn log_query(&self, query: fmt::Arguments, timing: &QueryTiming) {
+        let slow = self.slow_query_threshold.map_or(false, |threshold| timing.lock_wait + timing.compute >= threshold);
+        log!(
+            target: "slow_query",
+            if slow { LogLevel::Warn } else { LogLevel::Debug },
+            "{}: lock wait {:.3} ms, compute {:.3} ms, {} visits scanned",
+            query, millis(timing.lock_wait), millis(timing.compute), timing.scanned_visits
+        );
+    }
+
+    fn mutate<T, F>(&self, keys: &[(EntityKind, Id)], client: Option<SocketAddr>, mutation: F) -> Result<T, StoreError>
+    where F: FnOnce(&mut Store) -> Result<T, StoreError>
+    {
+        if self.replica {
+            return Err(StoreError::ReadOnly)
+        }
+        self.apply_mutation(keys, client, mutation)
+    }
+
+    /// Run mutation under write lock, publish applied changes to feed and record changes of given entities into history
+    fn apply_mutation<T, F>(&self, keys: &[(EntityKind, Id)], client: Option<SocketAddr>, mutation: F) -> Result<T, StoreError>
+    where F: FnOnce(&mut Store) -> Result<T, StoreError>
+    {
+        let mut store = self.store.write()?;
+
+        let mut unique_keys = Vec::with_capacity(keys.len());
+        if self.history.is_some() {
+            for key in keys {
+                if !unique_keys.contains(key) {
+                    unique_keys.push(*key);
+                }
+            }
+        }
+
+        let before = unique_keys.iter()
+            .map(|&(entity, id)| store.entity_value(entity, id))
+            .collect::<Vec<Option<serde_json::Value>>>();
+
+        let result = mutation(&mut store);
+
+        let changes = store.take_changes();
+        if !changes.is_empty() {
+            let mut feed = self.changes.lock()?;
+            for &(entity, id) in &changes {
+                if let (Some(data), Ok(version)) = (store.entity_value(entity, id), store.get_version(entity, id)) {
+                    feed.publish(entity, id, version, data);
+                }
+            }
+        }
+
+        let result = result?;
+
+        if let Some(ref history) = self.history {
+            // Entities with allocated ids are not known before mutation
+            let created = changes.into_iter()
+                .filter(|key| !unique_keys.contains(key))
+                .map(|key| (key, None));
+
+            let mut history = history.lock()?;
+            for ((entity, id), before) in unique_keys.iter().cloned().zip(before).chain(created) {
+                history.record(entity, id, before, store.entity_value(entity, id), client);
+            }
+        }
+
+        Ok(result)
+    }
+
+
+    pub fn patch(&self, entity: EntityKind, id: Id, patch: Patch, strict: bool, if_match: Option<&[Version]>, client: Option<SocketAddr>) ->
+            Result<Version, StoreError> {
+        self.mutate(&[(entity, id)], client, |store| {
+            check_version(store.get_version(entity, id)?, if_match)?;
+            store.patch(entity, id, &patch, strict)?;
+            store.get_version(entity, id)
+        })
+    }
+
+    /// Consistent snapshot and receiver of changes after it, closed when `limit` changes are not received
+    pub fn subscribe_snapshot(&self, limit: usize) -> Result<(Snapshot, mpsc::Receiver<Change>), StoreError> {
+        // Changes are published under write lock, so none is missed between snapshot and subscription
+        let store = self.store.read()?;
+        let mut feed = self.changes.lock()?;
+
+        let mut snapshot = store.snapshot();
+        snapshot.seq = feed.last_seq();
+        let receiver = feed.subscribe_bounded(limit);
+
+        Ok((snapshot, receiver))
+    }
+
+    /// Replace whole store with snapshot of primary. Change feed continues from sequence number of snapshot,
+    /// its current subscribers are dropped to reload state.
+    pub fn restore(&self, snapshot: Snapshot) -> Result<(), StoreError> {
+        let seq = snapshot.seq;
+        let mut store = Store::from_snapshot(snapshot)?;
+        store.record_changes();
+
+        let mut current = self.store.write()?;
+        *current = store;
+        self.changes.lock()?.reset(seq);
+        Ok(())
+    }
+
+    /// Apply replicated entity state with version assigned by primary, allowed on replica
+    pub fn replicate(&self, entity: Entity, version: Version) -> Result<Empty, StoreError> {
+        let (kind, id) = entity.key();
+        self.apply_mutation(&[(kind, id)], None, |store| {
+            store.upsert(entity)?;
+            store.set_version(kind, id, version)?;
+            Ok(Empty {})
+        })
+    }
+
+    /// Buffered changes after `since` or reset when some are lost, and receiver of next ones
+    /// closed when `limit` changes are not received
+    pub fn subscribe_changes(&self, since: Option<u64>, limit: usize) ->
+            Result<(Backlog, mpsc::Receiver<Change>), StoreError> {
+        Ok(self.changes.lock()?.subscribe(since, limit))
+    }
+
+    pub fn last_change_seq(&self) -> Result<u64, StoreError> {
+        Ok(self.changes.lock()?.last_seq())
+    }
+
+    pub fn get_history(&self, entity: EntityKind, id: Id) -> Result<EntityHistory, StoreError> {
+        match self.history {
+            Some(ref history) => Ok(history.lock()?.get(entity, id)),
+            None => Err(StoreError::EntityNotExists),
+        }
+    }
+
+    pub fn get_user(&self, user_id: Id) -> Result<Versioned<User>, StoreError> {
+        let store = self.store.read()?;
+        Ok(Versioned {
+            entity: store.get_user(user_id)?,
+            version: store.get_user_version(user_id)?,
+        })
+    }
+
+    /// New entity with `NEW_ID` gets id allocated under the write lock, so rejected entities do not use up ids.
+    /// Returns id of added entity.
+    pub fn add(&self, operation: Operation, client: Option<SocketAddr>) -> Result<Id, StoreError> {
+        self.mutate(&[operation.key()], client, |store| store.apply_new(operation))
+    }
+
+    pub fn add_user(&self, user: User, client: Option<SocketAddr>) -> Result<Id, StoreError> {
+        self.add(Operation::AddUser(user), client)
+    }
+
+    pub fn update_user(&self, user_id: Id, user_data: UserData, if_match: Option<&[Version]>, client: Option<SocketAddr>) ->
+            Result<Version, StoreError> {
+        self.mutate(&[(EntityKind::Users, user_id)], client, |store| {
+            check_version(store.get_user_version(user_id)?, if_match)?;
+            store.apply(Operation::UpdateUser(user_id, user_data))?;
+            store.get_user_version(user_id)
+        })
+    }
+
+    pub fn get_location(&self, location_id: Id) -> Result<Versioned<Location>, StoreError> {
+        let store = self.store.read()?;
+        Ok(Versioned {
+            entity: store.get_location(location_id)?,
+            version: store.get_location_version(location_id)?,
+        })
+    }
+
+    pub fn add_location(&self, location: Location, client: Option<SocketAddr>) -> Result<Id, StoreError> {
+        self.add(Operation::AddLocation(location), client)
+    }
+
+    pub fn update_location(
+        &self,
+        location_id: Id,
+        location_data: LocationData,
+        if_match: Option<&[Version]>,
+        client: Option<SocketAddr>,
+    ) -> Result<Version, StoreError> {
+        self.mutate(&[(EntityKind::Locations, location_id)], client, |store| {
+            check_version(store.get_location_version(location_id)?, if_match)?;
+            store.apply(Operation::UpdateLocation(location_id, location_data))?;
+            store.get_location_version(location_id)
+        })
+    }
+
+    pub fn get_visit(&self, visit_id: Id) -> Result<Versioned<Visit>, StoreError> {
+        let store = self.store.read()?;
+        Ok(Versioned {
+            entity: store.get_visit(visit_id)?,
+            version: store.get_visit_version(visit_id)?,
+        })
+    }
+
+    pub fn add_visit(&self, visit: Visit, client: Option<SocketAddr>) -> Result<Id, StoreError> {
+        self.add(Operation::AddVisit(visit), client)
+    }
+
+    pub fn update_visit(&self, visit_id: Id, visit_data: VisitData, if_match: Option<&[Version]>, client: Option<SocketAddr>) ->
+            Result<Version, StoreError> {
+        self.mutate(&[(EntityKind::Visits, visit_id)], client, |store| {
+            check_version(store.get_visit_version(visit_id)?, if_match)?;
+            store.apply(Operation::UpdateVisit(visit_id, visit_data))?;
+            store.get_visit_version(visit_id)
+        })
+    }
+
+    pub fn apply_batch(&self, operations: Vec<Operation>, client: Option<SocketAddr>) ->
+            Result<Vec<Result<Id, StoreError>>, StoreError> {
+        let keys = operations.iter().map(Operation::key).collect::<Vec<(EntityKind, Id)>>();
+        self.mutate(&keys, client, |store| Ok(store.apply_batch(operations)))
+    }
+
+    pub fn commit(&self, transaction: Transaction, client: Option<SocketAddr>) ->
+            Result<Result<Vec<Id>, TransactionError>, StoreError> {
+        let keys = transaction.operations().iter().map(Operation::key).collect::<Vec<(EntityKind, Id)>>();
+        self.mutate(&keys, client, |store| Ok(store.commit(transaction)))
+    }
+
+    pub fn get_user_visits(&self, user_id: Id, options: GetUserVisitsOptions) -> Result<UserVisits, StoreError> {
+        let logged_options = options.clone();
+        let (result, timing) = self.timed_read(|store|
+            (store.get_user_visits(user_id, options), store.user_visit_count(user_id))
+        )?;
+        self.log_query(format_args!("User visits {} by {:?}", user_id, logged_options), &timing);
+        result
+    }
+
+    pub fn get_user_summary(&self, user_id: Id) -> Result<UserSummary, StoreError> {
+        let (result, timing) = self.timed_read(|store|
+            (store.get_user_summary(user_id), store.user_visit_count(user_id))
+        )?;
+        self.log_query(format_args!("User summary {}", user_id), &timing);
+        result
+    }
 
     pub fn get_location_avg(&self, location_id: Id, options: GetLocationAvgOptions) -> Result<LocationRate, StoreError> {
-        self.store.read()?.get_location_avg(location_id, options)
+        let (result, timing) = self.timed_read(|store|
+            (store.get_location_avg(location_id, options), store.location_visit_count(location_id))
+        )?;
+        self.log_query(format_args!("Location avg {} by {:?}", location_id, options), &timing);
+        result
+    }
+
+    pub fn get_top_locations(&self, options: GetTopLocationsOptions) -> Result<TopLocations, StoreError> {
+        let logged_options = options.clone();
+        let (result, timing) = self.timed_read(|store|
+            match store.get_top_locations(options, self.max_scanned_visits) {
+                Ok((top_locations, scanned_visits)) => (Ok(top_locations), scanned_visits),
+                Err(err) => (Err(err), 0),
+            }
+        )?;
+        self.log_query(format_args!("Top locations by {:?}", logged_options), &timing);
+        result
+    }
+
+    /// Countries stats are kept up to date by mutations, so no visits are scanned
+    pub fn get_countries(&self) -> Result<Countries, StoreError> {
+        let (result, timing) = self.timed_read(|store| (store.get_countries(), 0))?;
+        self.log_query(format_args!("Countries"), &timing);
+        result
+    }
+
+    pub fn get_country_cities(&self, country: &str) -> Result<Cities, StoreError> {
+        let (result, timing) = self.timed_read(|store| (store.get_country_cities(country), 0))?;
+        self.log_query(format_args!("Country {} cities", country), &timing);
+        result
+    }
+
+    pub fn get_location_visits(&self, location_id: Id, options: GetLocationAvgOptions) -> Result<LocationVisits, StoreError> {
+        let (result, timing) = self.timed_read(|store|
+            (store.get_location_visits(location_id, options), store.location_visit_count(location_id))
+        )?;
+        self.log_query(format_args!("Location visits {} by {:?}", location_id, options), &timing);
+        result
+    }
+
+    pub fn get_location_demographics(&self, location_id: Id, options: GetLocationAvgOptions) ->
+            Result<LocationDemographics, StoreError> {
+        let (result, timing) = self.timed_read(|store|
+            (store.get_location_demographics(location_id, options), store.location_visit_count(location_id))
+        )?;
+        self.log_query(format_args!("Location demographics {} by {:?}", location_id, options), &timing);
+        result
     }
 }
 
@@ -615,6 +1798,7 @@ mod tests {
                         mark: visit_data.mark.unwrap(),
                         visited_at: visit_data.visited_at.unwrap(),
                         place: new_location.place,
+                        ..Default::default()
                     },
                 ],
             })
@@ -677,6 +1861,7 @@ mod tests {
                         mark: visit_data.mark.unwrap(),
                         visited_at: visit.visited_at,
                         place: location.place,
+                        ..Default::default()
                     },
                 ],
             })
@@ -739,6 +1924,7 @@ mod tests {
                         mark: visit.mark,
                         visited_at: visit.visited_at,
                         place: location.place,
+                        ..Default::default()
                     },
                 ],
             })
@@ -808,11 +1994,13 @@ mod tests {
                         mark: new_visit.mark,
                         visited_at: new_visit.visited_at,
                         place: new_location.place,
+                        ..Default::default()
                     },
                     UserVisit {
                         mark: old_visit.mark,
                         visited_at: visit_data.visited_at.unwrap(),
                         place: old_location.place,
+                        ..Default::default()
                     },
                 ],
             })
@@ -910,6 +2098,7 @@ mod tests {
                         mark: visit.mark,
                         visited_at: visit.visited_at,
                         place: location_data.place.unwrap(),
+                        ..Default::default()
                     }
                 ],
             })
@@ -989,6 +2178,7 @@ mod tests {
                         mark: visit_data.mark.unwrap(),
                         visited_at: visit.visited_at,
                         place: new_place.into(),
+                        ..Default::default()
                     },
                 ],
             })
@@ -996,26 +2186,864 @@ mod tests {
     }
 
     #[test]
-    fn get_location_avg_overflow() {
+    fn get_user_visits_with_filters() {
+        setup();
+
         let mut store = create_store();
 
         let user = old_user();
         store.add_user(user.clone()).unwrap();
 
+        let old_location = old_location();
+        store.add_location(old_location.clone()).unwrap();
+
+        let new_location = new_location();
+        store.add_location(new_location.clone()).unwrap();
+
+        let old_visit = Visit { id: 1, location: old_location.id, user: user.id, visited_at: 1, mark: 2 };
+        store.add_visit(old_visit.clone()).unwrap();
+
+        let new_visit = Visit { id: 2, location: new_location.id, user: user.id, visited_at: 2, mark: 5 };
+        store.add_visit(new_visit.clone()).unwrap();
+
+        let old_user_visit = UserVisit {
+            mark: old_visit.mark,
+            visited_at: old_visit.visited_at,
+            place: old_location.place.clone(),
+            ..Default::default()
+        };
+
+        let new_user_visit = UserVisit {
+            mark: new_visit.mark,
+            visited_at: new_visit.visited_at,
+            place: new_location.place.clone(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            store.get_user_visits(user.id, GetUserVisitsOptions {
+                city: Some(old_location.city.clone()),
+                ..Default::default()
+            }),
+            Ok(UserVisits { visits: vec![old_user_visit.clone()] })
+        );
+
+        assert_eq!(
+            store.get_user_visits(user.id, GetUserVisitsOptions {
+                from_distance: Some(new_location.distance),
+                ..Default::default()
+            }),
+            Ok(UserVisits { visits: vec![old_user_visit.clone()] })
+        );
+
+        assert_eq!(
+            store.get_user_visits(user.id, GetUserVisitsOptions {
+                min_mark: Some(3),
+                ..Default::default()
+            }),
+            Ok(UserVisits { visits: vec![new_user_visit.clone()] })
+        );
+
+        assert_eq!(
+            store.get_user_visits(user.id, GetUserVisitsOptions {
+                max_mark: Some(2),
+                ..Default::default()
+            }),
+            Ok(UserVisits { visits: vec![old_user_visit.clone()] })
+        );
+
+        assert_eq!(
+            store.get_user_visits(user.id, GetUserVisitsOptions {
+                location: Some(new_location.id),
+                expand: Some(true),
+                ..Default::default()
+            }),
+            Ok(UserVisits {
+                visits: vec![
+                    UserVisit {
+                        id: Some(new_visit.id),
+                        location: Some(new_location.id),
+                        country: Some(new_location.country.clone()),
+                        city: Some(new_location.city.clone()),
+                        ..new_user_visit
+                    },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn get_location_visits_and_demographics() {
+        setup();
+
+        let mut store = create_store();
+
+        let old_user = old_user();
+        store.add_user(old_user.clone()).unwrap();
+
+        let new_user = new_user();
+        store.add_user(new_user.clone()).unwrap();
+
         let location = old_location();
         store.add_location(location.clone()).unwrap();
 
-        for i in 1_u32..100_u32 {
-            let visit = Visit {
-                id: i,
-                user: user.id,
-                location: location.id,
-                mark: 5,
-                visited_at: 0,
-            };
-            assert_eq!(store.add_visit(visit), Ok(Empty{}));
+        let first_visit = Visit { id: 1, location: location.id, user: old_user.id, visited_at: 2, mark: 4 };
+        store.add_visit(first_visit.clone()).unwrap();
+
+        let second_visit = Visit { id: 2, location: location.id, user: new_user.id, visited_at: 1, mark: 5 };
+        store.add_visit(second_visit.clone()).unwrap();
+
+        let third_visit = Visit { id: 3, location: location.id, user: new_user.id, visited_at: 3, mark: 1 };
+        store.add_visit(third_visit.clone()).unwrap();
+
+        assert_eq!(
+            store.get_location_visits(location.id, GetLocationAvgOptions {
+                gender: Some('f'),
+                ..Default::default()
+            }),
+            Ok(LocationVisits {
+                visits: vec![
+                    LocationVisit {
+                        id: second_visit.id,
+                        mark: second_visit.mark,
+                        visited_at: second_visit.visited_at,
+                        user: new_user.clone(),
+                    },
+                    LocationVisit {
+                        id: third_visit.id,
+                        mark: third_visit.mark,
+                        visited_at: third_visit.visited_at,
+                        user: new_user.clone(),
+                    },
+                ],
+            })
+        );
+
+        let demographics = store.get_location_demographics(location.id, Default::default()).unwrap();
+        assert_eq!(demographics.visits, 3);
+        assert_eq!(demographics.visitors, 2);
+        assert_eq!(demographics.genders, GenderCount { f: 1, m: 1 });
+        assert_eq!(
+            demographics.ages.iter().filter(|b| b.visitors > 0).cloned().collect::<Vec<AgeBand>>(),
+            vec![
+                AgeBand { from_age: 25, to_age: Some(35), visitors: 1 },
+                AgeBand { from_age: 65, to_age: None, visitors: 1 },
+            ]
+        );
+
+        assert_eq!(
+            store.get_location_demographics(100, Default::default()),
+            Err(StoreError::EntityNotExists)
+        );
+    }
+
+    #[test]
+    fn get_top_locations() {
+        setup();
+
+        let mut store = create_store();
+
+        let old_user = old_user();
+        store.add_user(old_user.clone()).unwrap();
+
+        let new_user = new_user();
+        store.add_user(new_user.clone()).unwrap();
+
+        let old_location = old_location();
+        store.add_location(old_location.clone()).unwrap();
+
+        let new_location = new_location();
+        store.add_location(new_location.clone()).unwrap();
+
+        let foreign_location = Location {
+            id: 3,
+            place: "Louvre".into(),
+            city: "Paris".into(),
+            country: "France".into(),
+            distance: 5,
+        };
+        store.add_location(foreign_location.clone()).unwrap();
+
+        let visits = vec![
+            Visit { id: 1, location: old_location.id, user: old_user.id, visited_at: 1, mark: 2 },
+            Visit { id: 2, location: old_location.id, user: new_user.id, visited_at: 2, mark: 5 },
+            Visit { id: 3, location: new_location.id, user: new_user.id, visited_at: 3, mark: 4 },
+            Visit { id: 4, location: foreign_location.id, user: new_user.id, visited_at: 4, mark: 5 },
+        ];
+        for visit in visits {
+            store.add_visit(visit).unwrap();
         }
 
-        assert_eq!(store.get_location_avg(location.id, Default::default()), Ok(LocationRate{ avg: 5.0 }));
+        let ranking = |store: &Store, options: GetTopLocationsOptions|
+            store.get_top_locations(options, None).unwrap().0.locations.iter()
+                .map(|l| (l.id, l.avg, l.visits))
+                .collect::<Vec<(Id, f64, u64)>>();
+
+        assert_eq!(
+            ranking(&store, GetTopLocationsOptions {
+                country: Some("Russia".into()),
+                ..Default::default()
+            }),
+            vec![(new_location.id, 4.0, 1), (old_location.id, 3.5, 2)]
+        );
+
+        assert_eq!(
+            ranking(&store, GetTopLocationsOptions {
+                country: Some("Russia".into()),
+                gender: Some('f'),
+                to_age: Some(30),
+                ..Default::default()
+            }),
+            vec![(old_location.id, 5.0, 1), (new_location.id, 4.0, 1)]
+        );
+
+        assert_eq!(
+            ranking(&store, GetTopLocationsOptions {
+                min_visits: Some(2),
+                ..Default::default()
+            }),
+            vec![(old_location.id, 3.5, 2)]
+        );
+
+        assert_eq!(
+            ranking(&store, GetTopLocationsOptions {
+                limit: Some(1),
+                ..Default::default()
+            }),
+            vec![(foreign_location.id, 5.0, 1)]
+        );
+
+        assert_eq!(
+            ranking(&store, GetTopLocationsOptions {
+                city: Some("Paris".into()),
+                ..Default::default()
+            }),
+            vec![(foreign_location.id, 5.0, 1)]
+        );
+        assert!(ranking(&store, GetTopLocationsOptions {
+            country: Some("France".into()),
+            city: Some("Moscow".into()),
+            ..Default::default()
+        }).is_empty());
+
+        // Indexed marks of locations follow visit updates
+        store.update_visit(1, VisitData { mark: Some(4), ..Default::default() }).unwrap();
+        store.update_visit(3, VisitData { location: Some(foreign_location.id), ..Default::default() }).unwrap();
+        assert_eq!(
+            ranking(&store, Default::default()),
+            vec![(old_location.id, 4.5, 2), (foreign_location.id, 4.5, 2)]
+        );
+
+        let filtered = GetTopLocationsOptions {
+            gender: Some('f'),
+            ..Default::default()
+        };
+        assert_eq!(store.top_locations_visit_count(&filtered), 4);
+        assert_eq!(store.top_locations_visit_count(&Default::default()), 0);
+        assert_eq!(store.get_top_locations(filtered.clone(), Some(3)), Err(StoreError::ScanLimitExceeded(3)));
+        assert!(store.get_top_locations(filtered, Some(4)).is_ok());
+        assert!(store.get_top_locations(Default::default(), Some(1)).is_ok());
+    }
+
+    #[test]
+    fn area_stats_follow_updates() {
+        setup();
+
+        let mut store = create_store();
+
+        let user = old_user();
+        store.add_user(user.clone()).unwrap();
+
+        let old_location = old_location();
+        store.add_location(old_location.clone()).unwrap();
+
+        let new_location = new_location();
+        store.add_location(new_location.clone()).unwrap();
+
+        store.add_visit(Visit { id: 1, location: old_location.id, user: user.id, visited_at: 1, mark: 2 }).unwrap();
+        store.add_visit(Visit { id: 2, location: old_location.id, user: user.id, visited_at: 2, mark: 4 }).unwrap();
+        store.add_visit(Visit { id: 3, location: new_location.id, user: user.id, visited_at: 3, mark: 5 }).unwrap();
+
+        assert_eq!(
+            store.get_countries(),
+            Ok(Countries {
+                countries: vec![
+                    AreaStats { name: "Russia".into(), locations: 2, visits: 3, avg_mark: 3.66667, avg_distance: 5.0 },
+                ],
+            })
+        );
+
+        assert_eq!(
+            store.get_country_cities("Russia"),
+            Ok(Cities {
+                cities: vec![
+                    AreaStats { name: "Krasnodar".into(), locations: 1, visits: 2, avg_mark: 3.0, avg_distance: 10.0 },
+                    AreaStats { name: "Moscow".into(), locations: 1, visits: 1, avg_mark: 5.0, avg_distance: 0.0 },
+                ],
+            })
+        );
+
+        let location_data = LocationData {
+            country: Some("France".into()),
+            city: Some("Paris".into()),
+            distance: Some(20),
+            ..Default::default()
+        };
+        store.update_location(old_location.id, location_data).unwrap();
+
+        let visit_data = VisitData {
+            mark: Some(1),
+            ..Default::default()
+        };
+        store.update_visit(3, visit_data).unwrap();
+
+        assert_eq!(
+            store.get_countries(),
+            Ok(Countries {
+                countries: vec![
+                    AreaStats { name: "France".into(), locations: 1, visits: 2, avg_mark: 3.0, avg_distance: 20.0 },
+                    AreaStats { name: "Russia".into(), locations: 1, visits: 1, avg_mark: 1.0, avg_distance: 0.0 },
+                ],
+            })
+        );
+
+        assert_eq!(
+            store.get_country_cities("Russia"),
+            Ok(Cities {
+                cities: vec![
+                    AreaStats { name: "Moscow".into(), locations: 1, visits: 1, avg_mark: 1.0, avg_distance: 0.0 },
+                ],
+            })
+        );
+
+        let visit_data = VisitData {
+            location: Some(new_location.id),
+            ..Default::default()
+        };
+        store.update_visit(1, visit_data).unwrap();
+
+        assert_eq!(
+            store.get_country_cities("France"),
+            Ok(Cities {
+                cities: vec![
+                    AreaStats { name: "Paris".into(), locations: 1, visits: 1, avg_mark: 4.0, avg_distance: 20.0 },
+                ],
+            })
+        );
+
+        assert_eq!(store.get_country_cities("Spain"), Err(StoreError::EntityNotExists));
+    }
+
+    #[test]
+    fn get_user_summary() {
+        setup();
+
+        let mut store = create_store();
+
+        let user = new_user();
+        store.add_user(user.clone()).unwrap();
+
+        let old_location = old_location();
+        store.add_location(old_location.clone()).unwrap();
+
+        let new_location = new_location();
+        store.add_location(new_location.clone()).unwrap();
+
+        assert_eq!(
+            store.get_user_summary(user.id),
+            Ok(UserSummary {
+                age: 25,
+                ..Default::default()
+            })
+        );
+
+        store.add_visit(Visit { id: 1, location: old_location.id, user: user.id, visited_at: 30, mark: 2 }).unwrap();
+        store.add_visit(Visit { id: 2, location: new_location.id, user: user.id, visited_at: 10, mark: 3 }).unwrap();
+        store.add_visit(Visit { id: 3, location: old_location.id, user: user.id, visited_at: 20, mark: 5 }).unwrap();
+
+        assert_eq!(
+            store.get_user_summary(user.id),
+            Ok(UserSummary {
+                visits: 3,
+                locations: 2,
+                countries: 1,
+                first_visit: Some(10),
+                last_visit: Some(30),
+                avg_mark: 3.33333,
+                age: 25,
+            })
+        );
+
+        assert_eq!(store.get_user_summary(100), Err(StoreError::EntityNotExists));
+
+        let unborn = User { id: 3, birth_date: i64::max_value(), ..new_user() };
+        store.add_user(unborn.clone()).unwrap();
+        assert!(store.get_user_summary(unborn.id).unwrap().age < 0);
+
+        let ancient = User { id: 4, birth_date: i64::min_value(), ..new_user() };
+        store.add_user(ancient.clone()).unwrap();
+        assert!(store.get_user_summary(ancient.id).unwrap().age > 200_000);
+    }
+
+    #[test]
+    fn apply_batch_per_item() {
+        setup();
+
+        let mut store = create_store();
+
+        let user = old_user();
+        let location = old_location();
+        let visit = visit(&user, &location);
+
+        let results = store.apply_batch(vec![
+            Operation::AddUser(user.clone()),
+            Operation::AddLocation(location.clone()),
+            Operation::AddVisit(Visit { user: 100, ..visit.clone() }),
+            Operation::AddVisit(visit.clone()),
+            Operation::UpdateVisit(visit.id, VisitData { mark: Some(5), ..Default::default() }),
+        ]);
+
+        assert_eq!(results.len(), 5);
+        assert_matches!(results[2], Err(StoreError::InvalidEntity(_)));
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 4);
+
+        assert_eq!(store.get_visit(visit.id), Ok(Visit { mark: 5, ..visit }));
+    }
+
+    #[test]
+    fn commit_transaction() {
+        setup();
+
+        let mut store = create_store();
+
+        let user = old_user();
+        store.add_user(user.clone()).unwrap();
+
+        let old_location = old_location();
+        store.add_location(old_location.clone()).unwrap();
+
+        let visit = visit(&user, &old_location);
+        store.add_visit(visit.clone()).unwrap();
+
+        let new_location = new_location();
+        let new_user = new_user();
+
+        let mut transaction = Transaction::new();
+        transaction.push(Operation::AddLocation(new_location.clone()));
+        transaction.push(Operation::AddUser(new_user.clone()));
+        transaction.push(Operation::AddVisit(Visit {
+            id: 2,
+            user: new_user.id,
+            location: new_location.id,
+            ..visit.clone()
+        }));
+
+        assert_eq!(store.commit(transaction), Ok(vec![new_location.id, new_user.id, 2]));
+        assert_eq!(store.get_user(new_user.id), Ok(new_user.clone()));
+        assert_eq!(
+            store.get_location_avg(new_location.id, Default::default()),
+            Ok(LocationRate { avg: visit.mark as f64 })
+        );
+
+        let result = store.commit(Transaction::from(vec![
+            Operation::AddLocation(new_location.clone()),
+        ]));
+        assert_eq!(result, Err(TransactionError { index: 0, error: StoreError::EntryExists }));
+    }
+
+    #[test]
+    fn commit_rolls_back_on_failure() {
+        setup();
+
+        let mut store = create_store();
+
+        let user = old_user();
+        store.add_user(user.clone()).unwrap();
+
+        let old_location = old_location();
+        store.add_location(old_location.clone()).unwrap();
+
+        let visit = visit(&user, &old_location);
+        store.add_visit(visit.clone()).unwrap();
+
+        let new_location = new_location();
+        let new_user = new_user();
+
+        let operations = vec![
+            Operation::AddLocation(new_location.clone()),
+            Operation::AddUser(new_user.clone()),
+            Operation::UpdateVisit(visit.id, VisitData {
+                location: Some(new_location.id),
+                user: Some(new_user.id),
+                mark: Some(1),
+                ..Default::default()
+            }),
+            Operation::UpdateLocation(old_location.id, LocationData {
+                country: Some("France".into()),
+                ..Default::default()
+            }),
+            Operation::AddVisit(Visit { id: 2, ..visit.clone() }),
+            Operation::UpdateUser(user.id, UserData {
+                email: Some("vasia@mail.com".into()),
+                first_name: None,
+                last_name: None,
+                gender: None,
+                birth_date: None,
+            }),
+            Operation::AddVisit(Visit { id: 3, ..visit.clone() }),
+        ];
+
+        // Inject failure after each of operations
+        for index in 0..(operations.len() + 1) {
+            let mut transaction = Transaction::from(operations[..index].to_vec());
+            transaction.push(Operation::UpdateVisit(100, Default::default()));
+
+            assert_eq!(
+                store.commit(transaction),
+                Err(TransactionError { index: index, error: StoreError::EntityNotExists })
+            );
+            check_store_unchanged(&store, &user, &old_location, &visit);
+        }
+    }
+
+    fn check_store_unchanged(store: &Store, user: &User, old_location: &Location, visit: &Visit) {
+        let new_location = new_location();
+        let new_user = new_user();
+
+        assert_eq!(store.get_visit(visit.id), Ok(visit.clone()));
+        assert_eq!(store.get_visit(2), Err(StoreError::EntityNotExists));
+        assert_eq!(store.get_visit(3), Err(StoreError::EntityNotExists));
+        assert_eq!(store.get_user(user.id), Ok(user.clone()));
+        assert_eq!(store.get_user(new_user.id), Err(StoreError::EntityNotExists));
+        assert_eq!(store.get_location(new_location.id), Err(StoreError::EntityNotExists));
+        assert_eq!(store.get_location(old_location.id), Ok(old_location.clone()));
+
+        assert_eq!(
+            store.get_user_visits(user.id, Default::default()),
+            Ok(UserVisits {
+                visits: vec![
+                    UserVisit {
+                        mark: visit.mark,
+                        visited_at: visit.visited_at,
+                        place: old_location.place.clone(),
+                        ..Default::default()
+                    },
+                ],
+            })
+        );
+        assert_eq!(
+            store.get_location_avg(old_location.id, Default::default()),
+            Ok(LocationRate { avg: visit.mark as f64 })
+        );
+        assert_eq!(
+            store.get_countries(),
+            Ok(Countries {
+                countries: vec![
+                    AreaStats { name: "Russia".into(), locations: 1, visits: 1, avg_mark: 3.0, avg_distance: 10.0 },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn versions_follow_updates() {
+        setup();
+
+        let mut store = create_store();
+
+        let user = old_user();
+        store.add_user(user.clone()).unwrap();
+
+        let location = old_location();
+        store.add_location(location.clone()).unwrap();
+
+        let visit = visit(&user, &location);
+        store.add_visit(visit.clone()).unwrap();
+
+        assert_eq!(store.get_visit_version(visit.id), Ok(1));
+
+        store.update_visit(visit.id, VisitData { mark: Some(4), ..Default::default() }).unwrap();
+        assert_eq!(store.get_visit_version(visit.id), Ok(2));
+        assert_eq!(store.get_location_version(location.id), Ok(1));
+        assert_eq!(store.get_user_version(user.id), Ok(1));
+
+        let result = store.commit(Transaction::from(vec![
+            Operation::UpdateVisit(visit.id, VisitData { mark: Some(5), ..Default::default() }),
+            Operation::UpdateLocation(location.id, LocationData { distance: Some(1), ..Default::default() }),
+            Operation::AddUser(user.clone()),
+        ]));
+        assert_matches!(result, Err(TransactionError { index: 2, .. }));
+        assert_eq!(store.get_visit_version(visit.id), Ok(2));
+        assert_eq!(store.get_location_version(location.id), Ok(1));
+
+        let store = StoreWrapper::new(store);
+
+        assert_eq!(
+            store.update_user(user.id, UserData {
+                email: Some("vasia@mail.com".into()),
+                first_name: None,
+                last_name: None,
+                gender: None,
+                birth_date: None,
+            }, Some(&[1]), None),
+            Ok(2)
+        );
+        assert_eq!(
+            store.update_location(location.id, Default::default(), Some(&[1, 3]), None),
+            Ok(2)
+        );
+        assert_eq!(
+            store.update_visit(visit.id, VisitData { mark: Some(1), ..Default::default() }, Some(&[1]), None),
+            Err(StoreError::VersionMismatch)
+        );
+        assert_eq!(
+            store.get_visit(visit.id),
+            Ok(Versioned { entity: Visit { mark: 4, ..visit }, version: 2 })
+        );
+    }
+
+    #[test]
+    fn record_history() {
+        setup();
+
+        let store = StoreWrapper::new(create_store()).with_history(100);
+        let client = Some("127.0.0.1:1234".parse().unwrap());
+
+        let user = old_user();
+        store.add_user(user.clone(), client).unwrap();
+
+        let location = old_location();
+        store.add_location(location.clone(), client).unwrap();
+
+        let visit = visit(&user, &location);
+        store.add_visit(visit.clone(), client).unwrap();
+
+        store.update_visit(visit.id, VisitData { mark: Some(5), ..Default::default() }, None, client).unwrap();
+        assert_eq!(
+            store.update_visit(visit.id, VisitData { user: Some(100), ..Default::default() }, None, client),
+            Err(StoreError::InvalidEntity(ValidationError {
+                field: "user".into(),
+                message: "User with ID 100 not exists".into(),
+            }))
+        );
+
+        let history = store.get_history(EntityKind::Visits, visit.id).unwrap().history;
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].changes.len(), 5);
+        assert_eq!(history[1].client, Some("127.0.0.1:1234".into()));
+        assert_eq!(
+            history[1].changes.iter().collect::<Vec<(&String, &FieldChange)>>(),
+            vec![(&"mark".to_string(), &FieldChange { before: visit.mark.into(), after: 5.into() })]
+        );
+
+        let results = store.apply_batch(vec![
+            Operation::UpdateLocation(location.id, LocationData { distance: Some(1), ..Default::default() }),
+            Operation::UpdateLocation(location.id, LocationData { distance: Some(2), ..Default::default() }),
+            Operation::UpdateVisit(100, Default::default()),
+        ], None).unwrap();
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 2);
+
+        let history = store.get_history(EntityKind::Locations, location.id).unwrap().history;
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[1].client, None);
+        assert_eq!(history[1].changes["distance"], FieldChange { before: 10.into(), after: 2.into() });
+
+        assert!(store.get_history(EntityKind::Visits, 100).unwrap().history.is_empty());
+    }
+
+    #[test]
+    fn allocate_ids() {
+        let mut store = create_store();
+        let new_user = User { id: NEW_ID, ..old_user() };
+        assert_eq!(store.apply_new(Operation::AddUser(new_user.clone())), Ok(1));
+
+        store.add_user(User { id: 10, ..old_user() }).unwrap();
+        assert_eq!(store.apply_new(Operation::AddUser(new_user.clone())), Ok(11));
+
+        // Rejected entities do not use up ids
+        let invalid_user = User { gender: 'x', ..new_user.clone() };
+        assert_matches!(store.apply_new(Operation::AddUser(invalid_user.clone())), Err(StoreError::InvalidEntity(_)));
+        assert_matches!(
+            store.commit(Transaction::from(vec![
+                Operation::AddUser(new_user.clone()),
+                Operation::AddUser(invalid_user),
+            ])),
+            Err(TransactionError { index: 1, .. })
+        );
+        assert_eq!(store.get_user(12), Err(StoreError::EntityNotExists));
+        assert_eq!(
+            store.apply_batch(vec![Operation::AddUser(new_user.clone()), Operation::AddLocation(Location { id: NEW_ID, ..old_location() })]),
+            vec![Ok(12), Ok(1)]
+        );
+        assert_eq!(store.get_user(12), Ok(User { id: 12, ..new_user.clone() }));
+
+        store.last_ids.insert(EntityKind::Users, Id::max_value());
+        assert_eq!(store.apply_new(Operation::AddUser(new_user)), Err(StoreError::EntryExists));
+    }
+
+    #[test]
+    fn patch_entity() {
+        use patch::PatchOperation;
+
+        setup();
+
+        let store = StoreWrapper::new(create_store());
+        let user = old_user();
+        store.add_user(user.clone(), None).unwrap();
+
+        let merge = Patch::Merge(json!({"email": "merged@mail.com", "first_name": "Petia"}));
+        assert_eq!(store.patch(EntityKind::Users, user.id, merge, false, Some(&[1]), None), Ok(2));
+        let patched = store.get_user(user.id).unwrap().entity;
+        assert_eq!((patched.email.as_str(), patched.first_name.as_str()), ("merged@mail.com", "Petia"));
+
+        assert_eq!(
+            store.patch(EntityKind::Users, user.id, Patch::Merge(json!({"email": "x@mail.com"})), false, Some(&[1]), None),
+            Err(StoreError::VersionMismatch)
+        );
+        assert_matches!(
+            store.patch(EntityKind::Users, user.id, Patch::Merge(json!({"email": null})), false, None, None),
+            Err(StoreError::PatchFailed(PatchError::InvalidResult(_)))
+        );
+        assert_matches!(
+            store.patch(EntityKind::Users, user.id, Patch::Merge(json!({"gender": "x"})), false, None, None),
+            Err(StoreError::InvalidEntity(_))
+        );
+        assert_eq!(
+            store.patch(EntityKind::Users, user.id, Patch::Merge(json!({"id": 2})), false, None, None),
+            Err(StoreError::PatchFailed(PatchError::InvalidResult("id can not be changed".into())))
+        );
+        assert_eq!(
+            store.patch(EntityKind::Visits, 1, Patch::Merge(json!({})), false, None, None),
+            Err(StoreError::EntityNotExists)
+        );
+
+        let json_patch = Patch::Json(vec![
+            PatchOperation::Test { path: "/first_name".into(), value: json!("Vasia") },
+            PatchOperation::Replace { path: "/email".into(), value: json!("failed@mail.com") },
+        ]);
+        assert_eq!(
+            store.patch(EntityKind::Users, user.id, json_patch, false, None, None),
+            Err(StoreError::PatchFailed(PatchError::TestFailed("/first_name".into())))
+        );
+
+        let json_patch = Patch::Json(vec![
+            PatchOperation::Test { path: "/first_name".into(), value: json!("Petia") },
+            PatchOperation::Replace { path: "/last_name".into(), value: json!("Ivanov") },
+        ]);
+        assert_eq!(store.patch(EntityKind::Users, user.id, json_patch, false, None, None), Ok(3));
+
+        let patched = store.get_user(user.id).unwrap().entity;
+        assert_eq!(
+            (patched.email.as_str(), patched.first_name.as_str(), patched.last_name.as_str()),
+            ("merged@mail.com", "Petia", "Ivanov")
+        );
+
+        let typo = Patch::Merge(json!({"birthdate": 0}));
+        assert_eq!(
+            store.patch(EntityKind::Users, user.id, typo.clone(), true, None, None),
+            Err(StoreError::InvalidEntity(ValidationError { field: "birthdate".into(), message: "Unknown field".into() }))
+        );
+        let json_patch = Patch::Json(vec![PatchOperation::Remove { path: "/email".into() }]);
+        assert_eq!(
+            store.patch(EntityKind::Users, user.id, json_patch, true, None, None),
+            Err(StoreError::InvalidEntity(ValidationError { field: "email".into(), message: "Field is required".into() }))
+        );
+        assert_eq!(store.patch(EntityKind::Users, user.id, typo, false, None, None), Ok(4));
+    }
+
+    #[test]
+    fn publish_changes() {
+        use futures::{Future, Stream};
+
+        setup();
+
+        let store = StoreWrapper::new(create_store()).with_changes_buffer(100);
+        let (_, receiver) = store.subscribe_changes(None, 10).unwrap();
+
+        let user = old_user();
+        store.add_user(user.clone(), None).unwrap();
+
+        let location = old_location();
+        store.add_location(location.clone(), None).unwrap();
+        assert_eq!(store.add_location(location.clone(), None), Err(StoreError::EntryExists));
+
+        let visit = visit(&user, &location);
+        let mut transaction = Transaction::new();
+        transaction.push(Operation::AddVisit(visit.clone()));
+        transaction.push(Operation::UpdateVisit(100, Default::default()));
+        assert!(store.commit(transaction, None).unwrap().is_err());
+
+        store.update_user(user.id, UserData { email: Some("new@mail.com".into()), ..Default::default() }, None, None).unwrap();
+
+        let backlog = match store.subscribe_changes(Some(1), 10).unwrap().0 {
+            Backlog::Changes(changes) => changes,
+            Backlog::Reset(last_seq) => panic!("Unexpected reset at {}", last_seq),
+        };
+        assert_eq!(
+            backlog.iter().map(|change| (change.seq, change.entity, change.id)).collect::<Vec<(u64, EntityKind, Id)>>(),
+            vec![(2, EntityKind::Locations, location.id), (3, EntityKind::Users, user.id)]
+        );
+        assert_eq!(backlog[1].data["email"], "new@mail.com");
+
+        drop(store);
+        let changes = receiver.collect().wait().unwrap();
+        assert_eq!(changes.iter().map(|change| change.seq).collect::<Vec<u64>>(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn get_location_avg_overflow() {
+        let mut store = create_store();
+
+        let user = old_user();
+        store.add_user(user.clone()).unwrap();
+
+        let location = old_location();
+        store.add_location(location.clone()).unwrap();
+
+        for i in 1_u32..100_u32 {
+            let visit = Visit {
+                id: i,
+                user: user.id,
+                location: location.id,
+                mark: 5,
+                visited_at: 0,
+            };
+            assert_eq!(store.add_visit(visit), Ok(Empty{}));
+        }
+
+        assert_eq!(store.get_location_avg(location.id, Default::default()), Ok(LocationRate{ avg: 5.0 }));
+    }
+
+    #[test]
+    fn time_queries() {
+        setup();
+
+        let mut store = create_store();
+        let user = old_user();
+        store.add_user(user.clone()).unwrap();
+        let location = old_location();
+        store.add_location(location.clone()).unwrap();
+        for id in 1..4 {
+            store.add_visit(Visit { id: id, location: location.id, user: user.id, visited_at: 0, mark: 4 }).unwrap();
+        }
+
+        let store = StoreWrapper::new(store).with_slow_query_threshold(Duration::from_secs(0));
+        let (rate, timing) = store.timed_read(|store|
+            (store.get_location_avg(location.id, Default::default()), store.location_visit_count(location.id))
+        ).unwrap();
+        assert_eq!(rate, Ok(LocationRate { avg: 4.0 }));
+        assert_eq!(timing.scanned_visits, 3);
+
+        let by_gender = GetTopLocationsOptions { gender: Some(user.gender), ..Default::default() };
+        let top = store.store.read().unwrap().get_top_locations(by_gender.clone(), None);
+        assert_eq!(top.map(|(top, scanned_visits)| (top.locations.len(), scanned_visits)), Ok((1, 3)));
+        assert_eq!(store.get_top_locations(by_gender).map(|top| top.locations.len()), Ok(1));
+
+        assert_eq!(store.get_location_avg(location.id, Default::default()), Ok(LocationRate { avg: 4.0 }));
+        assert_eq!(store.get_user_visits(user.id, Default::default()).map(|visits| visits.visits.len()), Ok(3));
+        assert_eq!(store.get_user_summary(user.id).map(|summary| summary.visits), Ok(3));
+        assert_eq!(store.get_countries().map(|countries| countries.countries.len()), Ok(1));
+        assert_eq!(store.get_country_cities(&location.country).map(|cities| cities.cities.len()), Ok(1));
+        assert_eq!(store.get_location_visits(2, Default::default()), Err(StoreError::EntityNotExists));
     }
 }
@@ -0,0 +1,190 @@
+use serde_json::Value;
+
+use super::models::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FieldType {
+    Id,
+    Text,
+    Gender,
+    Timestamp,
+    Mark,
+    Distance,
+}
+
+const USER_FIELDS: &'static [(&'static str, FieldType)] = &[
+    ("id", FieldType::Id),
+    ("email", FieldType::Text),
+    ("first_name", FieldType::Text),
+    ("last_name", FieldType::Text),
+    ("gender", FieldType::Gender),
+    ("birth_date", FieldType::Timestamp),
+];
+
+const LOCATION_FIELDS: &'static [(&'static str, FieldType)] = &[
+    ("id", FieldType::Id),
+    ("place", FieldType::Text),
+    ("country", FieldType::Text),
+    ("city", FieldType::Text),
+    ("distance", FieldType::Distance),
+];
+
+const VISIT_FIELDS: &'static [(&'static str, FieldType)] = &[
+    ("id", FieldType::Id),
+    ("location", FieldType::Id),
+    ("user", FieldType::Id),
+    ("visited_at", FieldType::Timestamp),
+    ("mark", FieldType::Mark),
+];
+
+fn entity_fields(entity: EntityKind) -> &'static [(&'static str, FieldType)] {
+    match entity {
+        EntityKind::Users => USER_FIELDS,
+        EntityKind::Locations => LOCATION_FIELDS,
+        EntityKind::Visits => VISIT_FIELDS,
+    }
+}
+
+fn field_error(field: &str, message: &str) -> ValidationError {
+    ValidationError {
+        field: field.to_string(),
+        message: message.to_string(),
+    }
+}
+
+fn check_type(field: &str, field_type: FieldType, value: &Value) -> ValidationResult {
+    let valid = match field_type {
+        FieldType::Id => {
+            let id = value.as_u64().ok_or_else(|| field_error(field, "Expected integer"))?;
+            if id == 0 || id > u64::from(Id::max_value()) {
+                return Err(field_error(field, &format!("Id is out of range 1..{}", Id::max_value())))
+            }
+            true
+        },
+        FieldType::Text => value.is_string(),
+        FieldType::Gender => value.as_str().map_or(false, |gender| gender.chars().count() == 1),
+        FieldType::Timestamp => value.is_i64(),
+        FieldType::Mark => value.as_u64().map_or(false, |mark| mark <= u64::from(Mark::max_value())),
+        FieldType::Distance => value.as_u64().map_or(false, |distance| distance <= u64::from(u32::max_value())),
+    };
+
+    if valid {
+        Ok(())
+    } else {
+        let expected = match field_type {
+            FieldType::Id | FieldType::Timestamp => "Expected integer",
+            FieldType::Text => "Expected string",
+            FieldType::Gender => "Expected single character string",
+            FieldType::Mark => "Expected integer in range 0..255",
+            FieldType::Distance => "Expected non-negative integer",
+        };
+        Err(field_error(field, expected))
+    }
+}
+
+/// Reject unknown fields and values of wrong type. New entity must have all fields but optional `id`,
+/// update must not change `id`.
+pub fn check_fields(entity: EntityKind, creating: bool, value: &Value) -> ValidationResult {
+    let fields = entity_fields(entity);
+    let map = match *value {
+        Value::Object(ref map) => map,
+        _ => return Err(field_error("", "Expected object")),
+    };
+
+    for (field, value) in map {
+        match fields.iter().find(|&&(name, _)| name == field) {
+            Some(&("id", _)) if !creating => return Err(field_error(field, "Field can not be updated")),
+            Some(&(_, field_type)) => check_type(field, field_type, value)?,
+            None => return Err(field_error(field, "Unknown field")),
+        }
+    }
+
+    if creating {
+        if let Some(&(field, _)) = fields.iter().find(|&&(name, _)| name != "id" && !map.contains_key(name)) {
+            return Err(field_error(field, "Field is required"))
+        }
+    }
+
+    Ok(())
+}
+
+/// Integer fields of string values, e.g. cells of CSV body, parsed to numbers.
+/// Text fields and values which are not integers are left for checks and deserialization.
+pub fn parse_numbers(entity: EntityKind, mut value: Value) -> Value {
+    if let Value::Object(ref mut map) = value {
+        for &(field, field_type) in entity_fields(entity) {
+            let number = match map.get(field) {
+                Some(&Value::String(ref cell)) if field_type != FieldType::Text && field_type != FieldType::Gender =>
+                    cell.parse::<i64>().map(Value::from)
+                        .or_else(|_| cell.parse::<u64>().map(Value::from))
+                        .ok(),
+                _ => None,
+            };
+            if let Some(number) = number {
+                map.insert(field.to_string(), number);
+            }
+        }
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_user_update(value: Value) -> ValidationResult {
+        check_fields(EntityKind::Users, false, &value)
+    }
+
+    #[test]
+    fn accept_valid_fields() {
+        assert_eq!(check_user_update(json!({"email": "a@mail.com", "gender": "f", "birth_date": -100})), Ok(()));
+        assert_eq!(
+            check_fields(EntityKind::Visits, true, &json!({"id": 1, "location": 2, "user": 3, "visited_at": 0, "mark": 5})),
+            Ok(())
+        );
+        assert_eq!(
+            check_fields(EntityKind::Visits, true, &json!({"location": 2, "user": 3, "visited_at": 0, "mark": 5})),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn reject_invalid_fields() {
+        assert_eq!(check_user_update(json!({"birthdate": 0})), Err(field_error("birthdate", "Unknown field")));
+        assert_eq!(check_user_update(json!({"id": 2})), Err(field_error("id", "Field can not be updated")));
+        assert_eq!(check_user_update(json!({"email": 1})), Err(field_error("email", "Expected string")));
+        assert_eq!(check_user_update(json!({"gender": "mf"})), Err(field_error("gender", "Expected single character string")));
+        assert_eq!(check_user_update(json!({"birth_date": "0"})), Err(field_error("birth_date", "Expected integer")));
+        assert_eq!(check_user_update(json!([])), Err(field_error("", "Expected object")));
+
+        assert_eq!(
+            check_fields(EntityKind::Visits, false, &json!({"user": 4294967296_u64})),
+            Err(field_error("user", "Id is out of range 1..4294967295"))
+        );
+        assert_eq!(
+            check_fields(EntityKind::Visits, false, &json!({"location": 0})),
+            Err(field_error("location", "Id is out of range 1..4294967295"))
+        );
+        assert_eq!(
+            check_fields(EntityKind::Visits, false, &json!({"mark": 256})),
+            Err(field_error("mark", "Expected integer in range 0..255"))
+        );
+        assert_eq!(
+            check_fields(EntityKind::Locations, true, &json!({"id": 1, "place": "", "country": "", "distance": 1})),
+            Err(field_error("city", "Field is required"))
+        );
+    }
+
+    #[test]
+    fn parse_integer_fields() {
+        assert_eq!(
+            parse_numbers(EntityKind::Users, json!({"last_name": "123", "gender": "1", "birth_date": "-100", "id": "7"})),
+            json!({"last_name": "123", "gender": "1", "birth_date": -100, "id": 7})
+        );
+        assert_eq!(
+            parse_numbers(EntityKind::Locations, json!({"place": "1905", "distance": "1.5"})),
+            json!({"place": "1905", "distance": "1.5"})
+        );
+    }
+}
@@ -0,0 +1,219 @@
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::sync::RwLock;
+
+use futures::{
+    Future,
+    Stream,
+};
+use openssl::error::ErrorStack;
+use openssl::ssl::{
+    SslAcceptor,
+    SslFiletype,
+    SslMethod,
+};
+use tokio_core;
+use tokio_signal;
+
+#[derive(Debug)]
+pub enum Error {
+    SslError(ErrorStack),
+    LockError,
+}
+
+impl From<ErrorStack> for Error {
+    fn from(err: ErrorStack) -> Self {
+        Error::SslError(err)
+    }
+}
+
+fn load_acceptor(cert_path: &Path, key_path: &Path) -> Result<SslAcceptor, Error> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder.set_private_key_file(key_path, SslFiletype::PEM)?;
+    builder.set_certificate_chain_file(cert_path)?;
+    builder.check_private_key()?;
+    Ok(builder.build())
+}
+
+/// Acceptor of HTTPS connections with certificate and key reloadable from disk.
+/// Established connections keep acceptor they were accepted with.
+pub struct TlsAcceptor {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    acceptor: RwLock<SslAcceptor>,
+}
+
+impl TlsAcceptor {
+    pub fn new(cert_path: PathBuf, key_path: PathBuf) -> Result<Self, Error> {
+        let acceptor = load_acceptor(&cert_path, &key_path)?;
+        Ok(Self {
+            cert_path: cert_path,
+            key_path: key_path,
+            acceptor: RwLock::new(acceptor),
+        })
+    }
+
+    /// Acceptor for new connection
+    pub fn acceptor(&self) -> Result<SslAcceptor, Error> {
+        self.acceptor.read()
+            .map(|acceptor| acceptor.clone())
+            .map_err(|_| Error::LockError)
+    }
+
+    /// Read certificate and key again. Current acceptor is kept if they are invalid.
+    pub fn reload(&self) -> Result<(), Error> {
+        let acceptor = load_acceptor(&self.cert_path, &self.key_path)?;
+        *self.acceptor.write().map_err(|_| Error::LockError)? = acceptor;
+        Ok(())
+    }
+}
+
+/// Reload certificate on each SIGHUP. Blocks current thread.
+pub fn reload_on_hangup(acceptor: &TlsAcceptor) {
+    let mut core = tokio_core::reactor::Core::new().unwrap();
+    let signals = tokio_signal::unix::Signal::new(tokio_signal::unix::SIGHUP, &core.handle())
+        .flatten_stream()
+        .for_each(|_| {
+            match acceptor.reload() {
+                Ok(()) => info!("TLS certificate {} reloaded", acceptor.cert_path.display()),
+                Err(err) => warn!("TLS certificate {} reload failed: {:?}", acceptor.cert_path.display(), err),
+            }
+            Ok(())
+        });
+    core.run(signals).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io::{
+        Read,
+        Write,
+    };
+    use std::net::{
+        TcpListener,
+        TcpStream,
+    };
+    use std::sync::Arc;
+    use std::thread;
+
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::ssl::{
+        SslConnector,
+        SslStream,
+        SslVerifyMode,
+    };
+    use openssl::x509::{
+        X509,
+        X509Name,
+    };
+
+    /// Write self-signed certificate and its key, return certificate
+    fn write_self_signed(common_name: &str, cert_path: &Path, key_path: &Path) -> X509 {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_text("CN", common_name).unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap()).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = builder.build();
+
+        fs::write(cert_path, cert.to_pem().unwrap()).unwrap();
+        fs::write(key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        cert
+    }
+
+    fn temp_paths(name: &str) -> (PathBuf, PathBuf) {
+        let prefix = format!("hlcup1_{}_{}", name, ::std::process::id());
+        (env::temp_dir().join(format!("{}_cert.pem", prefix)), env::temp_dir().join(format!("{}_key.pem", prefix)))
+    }
+
+    /// Echo server accepting each connection with current acceptor
+    fn start_echo_server(tls: Arc<TlsAcceptor>) -> TcpListener {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let accepting_listener = listener.try_clone().unwrap();
+        thread::spawn(move || {
+            for stream in accepting_listener.incoming() {
+                let acceptor = tls.acceptor().unwrap();
+                thread::spawn(move || {
+                    let mut stream = acceptor.accept(stream.unwrap()).unwrap();
+                    let mut buffer = [0; 64];
+                    while let Ok(size) = stream.read(&mut buffer) {
+                        if size == 0 || stream.write_all(&buffer[..size]).is_err() {
+                            break
+                        }
+                    }
+                });
+            }
+        });
+        listener
+    }
+
+    fn connect(listener: &TcpListener) -> SslStream<TcpStream> {
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        connector.build().connect("localhost", stream).unwrap()
+    }
+
+    fn peer_certificate(stream: &SslStream<TcpStream>) -> Vec<u8> {
+        stream.ssl().peer_certificate().unwrap().to_der().unwrap()
+    }
+
+    fn echo(stream: &mut SslStream<TcpStream>, message: &[u8]) -> Vec<u8> {
+        stream.write_all(message).unwrap();
+        let mut buffer = vec![0; message.len()];
+        stream.read_exact(&mut buffer).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn reject_invalid_files() {
+        let (cert_path, key_path) = temp_paths("invalid");
+        fs::write(&cert_path, "not a certificate").unwrap();
+        fs::write(&key_path, "not a key").unwrap();
+
+        assert!(TlsAcceptor::new(cert_path, key_path).is_err());
+    }
+
+    #[test]
+    fn reload_certificate() {
+        let (cert_path, key_path) = temp_paths("reload");
+        let old_cert = write_self_signed("old.localhost", &cert_path, &key_path);
+        let tls = Arc::new(TlsAcceptor::new(cert_path.clone(), key_path.clone()).unwrap());
+        let listener = start_echo_server(tls.clone());
+
+        let mut old_stream = connect(&listener);
+        assert_eq!(peer_certificate(&old_stream), old_cert.to_der().unwrap());
+
+        let new_cert = write_self_signed("new.localhost", &cert_path, &key_path);
+        tls.reload().unwrap();
+
+        let mut new_stream = connect(&listener);
+        assert_eq!(peer_certificate(&new_stream), new_cert.to_der().unwrap());
+        assert_eq!(echo(&mut new_stream, b"new"), b"new");
+        assert_eq!(echo(&mut old_stream, b"old"), b"old");
+
+        // Broken key keeps certificate loaded before
+        fs::write(&key_path, "broken").unwrap();
+        assert!(tls.reload().is_err());
+        assert_eq!(peer_certificate(&connect(&listener)), new_cert.to_der().unwrap());
+    }
+}
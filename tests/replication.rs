@@ -0,0 +1,147 @@
+//! Primary and replicas running as separate server processes on localhost
+
+extern crate serde_json;
+
+use std::env;
+use std::fs;
+use std::io::{
+    Read,
+    Write,
+};
+use std::net::{
+    TcpListener,
+    TcpStream,
+};
+use std::path::PathBuf;
+use std::process::{
+    Child,
+    Command,
+    Stdio,
+};
+use std::thread;
+use std::time::Duration;
+
+/// End of central directory record of archive without files
+const EMPTY_ZIP: [u8; 22] = [0x50, 0x4b, 0x05, 0x06, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+const ADMIN_KEY: &'static str = "root";
+
+/// Server process killed on drop
+struct Server {
+    port: u16,
+    process: Child,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+fn data_dir() -> PathBuf {
+    let dir = env::temp_dir().join(format!("hlcup1-replication-data-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir(&dir).unwrap();
+    fs::write(dir.join("options.txt"), "1500000000\n1\n").unwrap();
+    fs::write(dir.join("data.zip"), &EMPTY_ZIP[..]).unwrap();
+    dir
+}
+
+fn start(vars: &[(&str, String)]) -> Server {
+    let port = free_port();
+    let mut command = Command::new(env!("CARGO_BIN_EXE_hlcup1"));
+    command
+        .env("LISTEN", format!("127.0.0.1:{}", port))
+        .env("THREADS", "1")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    for &(name, ref value) in vars {
+        command.env(name, value);
+    }
+    let server = Server {
+        port: port,
+        process: command.spawn().unwrap(),
+    };
+
+    for _ in 0..100 {
+        if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+            return server
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("Server on port {} is not started", port);
+}
+
+/// Status and body of HTTP/1.0 request
+fn request(port: u16, method: &str, path: &str, body: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.0\r\nAuthorization: Bearer {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        method, path, ADMIN_KEY, body.len(), body,
+    ).unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response[9..12].parse().unwrap();
+    let body = response.splitn(2, "\r\n\r\n").nth(1).unwrap_or("").to_string();
+    (status, body)
+}
+
+fn wait_for<F: Fn() -> bool>(condition: F) {
+    for _ in 0..100 {
+        if condition() {
+            return
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("Replica is not synchronized");
+}
+
+#[test]
+fn replicate_between_processes() {
+    let data_dir = data_dir();
+    let replication_port = free_port();
+    let replication_address = format!("127.0.0.1:{}", replication_port);
+
+    let primary = start(&[
+        ("DATA_PATH", data_dir.display().to_string()),
+        ("API_KEYS", format!("{}:admin,reader:read-only", ADMIN_KEY)),
+        ("REPLICATION_LISTEN", replication_address.clone()),
+    ]);
+    let (status, body) = request(
+        primary.port, "POST", "/users/new",
+        r#"{"email": "old@mail.com", "first_name": "Vasia", "last_name": "Pupkin", "gender": "m", "birth_date": 0}"#,
+    );
+    assert_eq!(status, 201);
+    let id = serde_json::from_str::<serde_json::Value>(&body).unwrap()["id"].as_u64().unwrap();
+    let path = format!("/users/{}", id);
+
+    let replicas = vec![
+        start(&[("REPLICATE_FROM", replication_address.clone()), ("REPLICATION_API_KEY", ADMIN_KEY.to_string())]),
+        start(&[("REPLICATE_FROM", replication_address.clone()), ("REPLICATION_API_KEY", ADMIN_KEY.to_string())]),
+    ];
+    let rejected = start(&[("REPLICATE_FROM", replication_address.clone()), ("REPLICATION_API_KEY", "reader".to_string())]);
+
+    // Snapshot
+    for replica in &replicas {
+        wait_for(|| request(replica.port, "GET", &path, "").0 == 200);
+        assert_eq!(request(replica.port, "GET", &path, ""), request(primary.port, "GET", &path, ""));
+    }
+
+    // Changes after snapshot
+    assert_eq!(request(primary.port, "POST", &path, r#"{"email": "new@mail.com"}"#).0, 200);
+    for replica in &replicas {
+        wait_for(|| request(replica.port, "GET", &path, "").1.contains("new@mail.com"));
+    }
+
+    assert_eq!(request(replicas[0].port, "POST", &path, r#"{"email": "replica@mail.com"}"#).0, 403);
+    assert_eq!(request(rejected.port, "GET", &path, "").0, 404);
+
+    fs::remove_dir_all(&data_dir).unwrap();
+}